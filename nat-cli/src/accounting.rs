@@ -0,0 +1,177 @@
+use crate::prepare::{self, NftablesEntry, NftablesOutput};
+use log::error;
+use nat_common::status::{self, Counter};
+use std::collections::BTreeMap;
+use std::io;
+
+const FILE_NAME_ACCOUNTING: &str = "/etc/nftables-nat/accounting.json";
+
+/// 本程序管理的表，只统计这些表中的规则
+const TABLES: [&str; 2] = ["self-nat", "self-filter"];
+
+/// 规则标识 -> "{family} {table} {chain}" -> 计数
+/// 同一条配置会在多个链中生成规则（DNAT、SNAT、限速等），按链分别累计，避免重复相加
+pub type Counters = BTreeMap<String, BTreeMap<String, Counter>>;
+
+/// nft中当前生效的规则
+#[derive(Debug, Default)]
+pub struct LiveRules {
+    /// 各规则的计数，已省略为0的计数
+    pub counters: Counters,
+    /// 规则标识 -> nft中的规则条数
+    pub rules: BTreeMap<String, usize>,
+}
+
+/// 读取nft中当前各规则的计数
+pub fn read_live() -> Result<LiveRules, io::Error> {
+    Ok(live_from(prepare::list_ruleset()?))
+}
+
+fn live_from(output: NftablesOutput) -> LiveRules {
+    let mut live = LiveRules::default();
+    for entry in output.nftables {
+        let NftablesEntry::Rule {
+            family,
+            table,
+            chain,
+            expr,
+            comment: Some(comment),
+            ..
+        } = entry
+        else {
+            continue;
+        };
+        if !TABLES.contains(&table.as_str()) {
+            continue;
+        }
+        // 旧版本生成的规则注释中没有标识，按整条注释统计
+        let rule_id = status::comment_rule_id(&comment).unwrap_or(&comment);
+        *live.rules.entry(rule_id.to_string()).or_default() += 1;
+        for counter in expr.iter().filter_map(|statement| statement.get("counter")) {
+            let counter = Counter {
+                packets: counter["packets"].as_u64().unwrap_or(0),
+                bytes: counter["bytes"].as_u64().unwrap_or(0),
+            };
+            if counter == Counter::default() {
+                continue;
+            }
+            live.counters
+                .entry(rule_id.to_string())
+                .or_default()
+                .entry(format!("{family} {table} {chain}"))
+                .or_default()
+                .add(counter);
+        }
+    }
+    live
+}
+
+/// 持久化的累计流量
+/// 每次重载都会重建表，规则计数随之清零，因此在重载前读出计数累加到文件中
+#[derive(Debug, Default)]
+pub struct Accounting {
+    totals: Counters,
+}
+
+impl Accounting {
+    /// 读取已保存的累计值，文件不存在时从0开始
+    pub fn load() -> Self {
+        let totals = match std::fs::read_to_string(FILE_NAME_ACCOUNTING) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                error!("解析流量统计文件 {FILE_NAME_ACCOUNTING} 失败: {e}");
+                Counters::new()
+            }),
+            Err(_) => Counters::new(),
+        };
+        Accounting { totals }
+    }
+
+    /// 累加重载前读出的计数并保存
+    pub fn add(&mut self, counters: Counters) {
+        if counters.is_empty() {
+            return;
+        }
+        self.merge(counters);
+        if let Err(e) = self.save() {
+            error!("保存流量统计文件 {FILE_NAME_ACCOUNTING} 失败: {e}");
+        }
+    }
+
+    /// 历次重载前累计的计数
+    pub fn totals(&self) -> &Counters {
+        &self.totals
+    }
+
+    fn merge(&mut self, counters: Counters) {
+        for (rule_id, chains) in counters {
+            let rule = self.totals.entry(rule_id).or_default();
+            for (chain, counter) in chains {
+                rule.entry(chain).or_default().add(counter);
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let content = serde_json::to_string_pretty(&self.totals)?;
+        std::fs::write(FILE_NAME_ACCOUNTING, content)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_from_ruleset() {
+        let json_data = r#"{
+    "nftables": [
+        {"metainfo": {"version": "1.1.3", "release_name": "Commodore Bullmoose #4", "json_schema_version": 1}},
+        {"rule": {"family": "ip", "table": "self-nat", "chain": "PREROUTING", "handle": 5,
+            "comment": "[single-tcp-10000-ipv4] SINGLE,10000,443,10.0.0.1,tcp,ipv4",
+            "expr": [{"counter": {"packets": 3, "bytes": 180}}, {"dnat": {"addr": "10.0.0.1", "port": 443}}]}},
+        {"rule": {"family": "ip", "table": "self-filter", "chain": "PREROUTING", "handle": 6,
+            "comment": "[single-tcp-10000-ipv4] SINGLE,10000,443,10.0.0.1,tcp,ipv4",
+            "expr": [{"counter": {"packets": 1, "bytes": 60}}, {"drop": null}]}},
+        {"rule": {"family": "ip", "table": "self-filter", "chain": "PREROUTING", "handle": 7,
+            "comment": "[single-tcp-10000-ipv4] SINGLE,10000,443,10.0.0.1,tcp,ipv4",
+            "expr": [{"counter": {"packets": 2, "bytes": 120}}, {"drop": null}]}},
+        {"rule": {"family": "ip", "table": "filter", "chain": "INPUT", "handle": 8,
+            "comment": "other",
+            "expr": [{"counter": {"packets": 9, "bytes": 900}}, {"accept": null}]}}
+    ]
+}"#;
+        let output: NftablesOutput = serde_json::from_str(json_data).unwrap();
+        let live = live_from(output);
+        assert_eq!(live.rules["single-tcp-10000-ipv4"], 3);
+        assert_eq!(live.rules.len(), 1);
+        let counters = live.counters;
+        assert_eq!(counters.len(), 1);
+        let rule = &counters["single-tcp-10000-ipv4"];
+        assert_eq!(
+            rule["ip self-nat PREROUTING"],
+            Counter {
+                packets: 3,
+                bytes: 180
+            }
+        );
+        assert_eq!(
+            rule["ip self-filter PREROUTING"],
+            Counter {
+                packets: 3,
+                bytes: 180
+            }
+        );
+
+        let mut accounting = Accounting::default();
+        accounting.merge(counters.clone());
+        accounting.merge(counters);
+        assert_eq!(
+            accounting.totals["single-tcp-10000-ipv4"]["ip self-nat PREROUTING"],
+            Counter {
+                packets: 6,
+                bytes: 360
+            }
+        );
+    }
+}
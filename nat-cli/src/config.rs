@@ -1,17 +1,27 @@
 #![deny(warnings)]
+use crate::geoip;
 use crate::ip;
+use crate::quota;
+use crate::schedule;
+use chrono::{DateTime, Utc};
 use ipnetwork::IpNetwork;
 use log::info;
-use nat_common::{Chain, IpVersion, NftCell, ParseError, Protocol, TomlConfig};
+use nat_common::status;
+use nat_common::{
+    BalanceStrategy, BalanceTarget, Chain, FilterAction, ForwardMode, IpSet, IpVersion, NftCell,
+    ParseError, Ports, Protocol, Quota, RateLimit, RuleGroup, Settings, Snat, TomlConfig,
+};
 use std::env;
 use std::fmt::Display;
 use std::fs;
 use std::io;
+use std::net::IpAddr;
 use std::str::FromStr;
 
 /// 运行时Cell，包装NftCell和Comment
 /// Comment仅用于运行时表示，不进入TOML配置
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum RuntimeCell {
     Rule(NftCell),
     Comment(String),
@@ -28,100 +38,339 @@ impl Display for RuntimeCell {
 
 /// Protocol扩展trait，提供nftables专用方法
 pub trait ProtocolExt {
-    fn nft_proto(&self) -> &str;
+    fn nft_proto(&self) -> String;
+    fn nft_port_key(&self) -> &str;
 }
 
 impl ProtocolExt for Protocol {
     /// 返回nft规则中的协议部分
     /// all类型返回"meta l4proto { tcp, udp } th"，匹配所有传输层协议
-    /// tcp/udp返回对应的协议名
-    fn nft_proto(&self) -> &str {
+    /// tcp/udp/sctp返回对应的协议名，后面可以接端口
+    /// icmp/icmpv6/l4proto没有端口，返回完整的匹配条件
+    fn nft_proto(&self) -> String {
+        match self {
+            Protocol::All => "meta l4proto { tcp, udp } th".to_string(),
+            Protocol::Tcp => "tcp".to_string(),
+            Protocol::Udp => "udp".to_string(),
+            Protocol::Sctp => "sctp".to_string(),
+            Protocol::Icmp(_) | Protocol::Icmpv6(_) => {
+                let (name, l4proto) = if matches!(self, Protocol::Icmp(_)) {
+                    ("icmp", "icmp")
+                } else {
+                    ("icmpv6", "ipv6-icmp")
+                };
+                match self.icmp_type_name() {
+                    Some(icmp_type) => format!("{name} type {icmp_type}"),
+                    None => format!("meta l4proto {l4proto}"),
+                }
+            }
+            Protocol::L4proto(number) => format!("meta l4proto {number}"),
+        }
+    }
+
+    /// 返回作为map键时的端口表达式前缀，all类型使用th
+    fn nft_port_key(&self) -> &str {
         match self {
-            Protocol::All => "meta l4proto { tcp, udp } th",
             Protocol::Tcp => "tcp",
             Protocol::Udp => "udp",
+            Protocol::Sctp => "sctp",
+            _ => "th",
+        }
+    }
+}
+
+/// Ports扩展trait，提供nftables专用方法
+pub trait PortsExt {
+    fn nft_ports(&self) -> String;
+}
+
+impl PortsExt for Ports {
+    /// 单个端口或端口段原样输出，多个时输出匿名集合，如 "{ 80, 443, 8000-8100 }"
+    fn nft_ports(&self) -> String {
+        match self.0.as_slice() {
+            [range] => range.to_string(),
+            ranges => {
+                let elements: Vec<String> = ranges.iter().map(|range| range.to_string()).collect();
+                format!("{{ {} }}", elements.join(", "))
+            }
         }
     }
 }
 
 /// NftCell构建扩展trait，提供nftables规则构建方法
 pub trait NftCellBuilder {
-    fn build(&self) -> Result<String, io::Error>;
+    fn build(&self, settings: &Settings) -> Result<String, io::Error>;
 }
 
 impl NftCellBuilder for NftCell {
-    fn build(&self) -> Result<String, io::Error> {
-        match self {
-            NftCell::Drop { .. } => build_drop_rule(self),
-            _ => {
-                let (domain, ip_version) = match &self {
-                    NftCell::Single {
-                        domain, ip_version, ..
-                    } => (domain, ip_version),
-                    NftCell::Range {
-                        domain, ip_version, ..
-                    } => (domain, ip_version),
-                    NftCell::Redirect { ip_version, .. } => {
-                        // Redirect doesn't need domain resolution
-                        return build_redirect_rules(self, ip_version);
-                    }
-                    NftCell::Drop { .. } => unreachable!(),
-                };
+    fn build(&self, settings: &Settings) -> Result<String, io::Error> {
+        let script = build_cell(self, settings)?;
+        match self.schedule() {
+            Some(time_window) => {
+                let matches = schedule::nft_time_matches(time_window, chrono::Utc::now())?;
+                // 只限制建立连接的入口规则（DNAT/重定向和过滤判定），窗口关闭后已建立的连接仍要计入配额、受限速约束
+                let is_filter = matches!(self, NftCell::Drop { .. } | NftCell::Accept { .. });
+                Ok(schedule::apply_time_matches(&script, &matches, |chain| {
+                    is_filter || matches!(chain, "self-nat PREROUTING" | "self-nat OUTPUT")
+                }))
+            }
+            None => Ok(script),
+        }
+    }
+}
+
+/// 按规则类型生成nft脚本
+fn build_cell(cell: &NftCell, settings: &Settings) -> Result<String, io::Error> {
+    match cell {
+        NftCell::Drop { .. } | NftCell::Accept { .. } => build_filter_rule(cell),
+        NftCell::Balance { .. } => build_balance_rules(cell, settings),
+        _ if cell.forward_mode() == ForwardMode::Relay => build_relay_rules(cell),
+        _ => {
+            let (domain, ip_version) = match cell {
+                NftCell::Single {
+                    domain, ip_version, ..
+                } => (domain, ip_version),
+                NftCell::Range {
+                    domain, ip_version, ..
+                } => (domain, ip_version),
+                NftCell::Redirect { ip_version, .. } => {
+                    // Redirect doesn't need domain resolution
+                    return build_redirect_rules(cell, ip_version, settings);
+                }
+                NftCell::Drop { .. } | NftCell::Accept { .. } | NftCell::Balance { .. } => {
+                    unreachable!()
+                }
+            };
+
+            // 双栈：分别解析A和AAAA记录，两个family各生成一份规则
+            if *ip_version == IpVersion::All {
+                let resolved = ip::remote_ips(domain)?;
+                return build_dual_stack_rules(cell, domain, resolved, settings);
+            }
 
-                // 根据配置的IP版本解析目标IP
-                let dst_ip = ip::remote_ip(domain, ip_version)?;
+            // 根据配置的IP版本解析目标IP
+            let dst_ip = ip::remote_ip(domain, ip_version)?;
 
-                let mut result = String::new();
+            let mut result = String::new();
 
-                // 检测实际IP类型并生成相应的规则
-                let is_ipv6_target = dst_ip.contains(':');
+            // 检测实际IP类型并生成相应的规则
+            let is_ipv6_target = dst_ip.contains(':');
 
-                match ip_version {
-                    IpVersion::V4 => {
-                        if is_ipv6_target {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "IPv6 target address resolved but rule is configured for IPv4 only",
-                            ));
-                        }
-                        result += &build_nat_rules(self, &dst_ip, &IpVersion::V4)?;
-                    }
-                    IpVersion::V6 => {
-                        if !is_ipv6_target {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "IPv4 target address resolved but rule is configured for IPv6 only",
-                            ));
-                        }
-                        result += &build_nat_rules(self, &dst_ip, &IpVersion::V6)?;
+            match ip_version {
+                IpVersion::V4 => {
+                    if is_ipv6_target {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "IPv6 target address resolved but rule is configured for IPv4 only",
+                        ));
                     }
-                    IpVersion::All => {
-                        if is_ipv6_target {
-                            result += &build_nat_rules(self, &dst_ip, &IpVersion::V6)?;
-                        } else {
-                            result += &build_nat_rules(self, &dst_ip, &IpVersion::V4)?;
-                        }
+                    result += &build_nat_rules(cell, &dst_ip, &IpVersion::V4, settings)?;
+                }
+                IpVersion::V6 => {
+                    if !is_ipv6_target {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "IPv4 target address resolved but rule is configured for IPv6 only",
+                        ));
                     }
+                    result += &build_nat_rules(cell, &dst_ip, &IpVersion::V6, settings)?;
                 }
-
-                Ok(result)
+                IpVersion::All => unreachable!(),
             }
+
+            Ok(result)
         }
     }
 }
 
+/// ip_version=all 的转发规则：解析到的每个family各生成一份规则
+/// 只解析到一个family时退化为单栈并记录警告
+fn build_dual_stack_rules(
+    cell: &NftCell,
+    domain: &str,
+    (ipv4, ipv6): (Option<String>, Option<String>),
+    settings: &Settings,
+) -> Result<String, io::Error> {
+    let resolved: Vec<(String, IpVersion)> = [(ipv4, IpVersion::V4), (ipv6, IpVersion::V6)]
+        .into_iter()
+        .filter_map(|(ip, family)| ip.map(|ip| (ip, family)))
+        .collect();
+    if resolved.len() == 1 && domain.parse::<IpAddr>().is_err() {
+        log::warn!(
+            "{domain} 只解析到{}地址，规则 {cell} 只生成该地址族的转发",
+            resolved[0].1
+        );
+    }
+
+    // 白名单或snat地址只属于一个family时跳过另一个family；都不适用时保留原有的报错
+    let applicable: Vec<&(String, IpVersion)> = resolved
+        .iter()
+        .filter(|(_, family)| family_applicable(cell, family))
+        .collect();
+    let targets: Vec<&(String, IpVersion)> = if applicable.is_empty() {
+        resolved.iter().collect()
+    } else {
+        applicable
+    };
+
+    let mut result = String::new();
+    for (dst_ip, family) in targets {
+        result += &build_nat_rules(cell, dst_ip, family, settings)?;
+    }
+    Ok(result)
+}
+
+/// 规则的源地址白名单和snat地址是否允许生成该family的规则
+fn family_applicable(cell: &NftCell, ip_version: &IpVersion) -> bool {
+    let allow_src: &[String] = match cell {
+        NftCell::Single { allow_src, .. }
+        | NftCell::Range { allow_src, .. }
+        | NftCell::Balance { allow_src, .. } => allow_src,
+        _ => &[],
+    };
+    let want_ipv6 = *ip_version == IpVersion::V6;
+    saddr_match(allow_src, ip_version).is_some()
+        && cell
+            .snat()
+            .and_then(Snat::is_ipv6)
+            .is_none_or(|is_ipv6| is_ipv6 == want_ipv6)
+}
+
+/// 解析配置文件得到的全局设置和规则
+#[derive(Debug, Default)]
+pub struct RuntimeConfig {
+    pub settings: Settings,
+    pub sets: Vec<IpSet>,
+    pub groups: Vec<RuleGroup>,
+    pub cells: Vec<RuntimeCell>,
+}
+
 impl RuntimeCell {
-    pub fn build(&self) -> Result<String, io::Error> {
+    /// 规则所属的分组，注释没有分组
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            RuntimeCell::Rule(cell) => cell.group(),
+            RuntimeCell::Comment(_) => None,
+        }
+    }
+
+    pub fn build(&self, settings: &Settings) -> Result<String, io::Error> {
         match self {
-            RuntimeCell::Rule(cell) => cell.build(),
+            RuntimeCell::Rule(cell) => cell.build(settings),
             RuntimeCell::Comment(content) => Ok(content.clone() + "\n"),
         }
     }
 }
 
-/// 构建过滤规则的nftables脚本
-fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
-    let NftCell::Drop {
+/// nft规则注释的长度上限（字节），超出时 nft -f 会拒绝整个脚本
+const NFT_COMMENT_MAX_LEN: usize = 128;
+
+/// 规则在nft中的注释：规则标识开头，用于把nft中的规则对应回配置，后接规则的简要说明；
+/// 过滤规则配置了 comment 时使用配置的注释。超出长度上限时按字符截断
+pub fn nft_comment(cell: &NftCell) -> String {
+    let summary = match (cell, cell.comment()) {
+        (NftCell::Drop { .. } | NftCell::Accept { .. }, Some(comment)) => comment.to_string(),
+        _ => comment_summary(cell),
+    };
+    let mut comment = status::comment_prefix(&cell.rule_id()) + &summary.replace('"', "'");
+    if comment.len() > NFT_COMMENT_MAX_LEN {
+        let mut end = NFT_COMMENT_MAX_LEN;
+        while !comment.is_char_boundary(end) {
+            end -= 1;
+        }
+        comment.truncate(end);
+    }
+    comment
+}
+
+/// 规则类型、协议、端口和目标，如 "SINGLE tcp 10000->example.com:443"
+fn comment_summary(cell: &NftCell) -> String {
+    match cell {
+        NftCell::Single {
+            sport,
+            dport,
+            domain,
+            protocol,
+            ..
+        } => format!("SINGLE {protocol} {sport}->{domain}:{dport}"),
+        NftCell::Range {
+            port_start,
+            port_end,
+            domain,
+            protocol,
+            ..
+        } => format!("RANGE {protocol} {port_start}-{port_end}->{domain}"),
+        NftCell::Redirect {
+            src_port,
+            src_port_end,
+            dst_port,
+            protocol,
+            ..
+        } => match src_port_end {
+            Some(end) => format!("REDIRECT {protocol} {src_port}-{end}->:{dst_port}"),
+            None => format!("REDIRECT {protocol} {src_port}->:{dst_port}"),
+        },
+        NftCell::Balance {
+            sport,
+            protocol,
+            targets,
+            ..
+        } => {
+            let targets: Vec<String> = targets.iter().map(ToString::to_string).collect();
+            format!("BALANCE {protocol} {sport}->{}", targets.join("|"))
+        }
+        NftCell::Drop {
+            chain,
+            src_ip,
+            dst_ip,
+            src_port,
+            src_port_end,
+            dst_port,
+            dst_port_end,
+            protocol,
+            ..
+        }
+        | NftCell::Accept {
+            chain,
+            src_ip,
+            dst_ip,
+            src_port,
+            src_port_end,
+            dst_port,
+            dst_port_end,
+            protocol,
+            ..
+        } => {
+            let kind = if matches!(cell, NftCell::Drop { .. }) {
+                "DROP"
+            } else {
+                "ACCEPT"
+            };
+            let mut parts = vec![format!("{kind} {chain} {protocol}")];
+            if let Some(ip) = src_ip {
+                parts.push(format!("src_ip={ip}"));
+            }
+            if let Some(ip) = dst_ip {
+                parts.push(format!("dst_ip={ip}"));
+            }
+            let port_range = |ports: &Ports, end: &Option<u16>| match end {
+                Some(end) => format!("{ports}-{end}"),
+                None => ports.to_string(),
+            };
+            if let Some(ports) = src_port {
+                parts.push(format!("src_port={}", port_range(ports, src_port_end)));
+            }
+            if let Some(ports) = dst_port {
+                parts.push(format!("dst_port={}", port_range(ports, dst_port_end)));
+            }
+            parts.join(" ")
+        }
+    }
+}
+
+/// 构建过滤规则（Drop/Accept）的nftables脚本
+fn build_filter_rule(cell: &NftCell) -> Result<String, io::Error> {
+    let (NftCell::Drop {
         chain,
         src_ip,
         dst_ip,
@@ -130,12 +379,23 @@ fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
         dst_port,
         dst_port_end,
         protocol,
-        comment,
-    } = cell
+        ..
+    }
+    | NftCell::Accept {
+        chain,
+        src_ip,
+        dst_ip,
+        src_port,
+        src_port_end,
+        dst_port,
+        dst_port_end,
+        protocol,
+        ..
+    }) = cell
     else {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "Expected Drop cell",
+            "Expected Drop or Accept cell",
         ));
     };
 
@@ -159,6 +419,9 @@ fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
                 format!("无效的IP地址: {}", ip),
             ));
         }
+    } else if let Some(ip_version) = protocol.ip_version() {
+        // icmp/icmpv6只属于一个family
+        ip_families.push(ip_version);
     } else {
         // 没有指定IP地址，在v4和v6中都添加规则
         ip_families.push(IpVersion::V4);
@@ -166,7 +429,7 @@ fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
     }
 
     for ip_version in ip_families {
-        result += &build_drop_rule_for_family(
+        result += &build_filter_rule_for_family(
             cell,
             chain,
             src_ip,
@@ -176,7 +439,6 @@ fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
             dst_port,
             dst_port_end,
             protocol,
-            comment,
             &ip_version,
         )?;
     }
@@ -186,17 +448,16 @@ fn build_drop_rule(cell: &NftCell) -> Result<String, io::Error> {
 
 /// 为特定IP family构建过滤规则
 #[allow(clippy::too_many_arguments)]
-fn build_drop_rule_for_family(
+fn build_filter_rule_for_family(
     cell: &NftCell,
     chain: &Chain,
     src_ip: &Option<String>,
     dst_ip: &Option<String>,
-    src_port: &Option<u16>,
+    src_port: &Option<Ports>,
     src_port_end: &Option<u16>,
-    dst_port: &Option<u16>,
+    dst_port: &Option<Ports>,
     dst_port_end: &Option<u16>,
     protocol: &Protocol,
-    comment: &Option<String>,
     ip_version: &IpVersion,
 ) -> Result<String, io::Error> {
     let (family, ip_prefix) = match ip_version {
@@ -217,11 +478,24 @@ fn build_drop_rule_for_family(
 
     let mut conditions = Vec::new();
 
+    if let Some(name) = cell.iifname() {
+        conditions.push(format!("iifname \"{name}\""));
+    }
+
     // 添加源IP条件（IP条件应该在协议条件之前）
     if let Some(ip) = src_ip {
         conditions.push(format!("{} saddr {}", ip_prefix, ip));
     }
 
+    if let Some(name) = cell.src_set() {
+        conditions.push(format!("{} saddr @{}", ip_prefix, name));
+    }
+
+    if !cell.src_country().is_empty() {
+        let name = geoip::set_name(cell.src_country());
+        conditions.push(format!("{} saddr @{}", ip_prefix, name));
+    }
+
     // 添加目标IP条件
     if let Some(ip) = dst_ip {
         conditions.push(format!("{} daddr {}", ip_prefix, ip));
@@ -229,50 +503,104 @@ fn build_drop_rule_for_family(
 
     // 添加协议条件
     if *protocol != Protocol::All || src_port.is_some() || dst_port.is_some() {
-        let proto = protocol.nft_proto();
-        conditions.push(proto.to_string());
+        conditions.push(protocol.nft_proto());
     }
 
     // 添加源端口条件
-    if let Some(port) = src_port {
+    if let Some(ports) = src_port {
         if let Some(end) = src_port_end {
-            conditions.push(format!("sport {}-{}", port, end));
+            conditions.push(format!("sport {}-{}", ports, end));
         } else {
-            conditions.push(format!("sport {}", port));
+            conditions.push(format!("sport {}", ports.nft_ports()));
         }
     }
 
     // 添加目标端口条件
-    if let Some(port) = dst_port {
+    if let Some(ports) = dst_port {
         if let Some(end) = dst_port_end {
-            conditions.push(format!("dport {}-{}", port, end));
+            conditions.push(format!("dport {}-{}", ports, end));
         } else {
-            conditions.push(format!("dport {}", port));
+            conditions.push(format!("dport {}", ports.nft_ports()));
         }
     }
 
     let conditions_str = conditions.join(" ");
-    let comment_str = if let Some(cmt) = comment {
-        format!(" comment \"{}\"", cmt)
-    } else {
-        format!(" comment \"{}\"", cell)
-    };
-
-    let rule = format!(
-        "add rule {family} self-filter {chain_name} {conditions_str} counter drop{comment_str}\n\n"
-    );
+    let comment_str = format!(" comment \"{}\"", nft_comment(cell));
+    let verdicts = filter_verdicts(cell, protocol, family);
+
+    // 配置了限速时先处理超出速率的包；Drop规则只处理超速部分，Accept规则放行剩余部分
+    let mut rule = build_log_rule(
+        cell,
+        family,
+        &format!("self-filter {chain_name}"),
+        &conditions_str,
+    )?;
+    if let Some(limit) = cell.limit() {
+        let limit = RateLimit::from_str(limit)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if matches!(cell, NftCell::Accept { .. }) {
+            rule += &format!(
+                "add rule {family} self-filter {chain_name} {conditions_str} limit rate over {limit} counter drop{comment_str}\n"
+            );
+        } else {
+            for (extra, verdict) in &verdicts {
+                rule += &format!(
+                    "add rule {family} self-filter {chain_name} {conditions_str}{extra} limit rate over {limit} counter {verdict}{comment_str}\n"
+                );
+            }
+            return Ok(rule + "\n");
+        }
+    }
+    for (extra, verdict) in &verdicts {
+        rule += &format!(
+            "add rule {family} self-filter {chain_name} {conditions_str}{extra} counter {verdict}{comment_str}\n"
+        );
+    }
+    rule += "\n";
 
     Ok(rule)
 }
 
+/// 过滤规则的附加匹配条件和动作
+/// reject 对TCP回复reset、对其他协议回复端口不可达；协议为all时拆成TCP和其他两条规则
+fn filter_verdicts(cell: &NftCell, protocol: &Protocol, family: &str) -> Vec<(String, String)> {
+    let unreachable = if family == "ip6" {
+        "reject with icmpv6 type port-unreachable"
+    } else {
+        "reject with icmp type port-unreachable"
+    };
+    match cell.filter_action() {
+        None => match mark_statements(cell) {
+            Some(marks) => vec![(String::new(), format!("{marks} accept"))],
+            None => vec![(String::new(), "accept".to_string())],
+        },
+        Some(FilterAction::Drop) => vec![(String::new(), "drop".to_string())],
+        Some(FilterAction::LogAndDrop) => {
+            vec![(String::new(), "log prefix \"nat-drop: \" drop".to_string())]
+        }
+        Some(FilterAction::Reject) => match protocol {
+            Protocol::Tcp => vec![(String::new(), "reject with tcp reset".to_string())],
+            Protocol::All => vec![
+                (
+                    " meta l4proto tcp".to_string(),
+                    "reject with tcp reset".to_string(),
+                ),
+                (String::new(), unreachable.to_string()),
+            ],
+            _ => vec![(String::new(), unreachable.to_string())],
+        },
+    }
+}
+
 fn build_nat_rules(
     cell: &NftCell,
     dst_ip: &str,
     ip_version: &IpVersion,
+    settings: &Settings,
 ) -> Result<String, io::Error> {
-    let (family, env_var, localhost_addr, fmt_ip) = match ip_version {
-        IpVersion::V4 => ("ip", "nat_local_ip", "127.0.0.1", dst_ip.to_string()),
-        IpVersion::V6 => ("ip6", "nat_local_ipv6", "::1", format!("[{}]", dst_ip)),
+    let (family, localhost_addr, fmt_ip) = match ip_version {
+        IpVersion::V4 => ("ip", "127.0.0.1", dst_ip.to_string()),
+        IpVersion::V6 => ("ip6", "::1", format!("[{}]", dst_ip)),
         IpVersion::All => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -281,22 +609,100 @@ fn build_nat_rules(
         }
     };
 
-    let snat_to_part = match env::var(env_var) {
-        Ok(ip) => "snat to ".to_owned() + &ip,
-        Err(_) => "masquerade".to_owned(),
-    };
+    let snat = resolve_snat(cell, settings, ip_version)?;
+    let iif = iifname_match(cell);
+    let comment = nft_comment(cell);
 
     match cell {
+        NftCell::Range {
+            port_start,
+            port_end,
+            dst_port_start: Some(dst_start),
+            dst_port_end: Some(dst_end),
+            protocol,
+            allow_src,
+            ..
+        } if (dst_start, dst_end) != (port_start, port_end) => {
+            // 监听端口段与目标端口段不同时，按端口逐一映射以保持一一对应
+            let proto = &protocol.nft_proto();
+            let port_key = protocol.nft_port_key();
+            let saddr = required_saddr_match(allow_src, ip_version)?;
+            let limit_rule = build_forward_filter_rules(
+                cell,
+                family,
+                &saddr,
+                proto,
+                &format!("{port_start}-{port_end}"),
+                Some(dst_ip),
+            )?;
+            let log_rule = build_log_rule(
+                cell,
+                family,
+                "self-nat PREROUTING",
+                &format!("{iif}ct state new {saddr}{proto} dport {port_start}-{port_end}"),
+            )?;
+            let map = (*port_start..=*port_end)
+                .zip(*dst_start..=*dst_end)
+                .map(|(src, dst)| format!("{src} : {dst_ip} . {dst}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let dnat = format!("dnat {family} to {port_key} dport map {{ {map} }}");
+            let dst_ports = format!("{dst_start}-{dst_end}");
+            let snat_rule = build_snat_rule(cell, family, dst_ip, proto, &dst_ports, &snat);
+            let hairpin_rules = build_hairpin_rules(
+                cell,
+                settings,
+                family,
+                &saddr,
+                proto,
+                &format!("{port_start}-{port_end}"),
+                &dnat,
+                Some((dst_ip, &dst_ports)),
+            );
+            let res = format!(
+                "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {port_start}-{port_end} counter {dnat} comment \"{comment}\"\n{snat_rule}{hairpin_rules}\n\
+                ",
+            );
+            Ok(res)
+        }
         NftCell::Range {
             port_start,
             port_end,
             protocol,
+            allow_src,
             ..
         } => {
-            let proto = protocol.nft_proto();
+            let proto = &protocol.nft_proto();
+            let saddr = required_saddr_match(allow_src, ip_version)?;
+            let limit_rule = build_forward_filter_rules(
+                cell,
+                family,
+                &saddr,
+                proto,
+                &format!("{port_start}-{port_end}"),
+                Some(dst_ip),
+            )?;
+            let log_rule = build_log_rule(
+                cell,
+                family,
+                "self-nat PREROUTING",
+                &format!("{iif}ct state new {saddr}{proto} dport {port_start}-{port_end}"),
+            )?;
+            let ports = format!("{port_start}-{port_end}");
+            let dnat = format!("dnat to {fmt_ip}:{ports}");
+            let snat_rule = build_snat_rule(cell, family, dst_ip, proto, &ports, &snat);
+            let hairpin_rules = build_hairpin_rules(
+                cell,
+                settings,
+                family,
+                &saddr,
+                proto,
+                &ports,
+                &dnat,
+                Some((dst_ip, &ports)),
+            );
             let res = format!(
-                "add rule {family} self-nat PREROUTING ct state new {proto} dport {port_start}-{port_end} counter dnat to {fmt_ip}:{port_start}-{port_end} comment \"{cell}\"\n\
-                add rule {family} self-nat POSTROUTING ct state new {family} daddr {dst_ip} {proto} dport {port_start}-{port_end} counter {snat_to_part} comment \"{cell}\"\n\n\
+                "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {ports} counter {dnat} comment \"{comment}\"\n{snat_rule}{hairpin_rules}\n\
                 ",
             );
             Ok(res)
@@ -306,22 +712,74 @@ fn build_nat_rules(
             dport,
             domain,
             protocol,
+            allow_src,
             ..
         } => {
-            let proto = protocol.nft_proto();
+            let proto = &protocol.nft_proto();
+            let port_key = protocol.nft_port_key();
+            let sports = sport.nft_ports();
+            let saddr = required_saddr_match(allow_src, ip_version)?;
+            let limit_rule =
+                build_forward_filter_rules(cell, family, &saddr, proto, &sports, Some(dst_ip))?;
+            let log_rule = build_log_rule(
+                cell,
+                family,
+                "self-nat PREROUTING",
+                &format!("{iif}ct state new {saddr}{proto} dport {sports}"),
+            )?;
+            // 目标端口为单个端口时直接改写；与监听端口相同时保留原端口；否则按顺序一一映射
+            let (redirect, dnat) = match dport.single() {
+                Some(port) => (
+                    format!("redirect to :{port}"),
+                    format!("dnat to {fmt_ip}:{port}"),
+                ),
+                None if dport == sport => ("redirect".to_string(), format!("dnat to {dst_ip}")),
+                None => {
+                    let port_pairs: Vec<(u16, u16)> =
+                        sport.iter_ports().zip(dport.iter_ports()).collect();
+                    let redirect_map = port_pairs
+                        .iter()
+                        .map(|(src, dst)| format!("{src} : {dst}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let dnat_map = port_pairs
+                        .iter()
+                        .map(|(src, dst)| format!("{src} : {dst_ip} . {dst}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    (
+                        format!("redirect to {port_key} dport map {{ {redirect_map} }}"),
+                        format!("dnat {family} to {port_key} dport map {{ {dnat_map} }}"),
+                    )
+                }
+            };
             let is_localhost = domain == "localhost" || domain == localhost_addr;
             if is_localhost {
                 // 重定向到本机
+                let hairpin_rules = build_hairpin_rules(
+                    cell, settings, family, &saddr, proto, &sports, &redirect, None,
+                );
                 let res = format!(
-                    "add rule {family} self-nat PREROUTING ct state new {proto} dport {sport} redirect to :{dport}  comment \"{cell}\"\n\n\
+                    "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {sports} {redirect}  comment \"{comment}\"\n{hairpin_rules}\n\
                     ",
                 );
                 Ok(res)
             } else {
                 // 转发到其他机器
+                let dports = dport.nft_ports();
+                let snat_rule = build_snat_rule(cell, family, dst_ip, proto, &dports, &snat);
+                let hairpin_rules = build_hairpin_rules(
+                    cell,
+                    settings,
+                    family,
+                    &saddr,
+                    proto,
+                    &sports,
+                    &dnat,
+                    Some((dst_ip, &dports)),
+                );
                 let res = format!(
-                    "add rule {family} self-nat PREROUTING ct state new {proto} dport {sport} counter dnat to {fmt_ip}:{dport}  comment \"{cell}\"\n\
-                    add rule {family} self-nat POSTROUTING ct state new {family} daddr {dst_ip} {proto} dport {dport} counter {snat_to_part} comment \"{cell}\"\n\n\
+                    "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {sports} counter {dnat}  comment \"{comment}\"\n{snat_rule}{hairpin_rules}\n\
                     ",
                 );
                 Ok(res)
@@ -331,30 +789,239 @@ fn build_nat_rules(
             io::ErrorKind::InvalidData,
             "Redirect cell should be built via build_redirect_rules",
         )),
-        NftCell::Drop { .. } => Err(io::Error::new(
+        NftCell::Balance { .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Balance cell should be built via build_balance_rules",
+        )),
+        NftCell::Drop { .. } | NftCell::Accept { .. } => Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "Drop cell should be built via build_drop_rule",
+            "Filter cell should be built via build_filter_rule",
         )),
     }
 }
 
-fn build_redirect_rules(cell: &NftCell, ip_version: &IpVersion) -> Result<String, io::Error> {
+/// 构建负载均衡规则：解析所有后端并确认地址族一致
+fn build_balance_rules(cell: &NftCell, settings: &Settings) -> Result<String, io::Error> {
+    let NftCell::Balance {
+        targets,
+        ip_version,
+        ..
+    } = cell
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected Balance cell",
+        ));
+    };
+
+    if *ip_version == IpVersion::All {
+        let mut resolved = Vec::with_capacity(targets.len());
+        for target in targets {
+            resolved.push(ip::remote_ips(&target.domain)?);
+        }
+        return build_dual_stack_balance_rules(cell, targets, resolved, settings);
+    }
+
+    let mut backends = Vec::with_capacity(targets.len());
+    for target in targets {
+        let ip = ip::remote_ip(&target.domain, ip_version)?;
+        backends.push((ip, target.weight));
+    }
+
+    let is_ipv6_target = backends.first().is_some_and(|(ip, _)| ip.contains(':'));
+    if backends
+        .iter()
+        .any(|(ip, _)| ip.contains(':') != is_ipv6_target)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "负载均衡的后端解析出的IP版本不一致，请通过 ip_version 指定",
+        ));
+    }
+
+    let family = if is_ipv6_target {
+        IpVersion::V6
+    } else {
+        IpVersion::V4
+    };
+    build_balance_nat_rules(cell, &backends, &family, settings)
+}
+
+/// ip_version=all 的负载均衡：所有后端都解析到地址的family才生成规则
+fn build_dual_stack_balance_rules(
+    cell: &NftCell,
+    targets: &[BalanceTarget],
+    resolved: Vec<(Option<String>, Option<String>)>,
+    settings: &Settings,
+) -> Result<String, io::Error> {
+    let mut ipv4 = Vec::with_capacity(targets.len());
+    let mut ipv6 = Vec::with_capacity(targets.len());
+    for (target, (v4, v6)) in targets.iter().zip(resolved) {
+        ipv4.push(v4.map(|ip| (ip, target.weight)));
+        ipv6.push(v6.map(|ip| (ip, target.weight)));
+    }
+    let resolved: Vec<(Vec<(String, u32)>, IpVersion)> =
+        [(ipv4, IpVersion::V4), (ipv6, IpVersion::V6)]
+            .into_iter()
+            .filter_map(|(backends, family)| {
+                backends
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .map(|backends| (backends, family))
+            })
+            .collect();
+    if resolved.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "负载均衡的后端解析出的IP版本不一致，请通过 ip_version 指定",
+        ));
+    }
+    if resolved.len() == 1
+        && targets
+            .iter()
+            .any(|target| target.domain.parse::<IpAddr>().is_err())
+    {
+        log::warn!(
+            "负载均衡的后端只有{}地址全部解析成功，规则 {cell} 只生成该地址族的转发",
+            resolved[0].1
+        );
+    }
+
+    let applicable: Vec<&(Vec<(String, u32)>, IpVersion)> = resolved
+        .iter()
+        .filter(|(_, family)| family_applicable(cell, family))
+        .collect();
+    let families: Vec<&(Vec<(String, u32)>, IpVersion)> = if applicable.is_empty() {
+        resolved.iter().collect()
+    } else {
+        applicable
+    };
+
+    let mut result = String::new();
+    for (backends, family) in families {
+        result += &build_balance_nat_rules(cell, backends, family, settings)?;
+    }
+    Ok(result)
+}
+
+/// 生成 numgen/jhash 映射的 dnat 规则，权重体现为映射中取值区间的大小
+fn build_balance_nat_rules(
+    cell: &NftCell,
+    backends: &[(String, u32)],
+    ip_version: &IpVersion,
+    settings: &Settings,
+) -> Result<String, io::Error> {
+    let NftCell::Balance {
+        sport,
+        dport,
+        strategy,
+        protocol,
+        allow_src,
+        ..
+    } = cell
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected Balance cell",
+        ));
+    };
+
+    let family = match ip_version {
+        IpVersion::V4 => "ip",
+        IpVersion::V6 => "ip6",
+        IpVersion::All => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "IpVersion::All should be handled at caller level",
+            ));
+        }
+    };
+
+    let snat = resolve_snat(cell, settings, ip_version)?;
+    let iif = iifname_match(cell);
+    let comment = nft_comment(cell);
+
+    let total: u32 = backends.iter().map(|(_, weight)| weight).sum();
+    let selector = match strategy {
+        BalanceStrategy::Random => format!("numgen random mod {total}"),
+        BalanceStrategy::RoundRobin => format!("numgen inc mod {total}"),
+        BalanceStrategy::Hash => format!("jhash {family} saddr mod {total}"),
+    };
+
+    let mut elements = Vec::with_capacity(backends.len());
+    let mut start = 0;
+    for (ip, weight) in backends {
+        let end = start + weight - 1;
+        if start == end {
+            elements.push(format!("{start} : {ip} . {dport}"));
+        } else {
+            elements.push(format!("{start}-{end} : {ip} . {dport}"));
+        }
+        start += weight;
+    }
+    let map = elements.join(", ");
+
+    let mut daddrs: Vec<&str> = backends.iter().map(|(ip, _)| ip.as_str()).collect();
+    daddrs.sort_unstable();
+    daddrs.dedup();
+    let daddrs = format!("{{ {} }}", daddrs.join(", "));
+
+    let proto = &protocol.nft_proto();
+    let saddr = required_saddr_match(allow_src, ip_version)?;
+    let sports = sport.nft_ports();
+    let limit_rule =
+        build_forward_filter_rules(cell, family, &saddr, proto, &sports, Some(&daddrs))?;
+    let log_rule = build_log_rule(
+        cell,
+        family,
+        "self-nat PREROUTING",
+        &format!("{iif}ct state new {saddr}{proto} dport {sports}"),
+    )?;
+    let dnat = format!("dnat {family} to {selector} map {{ {map} }}");
+    let dport = dport.to_string();
+    let snat_rule = build_snat_rule(cell, family, &daddrs, proto, &dport, &snat);
+    let hairpin_rules = build_hairpin_rules(
+        cell,
+        settings,
+        family,
+        &saddr,
+        proto,
+        &sports,
+        &dnat,
+        Some((&daddrs, &dport)),
+    );
+    let res = format!(
+        "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {sports} counter {dnat} comment \"{comment}\"\n{snat_rule}{hairpin_rules}\n\
+        ",
+    );
+    Ok(res)
+}
+
+fn build_redirect_rules(
+    cell: &NftCell,
+    ip_version: &IpVersion,
+    settings: &Settings,
+) -> Result<String, io::Error> {
     let mut result = String::new();
 
     match ip_version {
         IpVersion::All => {
-            result += &build_redirect_rule(cell, &IpVersion::V4)?;
-            result += &build_redirect_rule(cell, &IpVersion::V6)?;
+            result += &build_redirect_rule(cell, &IpVersion::V4, settings)?;
+            result += &build_redirect_rule(cell, &IpVersion::V6, settings)?;
         }
         _ => {
-            result += &build_redirect_rule(cell, ip_version)?;
+            result += &build_redirect_rule(cell, ip_version, settings)?;
         }
     }
 
     Ok(result)
 }
 
-fn build_redirect_rule(cell: &NftCell, ip_version: &IpVersion) -> Result<String, io::Error> {
+fn build_redirect_rule(
+    cell: &NftCell,
+    ip_version: &IpVersion,
+    settings: &Settings,
+) -> Result<String, io::Error> {
     let family = match ip_version {
         IpVersion::V4 => "ip",
         IpVersion::V6 => "ip6",
@@ -371,20 +1038,42 @@ fn build_redirect_rule(cell: &NftCell, ip_version: &IpVersion) -> Result<String,
             src_port_end,
             dst_port,
             protocol,
+            allow_src,
             ..
         } => {
-            let proto = protocol.nft_proto();
+            let proto = &protocol.nft_proto();
+            // 白名单中没有该family的地址时，该family不生成重定向
+            let Some(saddr) = saddr_match(allow_src, ip_version) else {
+                return Ok(String::new());
+            };
+            let dport = match src_port_end {
+                Some(end) => format!("{src_port}-{end}"),
+                None => src_port.to_string(),
+            };
+            let iif = iifname_match(cell);
+            let comment = nft_comment(cell);
+            let limit_rule = build_forward_filter_rules(cell, family, &saddr, proto, &dport, None)?;
+            let log_rule = build_log_rule(
+                cell,
+                family,
+                "self-nat PREROUTING",
+                &format!("{iif}ct state new {saddr}{proto} dport {dport}"),
+            )?;
+            let redirect = format!("redirect to :{dst_port}");
+            let hairpin_rules = build_hairpin_rules(
+                cell, settings, family, &saddr, proto, &dport, &redirect, None,
+            );
             let res = if let Some(end) = src_port_end {
                 // Range redirect
                 format!(
-                    "add rule {family} self-nat PREROUTING ct state new {proto} dport {src_port}-{src_port_end} redirect to :{dst_port} comment \"{cell}\"\n\n\
+                    "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {src_port}-{src_port_end} {redirect} comment \"{comment}\"\n{hairpin_rules}\n\
                     ",
                     src_port_end = end,
                 )
             } else {
                 // Single port redirect
                 format!(
-                    "add rule {family} self-nat PREROUTING ct state new {proto} dport {src_port} redirect to :{dst_port} comment \"{cell}\"\n\n\
+                    "{limit_rule}{log_rule}add rule {family} self-nat PREROUTING {iif}ct state new {saddr}{proto} dport {src_port} {redirect} comment \"{comment}\"\n{hairpin_rules}\n\
                     ",
                 )
             };
@@ -397,20 +1086,457 @@ fn build_redirect_rule(cell: &NftCell, ip_version: &IpVersion) -> Result<String,
     }
 }
 
-/// 解析一行legacy配置，返回RuntimeCell或错误
-/// 注释行返回 Some(RuntimeCell::Comment)
-/// 空行返回 None
-/// 规则行返回 Some(RuntimeCell::Rule)
-fn parse_legacy_line(line: &str) -> Option<RuntimeCell> {
-    let line = line.trim();
-
+/// mode = "relay" 的规则由用户态中继转发，脚本中只保留注释和 self-filter 中的限速、并发、日志规则
+/// 中继监听同时接受IPv4和IPv6连接，两个family都生成
+fn build_relay_rules(cell: &NftCell) -> Result<String, io::Error> {
+    let (protocol, allow_src, dport) = match cell {
+        NftCell::Single {
+            sport,
+            protocol,
+            allow_src,
+            ..
+        } => (protocol, allow_src, sport.nft_ports()),
+        NftCell::Range {
+            port_start,
+            port_end,
+            protocol,
+            allow_src,
+            ..
+        } => (protocol, allow_src, format!("{port_start}-{port_end}")),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Only Single and Range cells support relay mode",
+            ));
+        }
+    };
+    let proto = &protocol.nft_proto();
+    let mut result = format!("# relay: {cell}\n");
+    for (family, ip_version) in [("ip", IpVersion::V4), ("ip6", IpVersion::V6)] {
+        let Some(saddr) = saddr_match(allow_src, &ip_version) else {
+            continue;
+        };
+        result += &build_forward_filter_rules(cell, family, &saddr, proto, &dport, None)?;
+        result += &build_log_rule(
+            cell,
+            family,
+            "self-filter INPUT",
+            &format!("ct state new {saddr}{proto} dport {dport}"),
+        )?;
+    }
+    Ok(result + "\n")
+}
+
+/// 转发规则在 self-filter PREROUTING（优先级早于DNAT）中的附加规则：限速和单源并发连接数限制，
+/// 按连接原始目标端口计量的流量配额，以及 MANGLE 链中的打标记规则
+/// backend 为DNAT的目标地址（或地址集合），用于区分同一端口转发到不同后端的规则的配额；重定向到本机时为None
+fn build_forward_filter_rules(
+    cell: &NftCell,
+    family: &str,
+    saddr: &str,
+    proto: &str,
+    dport: &str,
+    backend: Option<&str>,
+) -> Result<String, io::Error> {
+    let mut rules = String::new();
+    let iif = iifname_match(cell);
+    let comment = nft_comment(cell);
+
+    // 源地址不在集合或国家列表中的新连接直接丢弃
+    let country_set = (!cell.src_country().is_empty()).then(|| geoip::set_name(cell.src_country()));
+    for name in cell.src_set().into_iter().chain(country_set.as_deref()) {
+        rules += &format!(
+            "add rule {family} self-filter PREROUTING {iif}ct state new {saddr}{family} saddr != @{name} {proto} dport {dport} counter drop comment \"{comment}\"\n"
+        );
+    }
+
+    // 包速率用于限制新建连接，字节速率限制全部流量
+    if let Some(limit) = cell.limit() {
+        let limit = RateLimit::from_str(limit)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let ct_state = if limit.is_bytes() {
+            ""
+        } else {
+            "ct state new "
+        };
+        rules += &format!(
+            "add rule {family} self-filter PREROUTING {iif}{ct_state}{saddr}{proto} dport {dport} limit rate over {limit} counter drop comment \"{comment}\"\n"
+        );
+    }
+
+    // 使用动态集合配合 ct count 统计每个源IP的连接数
+    if let Some(max) = cell.max_conns_per_src() {
+        let addr_type = if family == "ip6" {
+            "ipv6_addr"
+        } else {
+            "ipv4_addr"
+        };
+        // 按规则标识命名，监听端口相同但协议不同的规则各自计数
+        let set_name = format!("connlimit_{}", cell.rule_id());
+        rules += &format!(
+            "add set {family} self-filter {set_name} {{ type {addr_type}; size 65535; flags dynamic; }}\n\
+            add rule {family} self-filter PREROUTING {iif}ct state new {saddr}{proto} dport {dport} add @{set_name} {{ {family} saddr ct count over {max} }} counter drop comment \"{comment}\"\n"
+        );
+    }
+
+    // 路由之前为原方向的包打标记，回复方向的包由 MANGLE 链开头的规则从 ct mark 恢复
+    if let Some(marks) = mark_statements(cell) {
+        rules += &format!(
+            "add rule {family} self-filter MANGLE {iif}ct direction original {saddr}{proto} dport {dport} {marks} comment \"{comment}\"\n"
+        );
+    }
+
+    // 转发到其他机器的流量经过FORWARD，重定向到本机的经过INPUT和OUTPUT，两个方向都计入配额
+    // 配额链的优先级早于过滤链，用户的 accept 规则不会跳过配额；
+    // 每个包都要计量，因此入站网卡、源地址和后端地址都按连接跟踪中的原方向/回复方向匹配
+    if let (Some(quota), Some(name)) = (cell.quota(), quota::object_name(cell)) {
+        let quota =
+            Quota::from_str(quota).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let l4proto = match proto.strip_suffix(" th") {
+            Some(l4proto) => l4proto.to_string(),
+            None => format!("meta l4proto {proto}"),
+        };
+        let mut conntrack = String::new();
+        if !saddr.is_empty() {
+            conntrack += &format!("ct original {saddr}");
+        }
+        for name in cell.src_set().into_iter().chain(country_set.as_deref()) {
+            conntrack += &format!("ct original {family} saddr @{name} ");
+        }
+        if let Some(backend) = backend {
+            conntrack += &format!("ct reply {family} saddr {backend} ");
+        }
+        rules += &format!("add quota {family} self-filter {name} {{ over {quota} }}\n");
+        for chain in ["FORWARD", "INPUT", "OUTPUT"] {
+            // 原方向的包从入站网卡进入，回复方向的包从入站网卡发出
+            let interfaces = match (cell.iifname(), chain) {
+                (None, _) => vec![String::new()],
+                (Some(name), "FORWARD") => vec![
+                    format!("ct direction original iifname \"{name}\" "),
+                    format!("ct direction reply oifname \"{name}\" "),
+                ],
+                (Some(name), "INPUT") => vec![format!("iifname \"{name}\" ")],
+                (Some(name), _) => vec![format!("oifname \"{name}\" ")],
+            };
+            for interface in interfaces {
+                rules += &format!(
+                    "add rule {family} self-filter QUOTA_{chain} {interface}ct status dnat {conntrack}{l4proto} ct original proto-dst {dport} quota name \"{name}\" counter drop comment \"{comment}\"\n"
+                );
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// mark、ct_mark 对应的nft语句，如 `meta mark set 0x10 ct mark set 0x10`，都没有配置时返回None
+fn mark_statements(cell: &NftCell) -> Option<String> {
+    let statements: Vec<String> = [
+        cell.mark().map(|mark| format!("meta mark set {mark:#x}")),
+        cell.ct_mark().map(|mark| format!("ct mark set {mark:#x}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!statements.is_empty()).then(|| statements.join(" "))
+}
+
+/// 有规则设置 ct mark 时，在 MANGLE 和 ROUTE 链开头把回复方向包的 meta mark 恢复为 ct mark
+/// 转发的回复包经过 MANGLE，本机发出的回复包经过 ROUTE，mark 改变后内核会重新路由
+pub fn build_mark_restore_rules(cells: &[RuntimeCell]) -> String {
+    let uses_ct_mark = cells
+        .iter()
+        .any(|cell| matches!(cell, RuntimeCell::Rule(rule) if rule.ct_mark().is_some()));
+    if !uses_ct_mark {
+        return String::new();
+    }
+    let mut rules = String::new();
+    for family in ["ip", "ip6"] {
+        for chain in ["MANGLE", "ROUTE"] {
+            rules += &format!(
+                "add rule {family} self-filter {chain} ct direction reply ct mark != 0 meta mark set ct mark\n"
+            );
+        }
+    }
+    rules + "\n"
+}
+
+/// 配额规则所在的基础链，优先级早于 self-filter 中的 INPUT/FORWARD/OUTPUT 过滤链
+pub fn build_quota_chains() -> String {
+    let mut chains = String::new();
+    for family in ["ip", "ip6"] {
+        for (chain, hook) in [
+            ("QUOTA_INPUT", "input"),
+            ("QUOTA_FORWARD", "forward"),
+            ("QUOTA_OUTPUT", "output"),
+        ] {
+            chains += &format!(
+                "add chain {family} self-filter {chain} {{ type filter hook {hook} priority filter - 2 ; }}\n"
+            );
+        }
+    }
+    chains
+}
+
+/// 规则配置了 log 时，在同样的匹配条件下先插入一条只记录日志的规则
+/// 日志限速写在这条规则上，超出速率只是不记录，不影响后面规则的处理
+fn build_log_rule(
+    cell: &NftCell,
+    family: &str,
+    table_chain: &str,
+    conditions: &str,
+) -> Result<String, io::Error> {
+    let Some(log) = cell.log() else {
+        return Ok(String::new());
+    };
+    let mut statement = String::new();
+    if let Some(limit) = log
+        .rate_limit()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    {
+        statement += &format!("limit rate {limit} ");
+    }
+    statement += "log";
+    if let Some(prefix) = &log.prefix {
+        statement += &format!(" prefix \"{prefix} \"");
+    }
+    if let Some(level) = &log.level {
+        statement += &format!(" level {level}");
+    }
+    let conditions = conditions.trim_end();
+    let conditions = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("{conditions} ")
+    };
+    let comment = nft_comment(cell);
+    Ok(format!(
+        "add rule {family} {table_chain} {conditions}{statement} comment \"{comment}\"\n"
+    ))
+}
+
+/// 入站网卡匹配条件，未配置时为空字符串
+fn iifname_match(cell: &NftCell) -> String {
+    cell.iifname()
+        .map(|name| format!("iifname \"{name}\" "))
+        .unwrap_or_default()
+}
+
+/// 出站网卡匹配条件，用于POSTROUTING
+fn oifname_match(cell: &NftCell) -> String {
+    cell.oifname()
+        .map(|name| format!("oifname \"{name}\" "))
+        .unwrap_or_default()
+}
+
+/// 生成命名集合的定义，两个family各有一份同名集合，只包含对应family的地址
+/// 集合文件读取失败时只使用内联元素，避免整个脚本因引用不存在的集合而加载失败
+pub fn build_ip_set(set: &IpSet) -> String {
+    let mut networks: Vec<IpNetwork> = Vec::new();
+    let mut add_element = |source: &str, line: &str| match IpNetwork::from_str(line) {
+        Ok(network) => networks.push(network),
+        Err(e) => log::warn!("集合 {} 忽略无效地址 {line}（{source}）: {e}", set.name),
+    };
+    for element in &set.elements {
+        add_element("elements", element.trim());
+    }
+    if let Some(file) = &set.file {
+        match fs::read_to_string(file) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    add_element(file, line);
+                }
+            }
+            Err(e) => log::error!("读取集合 {} 的文件 {file} 失败: {e}", set.name),
+        }
+    }
+
+    let mut result = String::new();
+    for (family, addr_type, is_ipv6) in [("ip", "ipv4_addr", false), ("ip6", "ipv6_addr", true)] {
+        let name = &set.name;
+        result += &format!(
+            "add set {family} self-filter {name} {{ type {addr_type}; flags interval; auto-merge; }}\n"
+        );
+        let elements: Vec<String> = networks
+            .iter()
+            .filter(|network| network.is_ipv6() == is_ipv6)
+            .map(|network| {
+                // 去掉主机位，nft不接受 1.2.3.4/24 这样的写法
+                if network.prefix() == if network.is_ipv6() { 128 } else { 32 } {
+                    network.ip().to_string()
+                } else {
+                    format!("{}/{}", network.network(), network.prefix())
+                }
+            })
+            .collect();
+        if !elements.is_empty() {
+            result += &format!(
+                "add element {family} self-filter {name} {{ {} }}\n",
+                elements.join(", ")
+            );
+        }
+    }
+    result
+}
+
+/// 确定转发规则的SNAT方式，优先级：规则配置 > [settings] > 环境变量 nat_local_ip/nat_local_ipv6 > masquerade
+fn resolve_snat(
+    cell: &NftCell,
+    settings: &Settings,
+    ip_version: &IpVersion,
+) -> Result<Snat, io::Error> {
+    let (default, env_var, want_ipv6) = match ip_version {
+        IpVersion::V4 => (&settings.snat_ipv4, "nat_local_ip", false),
+        IpVersion::V6 => (&settings.snat_ipv6, "nat_local_ipv6", true),
+        IpVersion::All => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "IpVersion::All should be handled at caller level",
+            ));
+        }
+    };
+    let snat = match cell.snat().or(default.as_ref()) {
+        Some(snat) => snat.clone(),
+        None => match env::var(env_var) {
+            Ok(ip) => Snat::Address(ip),
+            Err(_) => Snat::Masquerade,
+        },
+    };
+    // ip_version=all 的规则在解析出目标地址后才知道family，此时再检查snat地址
+    if snat.is_ipv6().is_some_and(|is_ipv6| is_ipv6 != want_ipv6) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("snat 地址 {snat} 与目标地址族 {ip_version} 不一致"),
+        ));
+    }
+    Ok(snat)
+}
+
+/// POSTROUTING中的SNAT规则，snat=none时不生成
+fn build_snat_rule(
+    cell: &NftCell,
+    family: &str,
+    daddr: &str,
+    proto: &str,
+    dport: &str,
+    snat: &Snat,
+) -> String {
+    let action = match snat {
+        Snat::None => return String::new(),
+        Snat::Masquerade => "masquerade".to_string(),
+        Snat::Address(ip) => format!("snat to {ip}"),
+    };
+    let oif = oifname_match(cell);
+    let comment = nft_comment(cell);
+    format!(
+        "add rule {family} self-nat POSTROUTING {oif}ct state new {family} daddr {daddr} {proto} dport {dport} counter {action} comment \"{comment}\"\n"
+    )
+}
+
+/// 回环NAT：本机发起的连接经过OUTPUT，配置了入站网卡时内网其他网卡进入的连接也需要转换；
+/// 目标为其他机器时对回环的连接做masquerade，保证回包经过本机。
+/// masquerade只匹配回环流量（本机发起，或配置了入站网卡时从其他网卡进入），外部客户端仍按规则的snat处理
+#[allow(clippy::too_many_arguments)]
+fn build_hairpin_rules(
+    cell: &NftCell,
+    settings: &Settings,
+    family: &str,
+    saddr: &str,
+    proto: &str,
+    sports: &str,
+    action: &str,
+    backend: Option<(&str, &str)>,
+) -> String {
+    if !cell.hairpin().unwrap_or(settings.hairpin) {
+        return String::new();
+    }
+    let loopback = match family {
+        "ip6" => "ip6 daddr != ::1 ",
+        _ => "ip daddr != 127.0.0.0/8 ",
+    };
+    let comment = nft_comment(cell);
+    let mut result = format!(
+        "add rule {family} self-nat OUTPUT ct state new fib daddr type local {loopback}{saddr}{proto} dport {sports} counter {action} comment \"{comment}\"\n"
+    );
+    if let Some(name) = cell.iifname() {
+        result += &format!(
+            "add rule {family} self-nat PREROUTING iifname != \"{name}\" ct state new fib daddr type local {saddr}{proto} dport {sports} counter {action} comment \"{comment}\"\n"
+        );
+    }
+    if let Some((daddr, dport)) = backend {
+        let source = match cell.iifname() {
+            Some(name) => format!("iifname != \"{name}\" "),
+            None => "fib saddr type local ".to_string(),
+        };
+        result += &format!(
+            "add rule {family} self-nat POSTROUTING {source}ct status dnat {family} daddr {daddr} {proto} dport {dport} counter masquerade comment \"{comment}\"\n"
+        );
+    }
+    result
+}
+
+/// 生成源地址白名单的匹配条件，只保留与当前family一致的地址
+/// 未配置白名单时返回空字符串；配置了白名单但没有该family的地址时返回None
+fn saddr_match(allow_src: &[String], ip_version: &IpVersion) -> Option<String> {
+    if allow_src.is_empty() {
+        return Some(String::new());
+    }
+    let (family, want_ipv6) = match ip_version {
+        IpVersion::V4 => ("ip", false),
+        IpVersion::V6 => ("ip6", true),
+        IpVersion::All => return None,
+    };
+    let addrs: Vec<&str> = allow_src
+        .iter()
+        .filter(|ip| IpNetwork::from_str(ip).is_ok_and(|network| network.is_ipv6() == want_ipv6))
+        .map(|ip| ip.as_str())
+        .collect();
+    if addrs.is_empty() {
+        None
+    } else {
+        Some(format!("{family} saddr {{ {} }} ", addrs.join(", ")))
+    }
+}
+
+/// 同 saddr_match，但白名单中缺少目标family的地址时视为错误
+fn required_saddr_match(allow_src: &[String], ip_version: &IpVersion) -> Result<String, io::Error> {
+    saddr_match(allow_src, ip_version).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("allow_src 中没有与目标地址族 {ip_version} 一致的地址"),
+        )
+    })
+}
+
+/// 解析一行legacy配置，返回RuntimeCell或错误
+/// 注释行返回 Some(RuntimeCell::Comment)
+/// 空行返回 None
+/// 规则行返回 Some(RuntimeCell::Rule)
+fn parse_legacy_line(line: &str) -> Option<RuntimeCell> {
+    let line = line.trim();
+
     // 处理注释
     if line.starts_with('#') {
         return Some(RuntimeCell::Comment(line.to_string()));
     }
 
-    // 使用 nat-common 的 TryFrom 解析（包括NAT规则和Drop规则）
+    // 使用 nat-common 的 TryFrom 解析（包括NAT规则和Drop/Accept规则）
     match NftCell::try_from(line) {
+        Ok(cell) if !cell.enabled() => Some(disabled_comment(&cell)),
+        // 传统格式不能定义集合，引用不存在的集合会使整个 nft -f 失败
+        Ok(cell) if cell.src_set().is_some() => {
+            log::error!("跳过配置行，传统格式不支持 src_set，请使用TOML配置: {line}");
+            None
+        }
+        // 传统格式没有 geoip 设置，无法生成国家集合
+        Ok(cell) if !cell.src_country().is_empty() => {
+            log::error!("跳过配置行，传统格式不支持 src_country，请使用TOML配置: {line}");
+            None
+        }
         Ok(cell) => Some(RuntimeCell::Rule(cell)),
         Err(ParseError::Skip) => None,
         Err(ParseError::InvalidFormat(msg)) => {
@@ -420,6 +1546,35 @@ fn parse_legacy_line(line: &str) -> Option<RuntimeCell> {
     }
 }
 
+/// 停用的规则不参与生成，只以注释形式保留在脚本中
+fn disabled_comment(cell: &NftCell) -> RuntimeCell {
+    RuntimeCell::Comment(format!("# disabled: {cell}"))
+}
+
+/// 把已过期的规则替换为注释，返回本次发现的过期规则和其余规则中最近的过期时间
+pub fn expire_cells(
+    cells: &mut [RuntimeCell],
+    now: DateTime<Utc>,
+) -> (Vec<String>, Option<DateTime<Utc>>) {
+    let mut expired = Vec::new();
+    let mut next_expiry: Option<DateTime<Utc>> = None;
+    for cell in cells.iter_mut() {
+        let RuntimeCell::Rule(rule) = cell else {
+            continue;
+        };
+        let Some(expiry) = rule.expiry().map(|expiry| expiry.with_timezone(&Utc)) else {
+            continue;
+        };
+        if expiry <= now {
+            expired.push(rule.to_string());
+            *cell = RuntimeCell::Comment(format!("# expired: {rule}"));
+        } else if next_expiry.is_none_or(|next| expiry < next) {
+            next_expiry = Some(expiry);
+        }
+    }
+    (expired, next_expiry)
+}
+
 pub(crate) fn example(conf: &str) {
     info!("请在 {} 编写转发规则，内容类似：", &conf);
     info!(
@@ -430,33 +1585,72 @@ pub(crate) fn example(conf: &str) {
                     REDIRECT,8000-9000,3128,tcp,all\n\
                     DROP,input,src_ip=180.213.132.211,all,ipv4\n\
                     DROP,input,src_ip=240e:328:1301::/48,all,ipv6\n\
-                    DROP,forward,dst_port=22,tcp,all\n\
+                    ACCEPT,input,src_ip=10.0.0.0/8,dst_port=22,tcp\n\
+                    DROP,input,dst_port=22,tcp\n\
+                    BALANCE,10000,443,10.0.0.1*3|10.0.0.2,tcp,ipv4,random\n\
                     # 格式: TYPE,port(s),port/domain,protocol,ip_version\n\
-                    # TYPE: SINGLE, RANGE, REDIRECT 或 DROP\n\
+                    # TYPE: SINGLE, RANGE, REDIRECT, BALANCE, ACCEPT 或 DROP\n\
+                    # SINGLE/RANGE/REDIRECT/BALANCE 可追加 allow_src=CIDR|CIDR 限制允许的源地址\n\
+                    # 转发和过滤规则都可追加 limit=100/second burst 200 或 limit=10 mbytes/second 限速\n\
+                    # SINGLE/RANGE 可追加 max_conns_per_src=N 限制单个源IP的并发连接数\n\
+                    # RANGE 可追加 dst_port=start-end 将监听端口段映射到不同的目标端口段\n\
+                    # SINGLE/BALANCE 的监听端口和 DROP/ACCEPT 的 src_port/dst_port 可用 | 分隔多个端口或端口段，如 SINGLE,80|443,80|443,example.com\n\
+                    # 所有规则可追加 iifname=eth0 限定入站网卡，SINGLE/RANGE/BALANCE 可追加 oifname=eth1 限定出站网卡\n\
+                    # SINGLE/RANGE/BALANCE 可追加 snat=masquerade、snat=none（保留客户端源IP）或 snat=IP\n\
+                    # SINGLE/RANGE/REDIRECT/BALANCE 可追加 hairpin=true 使本机和内网客户端也能通过本机地址访问\n\
+                    # SINGLE/RANGE 可追加 mode=relay 改用用户态TCP/UDP中继转发，支持IPv6入、IPv4出\n\
+                    # 所有规则可追加 enabled=false 暂停该规则\n\
+                    # 所有规则可追加 group=分组名 将规则放入独立的nft链，tags=a|b 标记规则\n\
+                    # SINGLE/RANGE/REDIRECT/BALANCE 可追加 quota=500 GiB 限制总流量（ip_version 不能为 all），quota_reset=daily|weekly|monthly 定期清零\n\
+                    # 转发规则和ACCEPT规则可追加 mark=0x10 设置fwmark，ct_mark=0x10 设置连接标记并在回复包上恢复\n\
+                    # 所有规则可追加 expires_at=2026-10-18T18:00:00+08:00 到期后自动停止生效\n\
+                    # 所有规则可追加 id=web 指定规则标识，写入nft注释，用于对应nft中的规则、计数和错误\n\
+                    # 所有规则可追加 schedule=mon|fri 09:00-18:00 Asia/Shanghai 限定生效时间\n\
+                    # 所有规则可追加 log=前缀 级别 限速（如 log=web info 10/minute）记录命中的数据包\n\
+                    # BALANCE格式: BALANCE,sport,dport,target[*weight]|target[*weight]...[,protocol][,ip_version][,strategy]\n\
+                    #   strategy: random, round-robin 或 hash（按源地址固定后端）\n\
                     # REDIRECT格式: REDIRECT,src_port,dst_port 或 REDIRECT,src_port-src_port_end,dst_port\n\
                     # DROP格式: DROP,chain,key=value,...,protocol,ip_version\n\
+                    # ACCEPT格式与DROP相同，规则按配置顺序生效，放在DROP之前的ACCEPT优先\n\
                     #   chain: input 或 forward\n\
                     #   key=value: src_ip=IP, dst_ip=IP, src_port=PORT, dst_port=PORT\n\
-                    # protocol: tcp, udp, all\n\
+                    #   DROP可追加 action=reject（TCP回复reset，其他回复端口不可达）或 action=log-and-drop\n\
+                    # protocol: tcp, udp, sctp, all；DROP/ACCEPT还可以是 icmp[:类型]、icmpv6[:类型] 或 l4proto:协议号\n\
                     # ip_version: ipv4, ipv6, all"
     )
 }
 
-pub fn read_config(conf: &str) -> Result<Vec<RuntimeCell>, io::Error> {
+pub fn read_config(conf: &str) -> Result<RuntimeConfig, io::Error> {
     let mut cells = vec![];
     let mut contents = fs::read_to_string(conf)?;
     contents = contents.replace("\r\n", "\n");
 
+    // 传统格式不经过整体验证，标识重复的规则会共用配额和计数，跳过后面的一条
+    let mut rule_ids = std::collections::HashSet::new();
     for line in contents.lines() {
-        if let Some(cell) = parse_legacy_line(line) {
-            cells.push(cell);
+        match parse_legacy_line(line) {
+            Some(RuntimeCell::Rule(cell)) if !rule_ids.insert(cell.rule_id()) => {
+                log::warn!(
+                    "跳过规则标识 {} 重复的配置行，请用 id= 配置不同的标识: {}",
+                    cell.rule_id(),
+                    line.trim()
+                );
+            }
+            Some(cell) => cells.push(cell),
+            None => {}
         }
     }
-    Ok(cells)
+    // legacy格式没有全局设置，SNAT仍由环境变量决定
+    Ok(RuntimeConfig {
+        settings: Settings::default(),
+        sets: vec![],
+        groups: vec![],
+        cells,
+    })
 }
 
 // 读取TOML配置文件
-pub fn read_toml_config(toml_path: &str) -> Result<Vec<RuntimeCell>, io::Error> {
+pub fn read_toml_config(toml_path: &str) -> Result<RuntimeConfig, io::Error> {
     let contents = fs::read_to_string(toml_path)?;
 
     // 使用 nat-common 的解析和验证
@@ -472,38 +1666,108 @@ pub fn read_toml_config(toml_path: &str) -> Result<Vec<RuntimeCell>, io::Error>
             NftCell::Single { comment, .. } => comment.clone(),
             NftCell::Range { comment, .. } => comment.clone(),
             NftCell::Redirect { comment, .. } => comment.clone(),
+            NftCell::Balance { comment, .. } => comment.clone(),
             NftCell::Drop { comment, .. } => comment.clone(),
+            NftCell::Accept { comment, .. } => comment.clone(),
         };
 
         if let Some(comment_text) = comment {
             cells.push(RuntimeCell::Comment(format!("# {comment_text}")));
         }
 
-        cells.push(RuntimeCell::Rule(rule));
+        // 所属分组停用时，组内规则和单独停用的规则一样只保留注释
+        let group_enabled = rule.group().is_none_or(|name| {
+            config
+                .groups
+                .iter()
+                .any(|group| group.name == name && group.enabled)
+        });
+        if rule.enabled() && group_enabled {
+            cells.push(RuntimeCell::Rule(rule));
+        } else {
+            cells.push(disabled_comment(&rule));
+        }
     }
 
-    Ok(cells)
+    Ok(RuntimeConfig {
+        settings: config.settings,
+        sets: config.sets,
+        groups: config.groups,
+        cells,
+    })
 }
 
 // TOML配置示例函数
 pub fn toml_example(conf: &str) -> Result<(), io::Error> {
     let example_config = TomlConfig {
+        settings: Settings::default(),
+        sets: vec![IpSet {
+            name: "blocklist".to_string(),
+            elements: vec!["192.0.2.0/24".to_string(), "2001:db8::/32".to_string()],
+            file: None,
+            comment: Some("黑名单集合示例，也可以用 file 从文件加载".to_string()),
+        }],
+        groups: vec![],
         rules: vec![
             NftCell::Single {
-                sport: 10000,
-                dport: 443,
+                sport: 10000.into(),
+                dport: 443.into(),
                 domain: "baidu.com".to_string(),
                 protocol: Protocol::All,
                 ip_version: IpVersion::V4,
                 comment: Some("百度HTTPS服务转发示例".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                mode: None,
+                hairpin: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                quota: None,
+                quota_reset: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+                oifname: None,
+                snat: None,
+                max_conns_per_src: None,
+                allow_src: vec![],
             },
             NftCell::Range {
                 port_start: 1000,
                 port_end: 2000,
+                dst_port_start: None,
+                dst_port_end: None,
                 domain: "baidu.com".to_string(),
                 protocol: Protocol::Tcp,
                 ip_version: IpVersion::V4,
                 comment: Some("端口范围转发示例".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                mode: None,
+                hairpin: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                quota: None,
+                quota_reset: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+                oifname: None,
+                snat: None,
+                max_conns_per_src: None,
+                allow_src: vec![],
             },
             NftCell::Redirect {
                 src_port: 8000,
@@ -512,6 +1776,23 @@ pub fn toml_example(conf: &str) -> Result<(), io::Error> {
                 protocol: Protocol::All,
                 ip_version: IpVersion::V4,
                 comment: Some("单端口重定向到本机示例".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                hairpin: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                quota: None,
+                quota_reset: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+                allow_src: vec![],
             },
             NftCell::Redirect {
                 src_port: 30001,
@@ -520,6 +1801,60 @@ pub fn toml_example(conf: &str) -> Result<(), io::Error> {
                 protocol: Protocol::Tcp,
                 ip_version: IpVersion::V4,
                 comment: Some("端口范围重定向到本机示例".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                hairpin: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                quota: None,
+                quota_reset: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+                allow_src: vec![],
+            },
+            NftCell::Balance {
+                sport: 10080.into(),
+                dport: 80,
+                strategy: BalanceStrategy::Random,
+                protocol: Protocol::Tcp,
+                ip_version: IpVersion::V4,
+                comment: Some("按权重转发到多个后端示例".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                hairpin: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                quota: None,
+                quota_reset: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+                oifname: None,
+                snat: None,
+                allow_src: vec![],
+                targets: vec![
+                    BalanceTarget {
+                        domain: "10.0.0.1".to_string(),
+                        weight: 3,
+                    },
+                    BalanceTarget {
+                        domain: "10.0.0.2".to_string(),
+                        weight: 1,
+                    },
+                ],
             },
             NftCell::Drop {
                 chain: Chain::Input,
@@ -530,7 +1865,21 @@ pub fn toml_example(conf: &str) -> Result<(), io::Error> {
                 dst_port: None,
                 dst_port_end: None,
                 protocol: Protocol::All,
+                action: FilterAction::Drop,
                 comment: Some("阻止特定IPv4地址".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
             },
             NftCell::Drop {
                 chain: Chain::Input,
@@ -541,7 +1890,70 @@ pub fn toml_example(conf: &str) -> Result<(), io::Error> {
                 dst_port: None,
                 dst_port_end: None,
                 protocol: Protocol::All,
+                action: FilterAction::Drop,
                 comment: Some("阻止IPv6网段".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+            },
+            NftCell::Drop {
+                chain: Chain::Input,
+                src_ip: None,
+                dst_ip: None,
+                src_port: None,
+                src_port_end: None,
+                dst_port: None,
+                dst_port_end: None,
+                protocol: Protocol::All,
+                action: FilterAction::Drop,
+                comment: Some("阻止黑名单集合中的地址".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: Some("blocklist".to_string()),
+                limit: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
+            },
+            NftCell::Accept {
+                chain: Chain::Input,
+                src_ip: Some("10.0.0.0/8".to_string()),
+                dst_ip: None,
+                src_port: None,
+                src_port_end: None,
+                dst_port: Some(22.into()),
+                dst_port_end: None,
+                protocol: Protocol::Tcp,
+                comment: Some("允许内网访问SSH端口（需放在Drop规则之前）".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
             },
             NftCell::Drop {
                 chain: Chain::Input,
@@ -549,10 +1961,24 @@ pub fn toml_example(conf: &str) -> Result<(), io::Error> {
                 dst_ip: None,
                 src_port: None,
                 src_port_end: None,
-                dst_port: Some(22),
+                dst_port: Some(22.into()),
                 dst_port_end: None,
                 protocol: Protocol::Tcp,
+                action: FilterAction::Drop,
                 comment: Some("阻止SSH端口访问".to_string()),
+                id: None,
+                group: None,
+                tags: vec![],
+                enabled: true,
+                expires_at: None,
+                log: None,
+                schedule: None,
+                src_country: vec![],
+                src_set: None,
+                limit: None,
+                mark: None,
+                ct_mark: None,
+                iifname: None,
             },
         ],
     };
@@ -639,8 +2065,8 @@ mod redirect_parse_tests {
                 domain,
                 ..
             }) => {
-                assert_eq!(sport, 2222);
-                assert_eq!(dport, 22);
+                assert_eq!(sport, 2222.into());
+                assert_eq!(dport, 22.into());
                 assert_eq!(domain, "localhost");
             }
             other => panic!("Expected Single variant, got {:?}", other),
@@ -662,9 +2088,26 @@ mod redirect_build_tests {
             protocol: Protocol::All,
             ip_version: IpVersion::V4,
             comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec![],
         };
 
-        let result = cell.build().unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
         // all协议使用th dport匹配所有传输层协议
         assert!(result.contains("add rule ip self-nat PREROUTING ct state new meta l4proto { tcp, udp } th dport 8000 redirect to :3128"));
         assert!(!result.contains("ip6")); // Should not have IPv6 rules
@@ -679,9 +2122,26 @@ mod redirect_build_tests {
             protocol: Protocol::Tcp,
             ip_version: IpVersion::V4,
             comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec![],
         };
 
-        let result = cell.build().unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
         // tcp协议只生成tcp规则
         assert!(result.contains(
             "add rule ip self-nat PREROUTING ct state new tcp dport 30001-39999 redirect to :45678"
@@ -699,9 +2159,26 @@ mod redirect_build_tests {
             protocol: Protocol::All,
             ip_version: IpVersion::All,
             comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec![],
         };
 
-        let result = cell.build().unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
         // all协议应该使用th dport，同时包含IPv4和IPv6
         assert!(result.contains("add rule ip self-nat PREROUTING ct state new meta l4proto { tcp, udp } th dport 5000 redirect to :4000"));
         assert!(
@@ -709,3 +2186,1368 @@ mod redirect_build_tests {
         );
     }
 }
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod balance_build_tests {
+    use super::*;
+
+    fn balance_cell(strategy: BalanceStrategy) -> NftCell {
+        NftCell::Balance {
+            sport: 10000.into(),
+            dport: 443,
+            strategy,
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            allow_src: vec![],
+            targets: vec![
+                BalanceTarget {
+                    domain: "10.0.0.1".to_string(),
+                    weight: 3,
+                },
+                BalanceTarget {
+                    domain: "10.0.0.2".to_string(),
+                    weight: 1,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_build_balance_weighted_random() {
+        let result = balance_cell(BalanceStrategy::Random)
+            .build(&Settings::default())
+            .unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING ct state new tcp dport 10000 counter dnat ip to numgen random mod 4 map { 0-2 : 10.0.0.1 . 443, 3 : 10.0.0.2 . 443 }"
+        ));
+        assert!(result.contains("ip daddr { 10.0.0.1, 10.0.0.2 } tcp dport 443"));
+    }
+
+    #[test]
+    fn test_build_balance_hash() {
+        let result = balance_cell(BalanceStrategy::Hash)
+            .build(&Settings::default())
+            .unwrap();
+        assert!(result.contains("dnat ip to jhash ip saddr mod 4 map"));
+    }
+
+    #[test]
+    fn test_build_balance_mixed_family_fails() {
+        let cell = NftCell::Balance {
+            sport: 10000.into(),
+            dport: 443,
+            strategy: BalanceStrategy::Random,
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::All,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            allow_src: vec![],
+            targets: vec![
+                BalanceTarget {
+                    domain: "10.0.0.1".to_string(),
+                    weight: 1,
+                },
+                BalanceTarget {
+                    domain: "2001:db8::1".to_string(),
+                    weight: 1,
+                },
+            ],
+        };
+        assert!(cell.build(&Settings::default()).is_err());
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod allow_src_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_allow_src() {
+        let cell = NftCell::Single {
+            sport: 10000.into(),
+            dport: 443.into(),
+            domain: "10.0.0.1".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec!["1.2.3.0/24".to_string(), "5.6.7.8".to_string()],
+        };
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING ct state new ip saddr { 1.2.3.0/24, 5.6.7.8 } tcp dport 10000 counter dnat to 10.0.0.1:443"
+        ));
+    }
+
+    #[test]
+    fn test_build_redirect_all_with_single_family_allow_src() {
+        let cell = NftCell::Redirect {
+            src_port: 8000,
+            src_port_end: None,
+            dst_port: 3128,
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::All,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec!["2001:db8::/32".to_string()],
+        };
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip6 self-nat PREROUTING ct state new ip6 saddr { 2001:db8::/32 } tcp dport 8000 redirect to :3128"
+        ));
+        assert!(!result.contains("add rule ip self-nat"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod range_build_tests {
+    use super::*;
+
+    fn range_cell(ip_version: IpVersion, domain: &str) -> NftCell {
+        NftCell::Range {
+            port_start: 20000,
+            port_end: 20002,
+            dst_port_start: Some(30000),
+            dst_port_end: Some(30002),
+            domain: domain.to_string(),
+            protocol: Protocol::Tcp,
+            ip_version,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        }
+    }
+
+    #[test]
+    fn test_build_range_remap_ipv4() {
+        let result = range_cell(IpVersion::V4, "10.0.0.1")
+            .build(&Settings::default())
+            .unwrap();
+        assert!(result.contains(
+            "tcp dport 20000-20002 counter dnat ip to tcp dport map { 20000 : 10.0.0.1 . 30000, 20001 : 10.0.0.1 . 30001, 20002 : 10.0.0.1 . 30002 }"
+        ));
+        assert!(result.contains("ip daddr 10.0.0.1 tcp dport 30000-30002 counter"));
+    }
+
+    #[test]
+    fn test_build_range_remap_ipv6() {
+        let result = range_cell(IpVersion::V6, "2001:db8::1")
+            .build(&Settings::default())
+            .unwrap();
+        assert!(result.contains(
+            "dnat ip6 to tcp dport map { 20000 : 2001:db8::1 . 30000, 20001 : 2001:db8::1 . 30001, 20002 : 2001:db8::1 . 30002 }"
+        ));
+        assert!(result.contains("ip6 daddr 2001:db8::1 tcp dport 30000-30002 counter"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod filter_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_accept_before_drop() {
+        let cells = [
+            NftCell::try_from("ACCEPT,input,src_ip=10.0.0.0/8,dst_port=22,tcp").unwrap(),
+            NftCell::try_from("DROP,input,dst_port=22,tcp").unwrap(),
+        ];
+        let script: String = cells
+            .iter()
+            .map(|cell| cell.build(&Settings::default()).unwrap())
+            .collect();
+
+        let accept = script
+            .find("add rule ip self-filter INPUT ip saddr 10.0.0.0/8 tcp dport 22 counter accept")
+            .unwrap();
+        let drop = script
+            .find("add rule ip self-filter INPUT tcp dport 22 counter drop")
+            .unwrap();
+        assert!(accept < drop);
+        // 未指定IP的Drop规则同时生成IPv6规则
+        assert!(script.contains("add rule ip6 self-filter INPUT tcp dport 22 counter drop"));
+        assert!(!script.contains("ip6 self-filter INPUT ip6 saddr"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod limit_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_packet_limit() {
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,limit=100/second burst 200")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        let limit = result
+            .find("add rule ip self-filter PREROUTING ct state new tcp dport 10000 limit rate over 100/second burst 200 packets counter drop")
+            .unwrap();
+        let dnat = result.find("add rule ip self-nat PREROUTING").unwrap();
+        assert!(limit < dnat);
+    }
+
+    #[test]
+    fn test_build_redirect_with_byte_limit() {
+        let cell =
+            NftCell::try_from("REDIRECT,8000-8010,3128,tcp,all,limit=10 mbytes/second").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter PREROUTING tcp dport 8000-8010 limit rate over 10 mbytes/second counter drop"
+        ));
+        assert!(result.contains(
+            "add rule ip6 self-filter PREROUTING tcp dport 8000-8010 limit rate over 10 mbytes/second counter drop"
+        ));
+    }
+
+    #[test]
+    fn test_build_filter_with_limit() {
+        let cell = NftCell::try_from("ACCEPT,input,dst_port=22,limit=10/minute,tcp").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT tcp dport 22 limit rate over 10/minute counter drop"
+        ));
+        assert!(result.contains("add rule ip self-filter INPUT tcp dport 22 counter accept"));
+
+        let cell = NftCell::try_from("DROP,input,dst_port=22,limit=10/minute,tcp").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains("limit rate over 10/minute counter drop"));
+        assert!(!result.contains("tcp dport 22 counter drop"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod conn_limit_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_conn_limit_ipv4() {
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,max_conns_per_src=20").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add set ip self-filter connlimit_single-tcp-10000-ipv4 { type ipv4_addr; size 65535; flags dynamic; }"
+        ));
+        assert!(result.contains(
+            "add rule ip self-filter PREROUTING ct state new tcp dport 10000 add @connlimit_single-tcp-10000-ipv4 { ip saddr ct count over 20 } counter drop"
+        ));
+
+        // 同端口不同协议、或指定了id的规则使用各自的集合
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,udp,ipv4,max_conns_per_src=20").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains("add @connlimit_single-udp-10000-ipv4 "));
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,max_conns_per_src=20,id=web")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains("add set ip self-filter connlimit_web "));
+    }
+
+    #[test]
+    fn test_build_range_conn_limit_ipv6() {
+        let cell = NftCell::try_from("RANGE,20000,20100,2001:db8::1,tcp,ipv6,max_conns_per_src=5")
+            .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add set ip6 self-filter connlimit_range-tcp-20000-20100-ipv6 { type ipv6_addr; size 65535; flags dynamic; }"
+        ));
+        assert!(result.contains(
+            "add @connlimit_range-tcp-20000-20100-ipv6 { ip6 saddr ct count over 5 } counter drop"
+        ));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod ifname_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_ifname() {
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,iifname=eth0,oifname=wg0")
+            .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING iifname \"eth0\" ct state new tcp dport 10000 counter dnat to 10.0.0.1:443"
+        ));
+        assert!(result.contains(
+            "add rule ip self-nat POSTROUTING oifname \"wg0\" ct state new ip daddr 10.0.0.1 tcp dport 443 counter masquerade"
+        ));
+    }
+
+    #[test]
+    fn test_build_redirect_with_iifname_and_limit() {
+        let cell =
+            NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,limit=100/second,iifname=eth*").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter PREROUTING iifname \"eth*\" ct state new tcp dport 8000 limit rate over 100/second"
+        ));
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING iifname \"eth*\" ct state new tcp dport 8000 redirect to :3128"
+        ));
+    }
+
+    #[test]
+    fn test_build_drop_with_iifname() {
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,iifname=eth0,all").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT iifname \"eth0\" ip saddr 1.2.3.4 counter drop"
+        ));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod snat_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_snat_none() {
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,snat=none").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains("dnat to 10.0.0.1:443"));
+        assert!(!result.contains("POSTROUTING"));
+    }
+
+    #[test]
+    fn test_build_snat_from_settings() {
+        let settings = Settings {
+            snat_ipv4: Some(Snat::Address("192.168.1.2".to_string())),
+            snat_ipv6: None,
+            geoip: None,
+            hairpin: false,
+            relay_udp_timeout: None,
+        };
+        let cell = NftCell::try_from("RANGE,1000,2000,10.0.0.1,tcp,ipv4").unwrap();
+        let result = cell.build(&settings).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat POSTROUTING ct state new ip daddr 10.0.0.1 tcp dport 1000-2000 counter snat to 192.168.1.2"
+        ));
+
+        // 规则自身的配置优先于全局设置
+        let cell = NftCell::try_from("RANGE,1000,2000,10.0.0.1,tcp,ipv4,snat=masquerade").unwrap();
+        let result = cell.build(&settings).unwrap();
+        assert!(result.contains("tcp dport 1000-2000 counter masquerade"));
+    }
+
+    #[test]
+    fn test_build_balance_snat_address() {
+        let cell = NftCell::try_from(
+            "BALANCE,10000,443,10.0.0.1|10.0.0.2,tcp,ipv4,random,snat=192.168.1.2",
+        )
+        .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat POSTROUTING ct state new ip daddr { 10.0.0.1, 10.0.0.2 } tcp dport 443 counter snat to 192.168.1.2"
+        ));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod ip_set_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_ip_set_from_elements_and_file() {
+        let path = std::env::temp_dir().join("nat_ip_set_test.txt");
+        fs::write(&path, "# 注释\n10.1.0.0/16\n\n2001:db8::1\nnot-an-ip\n").unwrap();
+        let set = IpSet {
+            name: "blocklist".to_string(),
+            elements: vec!["192.0.2.7/24".to_string()],
+            file: Some(path.to_string_lossy().to_string()),
+            comment: None,
+        };
+        let result = build_ip_set(&set);
+        assert!(result.contains(
+            "add set ip self-filter blocklist { type ipv4_addr; flags interval; auto-merge; }"
+        ));
+        assert!(
+            result.contains("add element ip self-filter blocklist { 192.0.2.0/24, 10.1.0.0/16 }")
+        );
+        assert!(result.contains("add element ip6 self-filter blocklist { 2001:db8::1 }"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_build_ip_set_missing_file() {
+        let set = IpSet {
+            name: "allow".to_string(),
+            elements: vec![],
+            file: Some("/nonexistent/nat_ip_set.txt".to_string()),
+            comment: None,
+        };
+        let result = build_ip_set(&set);
+        assert!(result.contains("add set ip6 self-filter allow"));
+        assert!(!result.contains("add element"));
+    }
+
+    #[test]
+    fn test_build_rules_with_src_set() {
+        let toml_str = r#"
+[[sets]]
+name = "blocklist"
+elements = ["192.0.2.0/24"]
+
+[[rules]]
+type = "drop"
+chain = "input"
+src_set = "blocklist"
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.1"
+protocol = "tcp"
+src_set = "blocklist"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        let result = config.rules[0].build(&Settings::default()).unwrap();
+        assert!(result.contains("add rule ip self-filter INPUT ip saddr @blocklist counter drop"));
+        assert!(
+            result.contains("add rule ip6 self-filter INPUT ip6 saddr @blocklist counter drop")
+        );
+
+        let result = config.rules[1].build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter PREROUTING ct state new ip saddr != @blocklist tcp dport 10000 counter drop"
+        ));
+    }
+
+    #[test]
+    fn test_build_rules_with_src_country() {
+        let toml_str = r#"
+[settings]
+geoip = "/etc/nftables-nat/geoip"
+
+[[rules]]
+type = "drop"
+chain = "forward"
+src_country = ["RU", "CN"]
+
+[[rules]]
+type = "range"
+port_start = 20000
+port_end = 20100
+domain = "2001:db8::1"
+protocol = "udp"
+ip_version = "ipv6"
+src_country = ["JP"]
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        let result = config.rules[0].build(&Settings::default()).unwrap();
+        assert!(
+            result.contains("add rule ip self-filter FORWARD ip saddr @geoip_cn_ru counter drop")
+        );
+        assert!(
+            result.contains("add rule ip6 self-filter FORWARD ip6 saddr @geoip_cn_ru counter drop")
+        );
+
+        let result = config.rules[1].build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip6 self-filter PREROUTING ct state new ip6 saddr != @geoip_jp udp dport 20000-20100 counter drop"
+        ));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod schedule_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_redirect_with_schedule() {
+        let cell =
+            NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,schedule=mon|fri 09:00-18:00 UTC")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING meta day { \"Monday\", \"Friday\" } meta hour \"09:00\"-\"17:59:59\" ct state new tcp dport 8000 redirect to :3128"
+        ));
+    }
+
+    #[test]
+    fn test_build_drop_with_night_schedule() {
+        let cell =
+            NftCell::try_from("DROP,input,src_ip=1.2.3.4,schedule=22:00-06:00 UTC,all").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT meta hour \"00:00\"-\"05:59:59\" ip saddr 1.2.3.4 counter drop"
+        ));
+        assert!(result.contains(
+            "add rule ip self-filter INPUT meta hour \"22:00\"-\"23:59:59\" ip saddr 1.2.3.4 counter drop"
+        ));
+    }
+
+    #[test]
+    fn test_build_schedule_keeps_quota_unconditioned() {
+        let cell = NftCell::try_from(
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=1 GiB,limit=10/second,schedule=mon 09:00-18:00 UTC",
+        )
+        .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        let rules: Vec<&str> = result
+            .lines()
+            .filter(|line| line.starts_with("add rule "))
+            .collect();
+        // 只有DNAT入口规则带时间条件
+        for rule in &rules {
+            assert_eq!(
+                rule.contains("meta hour"),
+                rule.contains("self-nat PREROUTING") || rule.contains("self-nat OUTPUT"),
+                "{rule}"
+            );
+        }
+        assert!(
+            rules
+                .iter()
+                .any(|rule| rule.contains("self-nat PREROUTING meta day \"Monday\" meta hour"))
+        );
+        assert!(
+            rules
+                .iter()
+                .any(|rule| rule.contains("quota name") && !rule.contains("meta hour"))
+        );
+        assert!(
+            rules
+                .iter()
+                .any(|rule| rule.contains("limit rate over") && !rule.contains("meta hour"))
+        );
+        assert!(
+            rules
+                .iter()
+                .any(|rule| rule.contains("POSTROUTING") && !rule.contains("meta hour"))
+        );
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod filter_action_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_reject_tcp() {
+        let cell =
+            NftCell::try_from("DROP,input,src_ip=1.2.3.4,dst_port=22,action=reject,tcp").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT ip saddr 1.2.3.4 tcp dport 22 counter reject with tcp reset"
+        ));
+    }
+
+    #[test]
+    fn test_build_reject_all_protocols_ipv6() {
+        let cell =
+            NftCell::try_from("DROP,forward,src_ip=2001:db8::/32,action=reject,all").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip6 self-filter FORWARD ip6 saddr 2001:db8::/32 meta l4proto tcp counter reject with tcp reset"
+        ));
+        assert!(result.contains(
+            "add rule ip6 self-filter FORWARD ip6 saddr 2001:db8::/32 counter reject with icmpv6 type port-unreachable"
+        ));
+    }
+
+    #[test]
+    fn test_build_log_and_drop_with_limit() {
+        let cell =
+            NftCell::try_from("DROP,input,dst_port=53,limit=10/second,action=log-and-drop,udp")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT udp dport 53 limit rate over 10/second counter log prefix \"nat-drop: \" drop"
+        ));
+        assert!(!result.contains("counter drop"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod log_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_log() {
+        let cell =
+            NftCell::try_from("SINGLE,8080,80,127.0.0.1,tcp,ipv4,log=web info 10/minute").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        let log_pos = result
+            .find("add rule ip self-nat PREROUTING ct state new tcp dport 8080 limit rate 10/minute log prefix \"web \" level info comment")
+            .unwrap();
+        let rule_pos = result.find("redirect to :80").unwrap();
+        assert!(log_pos < rule_pos);
+    }
+
+    #[test]
+    fn test_build_filter_with_log() {
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,log=blocked,all").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter INPUT ip saddr 1.2.3.4 log prefix \"blocked \" comment"
+        ));
+        assert!(result.contains("add rule ip self-filter INPUT ip saddr 1.2.3.4 counter drop"));
+    }
+
+    #[test]
+    fn test_build_without_log() {
+        let cell = NftCell::try_from("SINGLE,8080,80,127.0.0.1,tcp,ipv4").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(!result.contains(" log"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod protocol_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_sctp_forward() {
+        let cell = NftCell::try_from("SINGLE,3868,3868,127.0.0.1,sctp,ipv4").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains("ct state new sctp dport 3868 redirect to :3868"));
+    }
+
+    #[test]
+    fn test_build_icmp_filter_only_ipv4() {
+        let cell = NftCell::try_from("DROP,input,icmp:echo-request").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(
+            result.contains("add rule ip self-filter INPUT icmp type echo-request counter drop")
+        );
+        assert!(!result.contains("ip6 self-filter"));
+    }
+
+    #[test]
+    fn test_build_icmpv6_and_l4proto_filter() {
+        let cell = NftCell::try_from("ACCEPT,input,src_ip=2001:db8::/32,icmpv6").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip6 self-filter INPUT ip6 saddr 2001:db8::/32 meta l4proto ipv6-icmp counter accept"
+        ));
+
+        let cell = NftCell::try_from("DROP,forward,src_ip=10.0.0.0/8,l4proto:47").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter FORWARD ip saddr 10.0.0.0/8 meta l4proto 47 counter drop"
+        ));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod multi_port_build_tests {
+    use super::*;
+
+    fn build(line: &str) -> String {
+        NftCell::try_from(line)
+            .unwrap()
+            .build(&Settings::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_build_ports_to_single_dport() {
+        let result = build("SINGLE,80|443,8080,10.0.0.1,tcp,ipv4");
+        assert!(result.contains("tcp dport { 80, 443 } counter dnat to 10.0.0.1:8080"));
+        assert!(result.contains("ip daddr 10.0.0.1 tcp dport 8080 counter masquerade"));
+    }
+
+    #[test]
+    fn test_build_ports_keep_original() {
+        let result = build("SINGLE,80|443|8000-8100,80|443|8000-8100,10.0.0.1,tcp,ipv4");
+        assert!(
+            result.contains("tcp dport { 80, 443, 8000-8100 } counter dnat to 10.0.0.1  comment")
+        );
+        assert!(result.contains("ip daddr 10.0.0.1 tcp dport { 80, 443, 8000-8100 } counter"));
+    }
+
+    #[test]
+    fn test_build_ports_one_to_one_map() {
+        let result = build("SINGLE,80|443,8080|8443,10.0.0.1,tcp,ipv4");
+        assert!(result.contains(
+            "tcp dport { 80, 443 } counter dnat ip to tcp dport map { 80 : 10.0.0.1 . 8080, 443 : 10.0.0.1 . 8443 }"
+        ));
+
+        let result = build("SINGLE,80|443,8080|8443,localhost,tcp,ipv4");
+        assert!(
+            result.contains(
+                "tcp dport { 80, 443 } redirect to tcp dport map { 80 : 8080, 443 : 8443 }"
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_filter_port_list() {
+        let result = build("DROP,input,dst_port=22|3306,tcp");
+        assert!(
+            result.contains("add rule ip self-filter INPUT tcp dport { 22, 3306 } counter drop")
+        );
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod hairpin_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_hairpin() {
+        let cell =
+            NftCell::try_from("SINGLE,8080,80,192.168.1.10,tcp,ipv4,iifname=eth0,hairpin=true")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat OUTPUT ct state new fib daddr type local ip daddr != 127.0.0.0/8 tcp dport 8080 counter dnat to 192.168.1.10:80 comment"
+        ));
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING iifname != \"eth0\" ct state new fib daddr type local tcp dport 8080 counter dnat to 192.168.1.10:80 comment"
+        ));
+        assert!(result.contains(
+            "add rule ip self-nat POSTROUTING iifname != \"eth0\" ct status dnat ip daddr 192.168.1.10 tcp dport 80 counter masquerade comment"
+        ));
+    }
+
+    #[test]
+    fn test_build_hairpin_keeps_snat_none() {
+        let cell = NftCell::try_from("SINGLE,8080,80,192.168.1.10,tcp,ipv4,snat=none,hairpin=true")
+            .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        let masquerade: Vec<&str> = result
+            .lines()
+            .filter(|line| line.contains("masquerade"))
+            .collect();
+        // 外部客户端保留源地址，只对本机发起的回环连接做masquerade
+        assert_eq!(masquerade.len(), 1);
+        assert!(masquerade[0].starts_with(
+            "add rule ip self-nat POSTROUTING fib saddr type local ct status dnat ip daddr 192.168.1.10 tcp dport 80 counter masquerade"
+        ));
+    }
+
+    #[test]
+    fn test_build_redirect_hairpin_from_settings() {
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv6").unwrap();
+        let settings = Settings {
+            hairpin: true,
+            ..Default::default()
+        };
+        let result = cell.build(&settings).unwrap();
+        assert!(result.contains(
+            "add rule ip6 self-nat OUTPUT ct state new fib daddr type local ip6 daddr != ::1 tcp dport 8080 counter redirect to :3128 comment"
+        ));
+        assert!(!result.contains("ct status dnat"));
+    }
+
+    #[test]
+    fn test_build_rule_disables_global_hairpin() {
+        let cell = NftCell::try_from("SINGLE,8080,80,192.168.1.10,tcp,ipv4,hairpin=false").unwrap();
+        let settings = Settings {
+            hairpin: true,
+            ..Default::default()
+        };
+        let result = cell.build(&settings).unwrap();
+        assert!(!result.contains("OUTPUT"));
+        assert!(!Settings::default().hairpin);
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod dual_stack_build_tests {
+    use super::*;
+
+    fn both() -> (Option<String>, Option<String>) {
+        (
+            Some("10.0.0.1".to_string()),
+            Some("2001:db8::1".to_string()),
+        )
+    }
+
+    #[test]
+    fn test_build_single_dual_stack() {
+        let cell = NftCell::try_from("SINGLE,8080,80,example.com,tcp,all").unwrap();
+        let result =
+            build_dual_stack_rules(&cell, "example.com", both(), &Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-nat PREROUTING ct state new tcp dport 8080 counter dnat to 10.0.0.1:80"
+        ));
+        assert!(result.contains(
+            "add rule ip6 self-nat PREROUTING ct state new tcp dport 8080 counter dnat to [2001:db8::1]:80"
+        ));
+        assert!(
+            result.contains("add rule ip6 self-nat POSTROUTING ct state new ip6 daddr 2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn test_build_dual_stack_fallback() {
+        let cell = NftCell::try_from("RANGE,1000,2000,example.com,udp,all").unwrap();
+        let result = build_dual_stack_rules(
+            &cell,
+            "example.com",
+            (None, Some("2001:db8::1".to_string())),
+            &Settings::default(),
+        )
+        .unwrap();
+        assert!(result.contains("add rule ip6 self-nat PREROUTING"));
+        assert!(!result.contains("add rule ip self-nat"));
+    }
+
+    #[test]
+    fn test_build_dual_stack_respects_allow_src_family() {
+        let cell = NftCell::try_from("SINGLE,8080,80,example.com,tcp,all,allow_src=192.168.0.0/16")
+            .unwrap();
+        let result =
+            build_dual_stack_rules(&cell, "example.com", both(), &Settings::default()).unwrap();
+        assert!(result.contains("ip saddr { 192.168.0.0/16 }"));
+        assert!(!result.contains("ip6 self-nat"));
+    }
+
+    #[test]
+    fn test_build_balance_dual_stack() {
+        let cell =
+            NftCell::try_from("BALANCE,10000,443,a.example.com|b.example.com,tcp,all").unwrap();
+        let NftCell::Balance { targets, .. } = &cell else {
+            unreachable!()
+        };
+        let resolved = vec![
+            both(),
+            (
+                Some("10.0.0.2".to_string()),
+                Some("2001:db8::2".to_string()),
+            ),
+        ];
+        let result =
+            build_dual_stack_balance_rules(&cell, targets, resolved, &Settings::default()).unwrap();
+        assert!(result.contains("map { 0 : 10.0.0.1 . 443, 1 : 10.0.0.2 . 443 }"));
+        assert!(result.contains("map { 0 : 2001:db8::1 . 443, 1 : 2001:db8::2 . 443 }"));
+
+        // 某个后端只有IPv4地址时，IPv6不生成规则
+        let resolved = vec![both(), (Some("10.0.0.2".to_string()), None)];
+        let result =
+            build_dual_stack_balance_rules(&cell, targets, resolved, &Settings::default()).unwrap();
+        assert!(result.contains("add rule ip self-nat PREROUTING"));
+        assert!(!result.contains("ip6 self-nat"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod relay_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_relay_skips_dnat() {
+        let cell =
+            NftCell::try_from("SINGLE,8080,80,10.0.0.1,tcp,ipv6,mode=relay,limit=100/second")
+                .unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert!(result.starts_with("# relay: SINGLE,8080,80,10.0.0.1,tcp,ipv6"));
+        assert!(!result.contains("self-nat"));
+        assert!(result.contains(
+            "add rule ip self-filter PREROUTING ct state new tcp dport 8080 limit rate over 100/second counter drop"
+        ));
+        assert!(result.contains("add rule ip6 self-filter PREROUTING ct state new tcp dport 8080"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod legacy_rule_id_tests {
+    use super::*;
+
+    #[test]
+    fn test_read_config_rule_ids() {
+        let path = std::env::temp_dir().join("nat_legacy_rule_id_test.conf");
+        fs::write(
+            &path,
+            "SINGLE,10000,443,192.168.1.10,tcp,ipv4,iifname=br-lan,quota=1 GiB\n\
+            SINGLE,10000,443,10.0.0.1,tcp,ipv4,iifname=eth0,quota=1 GiB\n\
+            SINGLE,10000,443,10.0.0.2,tcp,ipv4,iifname=eth0\n",
+        )
+        .unwrap();
+        let config = read_config(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+        let rules: Vec<&NftCell> = config
+            .cells
+            .iter()
+            .filter_map(|cell| match cell {
+                RuntimeCell::Rule(rule) => Some(rule),
+                RuntimeCell::Comment(_) => None,
+            })
+            .collect();
+        // LAN/WAN分流的两条规则各自使用配额对象，重复的第三条被跳过
+        assert_eq!(rules.len(), 2);
+        assert_ne!(
+            quota::object_name(rules[0]).unwrap(),
+            quota::object_name(rules[1]).unwrap()
+        );
+        assert_eq!(rules[1].iifname(), Some("eth0"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod enabled_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_legacy_line_rejects_src_set() {
+        for line in [
+            "DROP,input,src_set=blocklist,all",
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,src_set=office",
+        ] {
+            assert!(parse_legacy_line(line).is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_legacy_line_rejects_src_country() {
+        for line in [
+            "DROP,input,src_country=CN|HK,all",
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,src_country=CN",
+        ] {
+            assert!(parse_legacy_line(line).is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_disabled_legacy_line() {
+        let line = "SINGLE,8080,80,10.0.0.1,tcp,ipv4,enabled=false";
+        match parse_legacy_line(line) {
+            Some(RuntimeCell::Comment(content)) => {
+                assert_eq!(content, format!("# disabled: {line}"))
+            }
+            other => panic!("Expected Comment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_toml_config_skips_disabled() {
+        let path = std::env::temp_dir().join("nat_enabled_test.toml");
+        fs::write(
+            &path,
+            r#"
+[[rules]]
+type = "single"
+sport = 8080
+dport = 80
+domain = "10.0.0.1"
+comment = "暂停的转发"
+enabled = false
+
+[[rules]]
+type = "drop"
+chain = "input"
+src_ip = "1.2.3.4"
+"#,
+        )
+        .unwrap();
+        let config = read_toml_config(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut script = String::new();
+        for cell in &config.cells {
+            script += &cell.build(&config.settings).unwrap();
+        }
+        assert!(
+            script.contains(
+                "# 暂停的转发\n# disabled: SINGLE,8080,80,10.0.0.1,all,all,enabled=false\n"
+            )
+        );
+        assert!(!script.contains("self-nat"));
+        assert!(script.contains("add rule ip self-filter INPUT ip saddr 1.2.3.4 counter drop"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod group_config_tests {
+    use super::*;
+
+    #[test]
+    fn test_read_toml_config_disabled_group() {
+        let path = std::env::temp_dir().join("nat_group_test.toml");
+        fs::write(
+            &path,
+            r#"
+[[groups]]
+name = "acme"
+comment = "客户A"
+
+[[groups]]
+name = "globex"
+enabled = false
+
+[[rules]]
+type = "single"
+sport = 8080
+dport = 80
+domain = "10.0.0.1"
+group = "acme"
+tags = ["web"]
+
+[[rules]]
+type = "single"
+sport = 9090
+dport = 90
+domain = "10.0.0.2"
+group = "globex"
+"#,
+        )
+        .unwrap();
+        let config = read_toml_config(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.groups.len(), 2);
+        assert_eq!(config.cells[0].group(), Some("acme"));
+        match &config.cells[1] {
+            RuntimeCell::Comment(content) => {
+                assert_eq!(
+                    content,
+                    "# disabled: SINGLE,9090,90,10.0.0.2,all,all,group=globex"
+                )
+            }
+            other => panic!("Expected Comment, got {:?}", other),
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod quota_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_quota() {
+        let cell = NftCell::try_from(
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=500 GiB,quota_reset=monthly",
+        )
+        .unwrap();
+        let result = build_cell(&cell, &Settings::default()).unwrap();
+        assert!(result.contains(
+            "add quota ip self-filter quota_single-tcp-10000-ipv4 { over 536870912000 bytes }\n"
+        ));
+        for chain in ["FORWARD", "INPUT", "OUTPUT"] {
+            assert!(result.contains(&format!(
+                "add rule ip self-filter QUOTA_{chain} ct status dnat ct reply ip saddr 10.0.0.1 meta l4proto tcp ct original proto-dst 10000 quota name \"quota_single-tcp-10000-ipv4\" counter drop"
+            )));
+        }
+    }
+
+    #[test]
+    fn test_build_quota_matches_own_connections() {
+        // 同一端口按入站网卡分流的两条规则，配额只计入各自的连接
+        let cell = NftCell::try_from(
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,iifname=eth0,allow_src=1.2.3.0/24,quota=1 GiB",
+        )
+        .unwrap();
+        let name = quota::object_name(&cell).unwrap();
+        let result = build_cell(&cell, &Settings::default()).unwrap();
+        let conntrack = "ct status dnat ct original ip saddr { 1.2.3.0/24 } ct reply ip saddr 10.0.0.1 meta l4proto tcp ct original proto-dst 10000";
+        for rule in [
+            format!(
+                "QUOTA_FORWARD ct direction original iifname \"eth0\" {conntrack} quota name \"{name}\""
+            ),
+            format!(
+                "QUOTA_FORWARD ct direction reply oifname \"eth0\" {conntrack} quota name \"{name}\""
+            ),
+            format!("QUOTA_INPUT iifname \"eth0\" {conntrack} quota name \"{name}\""),
+            format!("QUOTA_OUTPUT oifname \"eth0\" {conntrack} quota name \"{name}\""),
+        ] {
+            assert!(result.contains(&rule), "{rule}\n{result}");
+        }
+    }
+
+    #[test]
+    fn test_quota_before_accept() {
+        let accept = NftCell::try_from("ACCEPT,forward,dst_port=443,tcp").unwrap();
+        let quota = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=1 GiB").unwrap();
+        let script = build_cell(&accept, &Settings::default()).unwrap()
+            + &build_cell(&quota, &Settings::default()).unwrap();
+        // accept 在 FORWARD 过滤链中，配额在优先级更早的 QUOTA_FORWARD 链中，先于 accept 执行
+        assert!(script.contains("add rule ip self-filter FORWARD tcp dport 443 counter accept"));
+        assert!(!script.contains("add rule ip self-filter FORWARD ct status dnat"));
+        assert!(script.contains("add rule ip self-filter QUOTA_FORWARD ct status dnat"));
+        assert!(build_quota_chains().contains(
+            "add chain ip self-filter QUOTA_FORWARD { type filter hook forward priority filter - 2 ; }"
+        ));
+    }
+
+    #[test]
+    fn test_build_redirect_with_quota_all_protocols() {
+        let cell = NftCell::try_from("REDIRECT,8000-8010,3128,all,ipv4,quota=10 GB").unwrap();
+        let result = build_cell(&cell, &Settings::default()).unwrap();
+        assert!(result.contains(
+            "add quota ip self-filter quota_redirect-all-8000-8010-ipv4 { over 10000000000 bytes }"
+        ));
+        assert!(result.contains(
+            "add rule ip self-filter QUOTA_INPUT ct status dnat meta l4proto { tcp, udp } ct original proto-dst 8000-8010 quota name \"quota_redirect-all-8000-8010-ipv4\""
+        ));
+        assert!(!result.contains("ip6"));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod mark_build_tests {
+    use super::*;
+
+    #[test]
+    fn test_build_single_with_marks() {
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,mark=0x10,ct_mark=0x10").unwrap();
+        let result = build_cell(&cell, &Settings::default()).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter MANGLE ct direction original tcp dport 10000 meta mark set 0x10 ct mark set 0x10 comment"
+        ));
+    }
+
+    #[test]
+    fn test_build_accept_with_mark() {
+        let cell = NftCell::try_from("ACCEPT,forward,src_ip=10.0.0.0/8,mark=0x2,tcp").unwrap();
+        let result = build_filter_rule(&cell).unwrap();
+        assert!(result.contains(
+            "add rule ip self-filter FORWARD ip saddr 10.0.0.0/8 tcp counter meta mark set 0x2 accept"
+        ));
+    }
+
+    #[test]
+    fn test_build_mark_restore_rules() {
+        let cells = vec![RuntimeCell::Rule(
+            NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,mark=0x1").unwrap(),
+        )];
+        assert_eq!(build_mark_restore_rules(&cells), "");
+
+        let cells = vec![RuntimeCell::Rule(
+            NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,ct_mark=0x1").unwrap(),
+        )];
+        let result = build_mark_restore_rules(&cells);
+        for family in ["ip", "ip6"] {
+            for chain in ["MANGLE", "ROUTE"] {
+                assert!(result.contains(&format!(
+                    "add rule {family} self-filter {chain} ct direction reply ct mark != 0 meta mark set ct mark\n"
+                )));
+            }
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod expiry_tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_cells() {
+        let mut cells = vec![
+            RuntimeCell::Rule(
+                NftCell::try_from(
+                    "SINGLE,10000,443,10.0.0.1,tcp,ipv4,expires_at=2026-10-17T10:00:00+08:00",
+                )
+                .unwrap(),
+            ),
+            RuntimeCell::Rule(
+                NftCell::try_from("DROP,input,src_ip=1.2.3.4,expires_at=2026-10-18T00:00:00Z,all")
+                    .unwrap(),
+            ),
+            RuntimeCell::Rule(
+                NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,expires_at=2026-10-17T12:00:00Z")
+                    .unwrap(),
+            ),
+            RuntimeCell::Rule(NftCell::try_from("REDIRECT,8081,3128,tcp,ipv4").unwrap()),
+        ];
+        let now = DateTime::parse_from_rfc3339("2026-10-17T06:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let (expired, next) = expire_cells(&mut cells, now);
+        assert_eq!(
+            expired,
+            vec!["SINGLE,10000,443,10.0.0.1,tcp,ipv4,expires_at=2026-10-17T10:00:00+08:00"]
+        );
+        assert_eq!(
+            next.unwrap(),
+            DateTime::parse_from_rfc3339("2026-10-17T12:00:00Z").unwrap()
+        );
+        match &cells[0] {
+            RuntimeCell::Comment(content) => assert_eq!(
+                content,
+                "# expired: SINGLE,10000,443,10.0.0.1,tcp,ipv4,expires_at=2026-10-17T10:00:00+08:00"
+            ),
+            other => panic!("Expected Comment, got {:?}", other),
+        }
+        assert!(matches!(cells[3], RuntimeCell::Rule(_)));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod rule_id_tests {
+    use super::*;
+
+    /// 取出脚本中所有规则注释
+    fn nft_comments(script: &str) -> Vec<&str> {
+        script
+            .split(" comment \"")
+            .skip(1)
+            .filter_map(|rest| rest.split_once('"').map(|(comment, _)| comment))
+            .collect()
+    }
+
+    #[test]
+    fn test_comment_prefix_and_summary() {
+        let cell = RuntimeCell::Rule(
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,id=web,limit=10/second").unwrap(),
+        );
+        let result = cell.build(&Settings::default()).unwrap();
+        let comments = nft_comments(&result);
+        assert!(comments.len() >= 2);
+        for comment in comments {
+            assert_eq!(comment, "[web] SINGLE tcp 10000->10.0.0.1:443");
+        }
+
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,dst_port=22,tcp").unwrap();
+        let result = cell.build(&Settings::default()).unwrap();
+        assert_eq!(
+            nft_comments(&result),
+            vec![format!(
+                "[{}] DROP input tcp src_ip=1.2.3.4 dst_port=22",
+                cell.rule_id()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_comment_length_limit() {
+        let settings = Settings {
+            hairpin: true,
+            ..Settings::default()
+        };
+        let lines = [
+            "SINGLE,10000|10001|10002,443|444|445,10.0.0.1,tcp,ipv4,id=customer_a_https_primary,\
+             limit=10/second,quota=500 GiB,quota_reset=monthly,tags=a|b|c,\
+             expires_at=2026-10-18T18:00:00+08:00,snat=10.0.0.254,iifname=eth0,oifname=eth1,\
+             log=web info 10/minute,mark=0x10,ct_mark=0x10,max_conns_per_src=10,\
+             allow_src=192.168.0.0/16|10.0.0.0/8",
+            "BALANCE,10000,443,10.0.0.1*3|10.0.0.2*3|10.0.0.3*3|10.0.0.4*3|10.0.0.5*3|10.0.0.6*3|10.0.0.7*3|10.0.0.8*3|10.0.0.9*3,tcp,ipv4,round-robin",
+        ];
+        let mut cells: Vec<NftCell> = lines
+            .iter()
+            .map(|line| NftCell::try_from(*line).unwrap())
+            .collect();
+        let mut accept =
+            NftCell::try_from("ACCEPT,input,src_ip=203.0.113.7,dst_port=22,tcp").unwrap();
+        if let NftCell::Accept { comment, .. } = &mut accept {
+            *comment = Some("允许外包团队临时访问SSH端口".repeat(5));
+        }
+        cells.push(accept);
+        for cell in &cells {
+            let result = cell.build(&settings).unwrap();
+            let comments = nft_comments(&result);
+            assert!(!comments.is_empty());
+            for comment in comments {
+                assert!(comment.len() <= NFT_COMMENT_MAX_LEN, "{comment}");
+                assert!(comment.starts_with(&format!("[{}] ", cell.rule_id())));
+            }
+        }
+    }
+}
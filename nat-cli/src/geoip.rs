@@ -0,0 +1,305 @@
+use ipnetwork::IpNetwork;
+use log::{error, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// 规则引用的国家列表对应的集合名，相同国家组合的规则共用一个集合
+pub fn set_name(countries: &[String]) -> String {
+    let countries: BTreeSet<String> = countries.iter().map(|c| c.to_lowercase()).collect();
+    format!(
+        "geoip_{}",
+        countries.into_iter().collect::<Vec<_>>().join("_")
+    )
+}
+
+/// 已加载的国家CIDR，数据库文件的修改时间或引用的国家变化时才重新读取
+#[derive(Debug, Default)]
+pub struct GeoIpCache {
+    fingerprint: Vec<(PathBuf, Option<SystemTime>, u64)>,
+    countries: BTreeSet<String>,
+    networks: HashMap<String, Vec<IpNetwork>>,
+}
+
+impl GeoIpCache {
+    /// 为每个国家组合生成 self-filter 中的 interval 集合（两个family各一份）
+    /// 数据库读取失败时生成空集合，保证引用它们的规则仍能加载
+    pub fn build_sets(&mut self, path: &str, country_lists: &BTreeSet<Vec<String>>) -> String {
+        let countries: BTreeSet<String> = country_lists
+            .iter()
+            .flatten()
+            .map(|c| c.to_uppercase())
+            .collect();
+        if let Err(e) = self.refresh(Path::new(path), countries) {
+            error!("读取GeoIP数据库 {path} 失败: {e}");
+            self.networks.clear();
+            self.fingerprint.clear();
+        }
+
+        let mut result = String::new();
+        for countries in country_lists {
+            let name = set_name(countries);
+            let networks: Vec<&IpNetwork> = countries
+                .iter()
+                .filter_map(|c| self.networks.get(&c.to_uppercase()))
+                .flatten()
+                .collect();
+            for (family, addr_type, is_ipv6) in
+                [("ip", "ipv4_addr", false), ("ip6", "ipv6_addr", true)]
+            {
+                result += &format!(
+                    "add set {family} self-filter {name} {{ type {addr_type}; flags interval; auto-merge; }}\n"
+                );
+                let elements: Vec<String> = networks
+                    .iter()
+                    .filter(|network| network.is_ipv6() == is_ipv6)
+                    .map(|network| network.to_string())
+                    .collect();
+                if !elements.is_empty() {
+                    result += &format!(
+                        "add element {family} self-filter {name} {{ {} }}\n",
+                        elements.join(", ")
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    fn refresh(&mut self, path: &Path, countries: BTreeSet<String>) -> Result<(), io::Error> {
+        let fingerprint = fingerprint(path)?;
+        if fingerprint == self.fingerprint && countries == self.countries {
+            return Ok(());
+        }
+        info!("加载GeoIP数据库 {}，国家: {countries:?}", path.display());
+        self.networks = if let Some(maxmind) = MaxMindFiles::find(path)? {
+            maxmind.load(&countries)?
+        } else {
+            load_country_dir(path, &countries)?
+        };
+        self.fingerprint = fingerprint;
+        self.countries = countries;
+        Ok(())
+    }
+}
+
+/// 数据库目录（含一级子目录）中所有文件的路径、修改时间和大小
+fn fingerprint(path: &Path) -> Result<Vec<(PathBuf, Option<SystemTime>, u64)>, io::Error> {
+    let mut result = Vec::new();
+    for file in list_files(path)? {
+        let metadata = fs::metadata(&file)?;
+        result.push((file, metadata.modified().ok(), metadata.len()));
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// 列出目录及其一级子目录（如 ipv4/、ipv6/）中的文件
+fn list_files(path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            for sub_entry in fs::read_dir(&entry_path)? {
+                let sub_path = sub_entry?.path();
+                if sub_path.is_file() {
+                    files.push(sub_path);
+                }
+            }
+        } else if entry_path.is_file() {
+            files.push(entry_path);
+        }
+    }
+    Ok(files)
+}
+
+/// 每个国家一个CIDR文件的目录，文件名（不含扩展名）为国家代码，如 cn.zone、CN.txt
+fn load_country_dir(
+    path: &Path,
+    countries: &BTreeSet<String>,
+) -> Result<HashMap<String, Vec<IpNetwork>>, io::Error> {
+    let mut result: HashMap<String, Vec<IpNetwork>> = HashMap::new();
+    for file in list_files(path)? {
+        let Some(country) = file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_uppercase())
+            .filter(|stem| countries.contains(stem))
+        else {
+            continue;
+        };
+        let networks = result.entry(country).or_default();
+        for line in fs::read_to_string(&file)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match IpNetwork::from_str(line) {
+                Ok(network) => networks.push(network),
+                Err(e) => warn!("{} 中的无效CIDR {line}: {e}", file.display()),
+            }
+        }
+    }
+    for country in countries {
+        if !result.contains_key(country) {
+            warn!("GeoIP数据库 {} 中没有国家 {country}", path.display());
+        }
+    }
+    Ok(result)
+}
+
+/// MaxMind GeoLite2-Country / GeoIP2-Country 的CSV文件
+struct MaxMindFiles {
+    locations: PathBuf,
+    blocks: Vec<PathBuf>,
+}
+
+impl MaxMindFiles {
+    fn find(path: &Path) -> Result<Option<Self>, io::Error> {
+        let mut locations = None;
+        let mut blocks = Vec::new();
+        for file in list_files(path)? {
+            let name = file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if name.ends_with("Country-Locations-en.csv") {
+                locations = Some(file);
+            } else if name.ends_with("Country-Blocks-IPv4.csv")
+                || name.ends_with("Country-Blocks-IPv6.csv")
+            {
+                blocks.push(file);
+            }
+        }
+        Ok(locations.map(|locations| MaxMindFiles { locations, blocks }))
+    }
+
+    fn load(
+        &self,
+        countries: &BTreeSet<String>,
+    ) -> Result<HashMap<String, Vec<IpNetwork>>, io::Error> {
+        // geoname_id,locale_code,continent_code,continent_name,country_iso_code,...
+        // 国家名可能包含带引号的逗号，但只用到前5列，直接按逗号切分即可
+        let mut geoname_ids: HashMap<String, String> = HashMap::new();
+        for line in fs::read_to_string(&self.locations)?.lines().skip(1) {
+            let columns: Vec<&str> = line.split(',').collect();
+            if let (Some(id), Some(country)) = (columns.first(), columns.get(4)) {
+                let country = country.trim_matches('"').to_uppercase();
+                if countries.contains(&country) {
+                    geoname_ids.insert(id.to_string(), country);
+                }
+            }
+        }
+
+        // network,geoname_id,registered_country_geoname_id,...
+        // geoname_id为空时（如卫星、匿名代理）使用注册国家
+        let mut result: HashMap<String, Vec<IpNetwork>> = HashMap::new();
+        for file in &self.blocks {
+            for line in fs::read_to_string(file)?.lines().skip(1) {
+                let columns: Vec<&str> = line.split(',').collect();
+                let id = match (columns.get(1), columns.get(2)) {
+                    (Some(id), _) if !id.is_empty() => id,
+                    (_, Some(id)) => id,
+                    _ => continue,
+                };
+                let Some(country) = geoname_ids.get(*id) else {
+                    continue;
+                };
+                match IpNetwork::from_str(columns[0]) {
+                    Ok(network) => result.entry(country.clone()).or_default().push(network),
+                    Err(e) => warn!("{} 中的无效CIDR {}: {e}", file.display(), columns[0]),
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_set_name() {
+        assert_eq!(
+            set_name(&["RU".to_string(), "cn".to_string()]),
+            "geoip_cn_ru"
+        );
+    }
+
+    #[test]
+    fn test_build_sets_from_country_dir() {
+        let dir = temp_dir("nat_geoip_country_dir");
+        fs::create_dir_all(dir.join("ipv6")).unwrap();
+        fs::write(dir.join("cn.zone"), "1.0.1.0/24\n1.0.2.0/23\n").unwrap();
+        fs::write(dir.join("ipv6").join("cn.zone"), "2001:250::/35\n").unwrap();
+        fs::write(dir.join("US.txt"), "3.0.0.0/8\n").unwrap();
+
+        let mut cache = GeoIpCache::default();
+        let lists = BTreeSet::from([vec!["CN".to_string()]]);
+        let result = cache.build_sets(&dir.to_string_lossy(), &lists);
+        assert!(result.contains(
+            "add set ip self-filter geoip_cn { type ipv4_addr; flags interval; auto-merge; }"
+        ));
+        assert!(result.contains("1.0.1.0/24"));
+        assert!(result.contains("add element ip6 self-filter geoip_cn { 2001:250::/35 }"));
+        assert!(!result.contains("3.0.0.0/8"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_sets_from_maxmind_csv() {
+        let dir = temp_dir("nat_geoip_maxmind");
+        fs::write(
+            dir.join("GeoLite2-Country-Locations-en.csv"),
+            "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,is_in_european_union\n\
+            1814991,en,AS,Asia,CN,China,0\n\
+            2017370,en,EU,Europe,RU,Russia,0\n\
+            1835841,en,AS,Asia,KR,\"Korea, Republic of\",0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("GeoLite2-Country-Blocks-IPv4.csv"),
+            "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider\n\
+            1.0.1.0/24,1814991,1814991,,0,0\n\
+            1.11.0.0/16,1835841,1835841,,0,0\n\
+            5.3.0.0/16,,2017370,,0,0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("GeoLite2-Country-Blocks-IPv6.csv"),
+            "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider\n\
+            2001:250::/35,1814991,1814991,,0,0\n",
+        )
+        .unwrap();
+
+        let mut cache = GeoIpCache::default();
+        let lists = BTreeSet::from([vec!["CN".to_string(), "RU".to_string()]]);
+        let result = cache.build_sets(&dir.to_string_lossy(), &lists);
+        assert!(
+            result.contains("add element ip self-filter geoip_cn_ru { 1.0.1.0/24, 5.3.0.0/16 }")
+        );
+        assert!(result.contains("add element ip6 self-filter geoip_cn_ru { 2001:250::/35 }"));
+        assert!(!result.contains("1.11.0.0/16"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_sets_missing_database() {
+        let mut cache = GeoIpCache::default();
+        let lists = BTreeSet::from([vec!["CN".to_string()]]);
+        let result = cache.build_sets("/nonexistent/nat_geoip", &lists);
+        assert!(result.contains("add set ip self-filter geoip_cn"));
+        assert!(!result.contains("add element"));
+    }
+}
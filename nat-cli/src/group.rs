@@ -0,0 +1,107 @@
+use nat_common::RuleGroup;
+use std::collections::{BTreeSet, HashMap};
+
+/// 分组规则所在的链名，如 PREROUTING_acme
+pub fn chain_name(chain: &str, group: &str) -> String {
+    format!("{chain}_{group}")
+}
+
+/// 把分组规则移到分组自己的链中
+/// 分组第一次用到某条基础链时创建对应的链，并在基础链的当前位置跳转过去，
+/// 组内后续规则都追加到该链，因此组内规则在该组第一条规则的位置统一生效
+#[derive(Debug, Default)]
+pub struct GroupChains {
+    comments: HashMap<String, String>,
+    announced: BTreeSet<String>,
+    created: BTreeSet<(String, String, String, String)>,
+}
+
+impl GroupChains {
+    pub fn new(groups: &[RuleGroup]) -> Self {
+        GroupChains {
+            comments: groups
+                .iter()
+                .filter_map(|group| Some((group.name.clone(), group.comment.clone()?)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 改写一条规则生成的脚本，其中的 add rule 放入分组链，其余语句保持不变
+    pub fn apply(&mut self, group: &str, script: &str) -> String {
+        let mut result = String::new();
+        for line in script.split_inclusive('\n') {
+            let Some(rest) = line.strip_prefix("add rule ") else {
+                result += line;
+                continue;
+            };
+            // add rule {family} {table} {chain} {expr}
+            let mut parts = rest.splitn(4, ' ');
+            let (Some(family), Some(table), Some(chain), Some(expr)) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                result += line;
+                continue;
+            };
+            let group_chain = chain_name(chain, group);
+            if self.announced.insert(group.to_string())
+                && let Some(comment) = self.comments.get(group)
+            {
+                result += &format!("# group {group}: {comment}\n");
+            }
+            if self.created.insert((
+                family.to_string(),
+                table.to_string(),
+                chain.to_string(),
+                group.to_string(),
+            )) {
+                result += &format!(
+                    "add chain {family} {table} {group_chain}\n\
+                    add rule {family} {table} {chain} jump {group_chain} comment \"group {group}\"\n"
+                );
+            }
+            result += &format!("add rule {family} {table} {group_chain} {expr}");
+        }
+        result
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_group_chains() {
+        let mut chains = GroupChains::new(&[RuleGroup {
+            name: "acme".to_string(),
+            enabled: true,
+            comment: Some("客户A".to_string()),
+        }]);
+        let script = "add set ip self-filter connlimit_80 { type ipv4_addr; size 65535; flags dynamic; }\n\
+            add rule ip self-nat PREROUTING ct state new tcp dport 80 counter dnat to 10.0.0.1:80\n\
+            add rule ip self-nat POSTROUTING ct state new ip daddr 10.0.0.1 tcp dport 80 counter masquerade\n";
+        let result = chains.apply("acme", script);
+        assert_eq!(
+            result,
+            "add set ip self-filter connlimit_80 { type ipv4_addr; size 65535; flags dynamic; }\n\
+            # group acme: 客户A\n\
+            add chain ip self-nat PREROUTING_acme\n\
+            add rule ip self-nat PREROUTING jump PREROUTING_acme comment \"group acme\"\n\
+            add rule ip self-nat PREROUTING_acme ct state new tcp dport 80 counter dnat to 10.0.0.1:80\n\
+            add chain ip self-nat POSTROUTING_acme\n\
+            add rule ip self-nat POSTROUTING jump POSTROUTING_acme comment \"group acme\"\n\
+            add rule ip self-nat POSTROUTING_acme ct state new ip daddr 10.0.0.1 tcp dport 80 counter masquerade\n"
+        );
+
+        // 同一分组的后续规则直接追加到已创建的链
+        let result = chains.apply(
+            "acme",
+            "add rule ip self-nat PREROUTING ct state new tcp dport 443 counter dnat to 10.0.0.1:443\n",
+        );
+        assert_eq!(
+            result,
+            "add rule ip self-nat PREROUTING_acme ct state new tcp dport 443 counter dnat to 10.0.0.1:443\n"
+        );
+    }
+}
@@ -58,6 +58,32 @@ pub fn remote_ip(domain: &String, ip_version: &IpVersion) -> io::Result<String>
     }
 }
 
+/// ip_version=all 时同时解析IPv4和IPv6地址，没有解析到的family为None
+/// 两个family都没有地址时返回错误
+pub fn remote_ips(domain: &str) -> io::Result<(Option<String>, Option<String>)> {
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        return Ok(if ip.is_ipv4() {
+            (Some(ip.to_string()), None)
+        } else {
+            (None, Some(ip.to_string()))
+        });
+    }
+
+    let socket_addrs: Vec<_> = domain.to_string().add(":80").to_socket_addrs()?.collect();
+    let ipv4 = socket_addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .map(|addr| addr.ip().to_string());
+    let ipv6 = socket_addrs
+        .iter()
+        .find(|addr| addr.is_ipv6())
+        .map(|addr| addr.ip().to_string());
+    if ipv4.is_none() && ipv6.is_none() {
+        return Err(io::Error::other("Failed to resolve any IP address"));
+    }
+    Ok((ipv4, ipv6))
+}
+
 #[allow(clippy::unwrap_used)]
 mod test {
 
@@ -116,4 +142,15 @@ mod test {
         println!("Resolved IPv4 for {domain}: {res:?}");
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_remote_ips_literal() {
+        let (ipv4, ipv6) = super::remote_ips("10.0.0.1").unwrap();
+        assert_eq!(ipv4.as_deref(), Some("10.0.0.1"));
+        assert_eq!(ipv6, None);
+
+        let (ipv4, ipv6) = super::remote_ips("2001:db8::1").unwrap();
+        assert_eq!(ipv4, None);
+        assert_eq!(ipv6.as_deref(), Some("2001:db8::1"));
+    }
 }
@@ -1,13 +1,21 @@
 #![deny(warnings)]
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::expect_used)]
+mod accounting;
 mod config;
+mod geoip;
+mod group;
 mod ip;
 mod prepare;
+mod quota;
+mod relay;
+mod schedule;
+mod status;
 
 use clap::Parser;
 use log::{error, info};
 use nat_common::{Args, logger};
+use std::collections::{BTreeSet, HashSet};
 use std::fs::File;
 use std::io::{self, Write};
 use std::process::Command;
@@ -36,8 +44,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn parse_conf(
     args: &Args,
-) -> Result<Vec<config::RuntimeCell>, Box<dyn std::error::Error + Send + Sync>> {
-    let nat_cells = if let Some(compatible_config_file) = &args.compatible_config_file {
+) -> Result<config::RuntimeConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let runtime_config = if let Some(compatible_config_file) = &args.compatible_config_file {
         config::read_config(compatible_config_file).map_err(|e| {
             info!("读取配置文件失败: {e:?}");
             config::example(compatible_config_file);
@@ -54,7 +62,7 @@ fn parse_conf(
     } else {
         return Err("请提供配置文件路径".into());
     };
-    Ok(nat_cells)
+    Ok(runtime_config)
 }
 
 fn global_prepare() -> Result<(), io::Error> {
@@ -99,9 +107,16 @@ fn global_prepare() -> Result<(), io::Error> {
 
 fn handle_loop(args: &Args) -> Result<(), io::Error> {
     let mut latest_script = String::new();
+    let mut geoip_cache = geoip::GeoIpCache::default();
+    let mut relays = relay::RelayManager::default();
+    let mut quotas = quota::QuotaTracker::load();
+    let mut accounting = accounting::Accounting::load();
+    let mut expired_rules = HashSet::new();
+    // 最近一次 nft -f 的错误，保留到下次重载
+    let mut load_errors = status::RuleErrors::new();
     loop {
-        let nat_cells = match parse_conf(args) {
-            Ok(cells) => cells,
+        let mut runtime_config = match parse_conf(args) {
+            Ok(runtime_config) => runtime_config,
             Err(e) => {
                 error!("解析配置文件失败: {e:?}");
                 if cfg!(debug_assertions) {
@@ -112,21 +127,43 @@ fn handle_loop(args: &Args) -> Result<(), io::Error> {
                 continue;
             }
         };
-        let script = build_new_script(&nat_cells)?;
+        let (expired, next_expiry) =
+            config::expire_cells(&mut runtime_config.cells, chrono::Utc::now());
+        for rule in expired {
+            if expired_rules.insert(rule.clone()) {
+                info!("规则已过期，停止生效: {rule}");
+            }
+        }
+        let mut build_errors = status::RuleErrors::new();
+        let script = build_new_script(&runtime_config, &mut geoip_cache, &mut build_errors)?;
+        let udp_timeout = runtime_config
+            .settings
+            .relay_udp_timeout
+            .map_or(relay::DEFAULT_UDP_TIMEOUT, Duration::from_secs);
+        relays.update(relay::collect_specs(&runtime_config.cells), udp_timeout);
         prepare::check_and_prepare()?;
+        quotas.sync(&runtime_config.cells, chrono::Local::now());
         if script != latest_script {
             info!("当前配置: ");
-            for ele in &nat_cells {
+            for ele in &runtime_config.cells {
                 info!("{ele:?}");
             }
             info!("nftables脚本如下：\n{script}");
             latest_script.clone_from(&script);
+            let applied_script = quotas.apply(&script);
             let f = File::create(FILE_NAME_SCRIPT);
             if let Ok(mut file) = f {
-                file.write_all(script.as_bytes())?;
+                file.write_all(applied_script.as_bytes())?;
             }
 
+            // 重建表会清零规则计数，重载前先读出本轮的计数
+            let live = accounting::read_live().unwrap_or_else(|e| {
+                error!("读取规则计数失败: {e}");
+                accounting::LiveRules::default()
+            });
+            // 规则中的 meta hour 已换算为UTC，需要让nft按UTC解析
             let output = Command::new("/usr/sbin/nft")
+                .env("TZ", "UTC")
                 .arg("-f")
                 .arg(FILE_NAME_SCRIPT)
                 .output()?;
@@ -134,21 +171,41 @@ fn handle_loop(args: &Args) -> Result<(), io::Error> {
                 "执行/usr/sbin/nft -f {FILE_NAME_SCRIPT} 执行结果: {}",
                 output.status
             );
+            let stderr = String::from_utf8_lossy(&output.stderr);
             log::info!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-            log::error!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+            log::error!("stderr: {stderr}");
+            load_errors = status::nft_errors(&applied_script, &stderr);
+            // 重载失败时旧表和计数仍在，下次重载再累加
+            if output.status.success() {
+                accounting.add(live.counters);
+            }
             info!("WAIT:等待配置或目标IP发生改变....\n");
         }
+        status::write(&accounting, &[&build_errors, &load_errors]);
 
-        if cfg!(debug_assertions) {
-            sleep(Duration::from_secs(5));
+        let interval = if cfg!(debug_assertions) {
+            Duration::from_secs(5)
         } else {
             //等待60秒
-            sleep(Duration::new(60, 0));
-        }
+            Duration::new(60, 0)
+        };
+        // 有规则即将过期时在过期时刻醒来
+        let wait = match next_expiry {
+            Some(expiry) => (expiry - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .min(interval),
+            None => interval,
+        };
+        sleep(wait);
     }
 }
 
-fn build_new_script(nat_cells: &[config::RuntimeCell]) -> Result<String, io::Error> {
+fn build_new_script(
+    runtime_config: &config::RuntimeConfig,
+    geoip_cache: &mut geoip::GeoIpCache,
+    errors: &mut status::RuleErrors,
+) -> Result<String, io::Error> {
     //脚本的前缀 - 创建IPv4和IPv6表
     let mut script = String::from(
         "#!/usr/sbin/nft -f\n\
@@ -158,6 +215,7 @@ fn build_new_script(nat_cells: &[config::RuntimeCell]) -> Result<String, io::Err
         delete table ip self-nat\n\
         add table ip self-nat\n\
         add chain ip self-nat PREROUTING { type nat hook prerouting priority -110 ; }\n\
+        add chain ip self-nat OUTPUT { type nat hook output priority -110 ; }\n\
         add chain ip self-nat POSTROUTING { type nat hook postrouting priority 110 ; }\n\
         \n\
         # IPv6 NAT table\n\
@@ -165,29 +223,69 @@ fn build_new_script(nat_cells: &[config::RuntimeCell]) -> Result<String, io::Err
         delete table ip6 self-nat\n\
         add table ip6 self-nat\n\
         add chain ip6 self-nat PREROUTING { type nat hook prerouting priority -110 ; }\n\
+        add chain ip6 self-nat OUTPUT { type nat hook output priority -110 ; }\n\
         add chain ip6 self-nat POSTROUTING { type nat hook postrouting priority 110 ; }\n\
         \n\
         # IPv4 Drop table\n\
         add table ip self-filter\n\
         delete table ip self-filter\n\
         add table ip self-filter\n\
+        add chain ip self-filter PREROUTING { type filter hook prerouting priority mangle ; }\n\
         add chain ip self-filter INPUT { type filter hook input priority filter - 1 ; }\n\
         add chain ip self-filter FORWARD { type filter hook forward priority filter - 1 ; }\n\
+        add chain ip self-filter OUTPUT { type filter hook output priority filter - 1 ; }\n\
+        add chain ip self-filter MANGLE { type filter hook prerouting priority mangle - 1 ; }\n\
+        add chain ip self-filter ROUTE { type route hook output priority mangle ; }\n\
         \n\
         # IPv6 Drop table\n\
         add table ip6 self-filter\n\
         delete table ip6 self-filter\n\
         add table ip6 self-filter\n\
+        add chain ip6 self-filter PREROUTING { type filter hook prerouting priority mangle ; }\n\
         add chain ip6 self-filter INPUT { type filter hook input priority filter - 1 ; }\n\
         add chain ip6 self-filter FORWARD { type filter hook forward priority filter - 1 ; }\n\
+        add chain ip6 self-filter OUTPUT { type filter hook output priority filter - 1 ; }\n\
+        add chain ip6 self-filter MANGLE { type filter hook prerouting priority mangle - 1 ; }\n\
+        add chain ip6 self-filter ROUTE { type route hook output priority mangle ; }\n\
         ",
     );
 
-    for x in nat_cells.iter() {
-        match x.build() {
-            Ok(rule) => script += &rule,
+    script += &config::build_quota_chains();
+    script += &config::build_mark_restore_rules(&runtime_config.cells);
+    for set in runtime_config.sets.iter() {
+        script += &config::build_ip_set(set);
+    }
+    // 所有规则用到的国家组合，每个组合生成一个集合
+    let country_lists: BTreeSet<Vec<String>> = runtime_config
+        .cells
+        .iter()
+        .filter_map(|cell| match cell {
+            config::RuntimeCell::Rule(rule) if !rule.src_country().is_empty() => {
+                Some(rule.src_country().to_vec())
+            }
+            _ => None,
+        })
+        .collect();
+    if let Some(path) = &runtime_config.settings.geoip
+        && !country_lists.is_empty()
+    {
+        script += &geoip_cache.build_sets(path, &country_lists);
+    }
+    let mut group_chains = group::GroupChains::new(&runtime_config.groups);
+    for x in runtime_config.cells.iter() {
+        match x.build(&runtime_config.settings) {
+            Ok(rule) => match x.group() {
+                Some(name) => script += &group_chains.apply(name, &rule),
+                None => script += &rule,
+            },
             Err(e) => {
                 log::error!("Failed to build rule for {x:?}: {e}");
+                if let config::RuntimeCell::Rule(rule) = x {
+                    errors
+                        .entry(rule.rule_id())
+                        .or_default()
+                        .push(e.to_string());
+                }
             }
         }
     }
@@ -60,28 +60,7 @@ fn prepare_script() -> Result<Option<String>, io::Error> {
 
 fn check_current_ruleset() -> Result<CheckResult, io::Error> {
     let mut res = CheckResult::default();
-    let output = Command::new("/usr/sbin/nft")
-        .arg("-j")
-        .arg("list")
-        .arg("ruleset")
-        .output()?;
-
-    if !output.status.success() {
-        info!("执行 nft -j list ruleset 命令失败");
-        return Err(io::Error::other("执行 nft -j list ruleset 命令失败"));
-    }
-
-    let json_str = String::from_utf8_lossy(&output.stdout);
-    let nftables_output: NftablesOutput = match serde_json::from_str(&json_str) {
-        Ok(output) => output,
-        Err(e) => {
-            info!("解析 nft 输出的 JSON 失败: {e}");
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "解析 nft 输出的 JSON 失败",
-            ));
-        }
-    };
+    let nftables_output = list_ruleset()?;
 
     for entry in nftables_output.nftables {
         #[allow(clippy::single_match)]
@@ -139,18 +118,38 @@ fn check_current_ruleset() -> Result<CheckResult, io::Error> {
     Ok(res)
 }
 
+/// 执行 nft -j list ruleset 并解析输出
+pub(crate) fn list_ruleset() -> Result<NftablesOutput, io::Error> {
+    let output = Command::new("/usr/sbin/nft")
+        .arg("-j")
+        .arg("list")
+        .arg("ruleset")
+        .output()?;
+
+    if !output.status.success() {
+        info!("执行 nft -j list ruleset 命令失败");
+        return Err(io::Error::other("执行 nft -j list ruleset 命令失败"));
+    }
+
+    let json_str = String::from_utf8_lossy(&output.stdout);
+    serde_json::from_str(&json_str).map_err(|e| {
+        info!("解析 nft 输出的 JSON 失败: {e}");
+        io::Error::new(io::ErrorKind::InvalidData, "解析 nft 输出的 JSON 失败")
+    })
+}
+
 const FILE_NAME_PREPARE: &str = "/etc/nftables-nat/nat-prepare.nft";
 
 // 用于解析 nft -j list ruleset 输出的数据结构
 #[derive(Debug, Serialize, Deserialize)]
-struct NftablesOutput {
-    nftables: Vec<NftablesEntry>,
+pub(crate) struct NftablesOutput {
+    pub(crate) nftables: Vec<NftablesEntry>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 // #[serde(untagged)]
 #[serde(rename_all = "snake_case")]
-enum NftablesEntry {
+pub(crate) enum NftablesEntry {
     Metainfo {
         version: String,
         release_name: String,
@@ -181,6 +180,8 @@ enum NftablesEntry {
         chain: String,
         handle: u32,
         expr: Vec<serde_json::Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
     },
     Set {
         family: String,
@@ -210,6 +211,16 @@ enum NftablesEntry {
         #[serde(skip_serializing_if = "Option::is_none")]
         elem: Option<Vec<serde_json::Value>>,
     },
+    Quota {
+        family: String,
+        table: String,
+        name: String,
+        handle: u32,
+        bytes: u64,
+        used: u64,
+        #[serde(default)]
+        inv: bool,
+    },
     Element {
         family: String,
         table: String,
@@ -457,12 +468,14 @@ mod tests {
                 chain,
                 handle,
                 expr,
+                comment,
             } => {
                 assert_eq!(family, "ip");
                 assert_eq!(table, "netbird");
                 assert_eq!(chain, "netbird-rt-fwd");
                 assert_eq!(*handle, 22);
                 assert_eq!(expr.len(), 3);
+                assert_eq!(*comment, None);
             }
             _ => panic!("Expected Rule entry"),
         }
@@ -0,0 +1,272 @@
+use crate::config::RuntimeCell;
+use crate::prepare::{self, NftablesEntry};
+use chrono::{DateTime, Local};
+use log::{error, info};
+use nat_common::{NftCell, QuotaReset};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::process::Command;
+
+const FILE_NAME_QUOTA: &str = "/etc/nftables-nat/quota.json";
+
+/// 规则的配额对象名，由规则标识生成，如 quota_single-tcp-10000-ipv4
+/// 只修改目标地址、注释等其他配置时标识不变，已用流量可以延续；不同规则不会共用同一个配额对象
+pub fn object_name(cell: &NftCell) -> Option<String> {
+    cell.quota()?;
+    // 规则标识只包含字母、数字、下划线和连字符，可以直接用作nft对象名
+    Some(format!("quota_{}", cell.rule_id()))
+}
+
+/// 清零周期的标识，进入新周期时标识改变；未配置清零周期时为空
+fn period(reset: Option<QuotaReset>, now: DateTime<Local>) -> String {
+    match reset {
+        Some(QuotaReset::Daily) => now.format("%Y-%m-%d").to_string(),
+        Some(QuotaReset::Weekly) => now.format("%G-W%V").to_string(),
+        Some(QuotaReset::Monthly) => now.format("%Y-%m").to_string(),
+        None => String::new(),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct QuotaUsage {
+    /// 已用字节数
+    used: u64,
+    /// 当前计量周期
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    period: String,
+}
+
+/// 配额的已用流量
+/// 每次重载都会删除并重建 self-filter 表，配额对象随之清零，
+/// 因此每轮先从nft读取已用流量保存到文件，重载时再写回 add quota 语句
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    /// 键为 "{family} {配额对象名}"，如 "ip quota_tcp_10000"
+    usage: BTreeMap<String, QuotaUsage>,
+}
+
+impl QuotaTracker {
+    /// 读取上次保存的已用流量，文件不存在时从0开始计量
+    pub fn load() -> Self {
+        let usage = match std::fs::read_to_string(FILE_NAME_QUOTA) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                error!("解析配额文件 {FILE_NAME_QUOTA} 失败: {e}");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        QuotaTracker { usage }
+    }
+
+    /// 同步nft中配额对象的已用流量，到达清零周期的配额清零，有变化时保存到文件
+    pub fn sync(&mut self, cells: &[RuntimeCell], now: DateTime<Local>) {
+        let resets: HashMap<String, Option<QuotaReset>> = cells
+            .iter()
+            .filter_map(|cell| match cell {
+                RuntimeCell::Rule(rule) => Some((object_name(rule)?, rule.quota_reset())),
+                RuntimeCell::Comment(_) => None,
+            })
+            .collect();
+        if resets.is_empty() {
+            return;
+        }
+        let live = live_usage().unwrap_or_else(|e| {
+            error!("读取配额已用流量失败: {e}");
+            BTreeMap::new()
+        });
+        let before = self.usage.clone();
+        for key in self.update(&resets, &live, now) {
+            reset_live_quota(&key);
+        }
+        if self.usage != before
+            && let Err(e) = self.save()
+        {
+            error!("保存配额文件 {FILE_NAME_QUOTA} 失败: {e}");
+        }
+    }
+
+    /// 用nft中的已用流量更新记录，返回需要在nft中清零的配额
+    fn update(
+        &mut self,
+        resets: &HashMap<String, Option<QuotaReset>>,
+        live: &BTreeMap<String, u64>,
+        now: DateTime<Local>,
+    ) -> Vec<String> {
+        let reset_of = |key: &str| {
+            let (_, name) = key.split_once(' ')?;
+            resets.get(name).copied()
+        };
+        for (key, used) in live {
+            let Some(reset) = reset_of(key) else {
+                continue;
+            };
+            self.usage
+                .entry(key.clone())
+                .or_insert_with(|| QuotaUsage {
+                    used: 0,
+                    period: period(reset, now),
+                })
+                .used = *used;
+        }
+
+        let mut expired = Vec::new();
+        for (key, usage) in self.usage.iter_mut() {
+            let Some(reset) = reset_of(key) else {
+                continue;
+            };
+            let current = period(reset, now);
+            if usage.period == current {
+                continue;
+            }
+            // 新增或取消清零周期时只记录周期，不清零
+            if !usage.period.is_empty() && !current.is_empty() {
+                info!("配额 {key} 进入新周期 {current}，已用流量清零");
+                usage.used = 0;
+                if live.contains_key(key) {
+                    expired.push(key.clone());
+                }
+            }
+            usage.period = current;
+        }
+        expired
+    }
+
+    /// 在脚本的 add quota 语句中写入已用流量，重建后的配额对象接着计量
+    pub fn apply(&self, script: &str) -> String {
+        script
+            .split_inclusive('\n')
+            .map(|line| {
+                // add quota {family} self-filter {name} { over N bytes }
+                let Some(rest) = line.strip_prefix("add quota ") else {
+                    return line.to_string();
+                };
+                let mut parts = rest.split(' ');
+                let (Some(family), Some(_table), Some(name)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return line.to_string();
+                };
+                match self.usage.get(&format!("{family} {name}")) {
+                    Some(usage) if usage.used > 0 => line.replacen(
+                        " bytes }",
+                        &format!(" bytes used {} bytes }}", usage.used),
+                        1,
+                    ),
+                    _ => line.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let content = serde_json::to_string_pretty(&self.usage)?;
+        std::fs::write(FILE_NAME_QUOTA, content)
+    }
+}
+
+/// nft中 self-filter 表里各配额对象的已用流量
+fn live_usage() -> Result<BTreeMap<String, u64>, io::Error> {
+    Ok(prepare::list_ruleset()?
+        .nftables
+        .into_iter()
+        .filter_map(|entry| match entry {
+            NftablesEntry::Quota {
+                family,
+                table,
+                name,
+                used,
+                ..
+            } if table == "self-filter" => Some((format!("{family} {name}"), used)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn reset_live_quota(key: &str) {
+    let Some((family, name)) = key.split_once(' ') else {
+        return;
+    };
+    match Command::new("/usr/sbin/nft")
+        .args(["reset", "quota", family, "self-filter", name])
+        .output()
+    {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => error!(
+            "清零配额 {key} 失败: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => error!("清零配额 {key} 失败: {e}"),
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_object_name() {
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=500 GiB").unwrap();
+        assert_eq!(object_name(&cell).unwrap(), "quota_single-tcp-10000-ipv4");
+        let cell = NftCell::try_from("RANGE,1000,2000,10.0.0.1,all,ipv4,quota=1 TB").unwrap();
+        assert_eq!(
+            object_name(&cell).unwrap(),
+            "quota_range-all-1000-2000-ipv4"
+        );
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=1 TB,id=customer-a")
+            .unwrap();
+        assert_eq!(object_name(&cell).unwrap(), "quota_customer-a");
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4").unwrap();
+        assert!(object_name(&cell).is_none());
+    }
+
+    #[test]
+    fn test_update_resets_on_new_period() {
+        let resets = HashMap::from([("quota_tcp_10000".to_string(), Some(QuotaReset::Monthly))]);
+        let october = Local.with_ymd_and_hms(2026, 10, 31, 23, 0, 0).unwrap();
+        let november = Local.with_ymd_and_hms(2026, 11, 1, 0, 1, 0).unwrap();
+        let mut tracker = QuotaTracker::default();
+
+        let live = BTreeMap::from([("ip quota_tcp_10000".to_string(), 1000)]);
+        assert!(tracker.update(&resets, &live, october).is_empty());
+        assert_eq!(tracker.usage["ip quota_tcp_10000"].used, 1000);
+        assert_eq!(tracker.usage["ip quota_tcp_10000"].period, "2026-10");
+
+        // 表重建后nft中暂时没有配额对象，保留已记录的用量
+        assert!(
+            tracker
+                .update(&resets, &BTreeMap::new(), october)
+                .is_empty()
+        );
+        assert_eq!(tracker.usage["ip quota_tcp_10000"].used, 1000);
+
+        let live = BTreeMap::from([("ip quota_tcp_10000".to_string(), 2000)]);
+        assert_eq!(
+            tracker.update(&resets, &live, november),
+            vec!["ip quota_tcp_10000".to_string()]
+        );
+        assert_eq!(tracker.usage["ip quota_tcp_10000"].used, 0);
+        assert_eq!(tracker.usage["ip quota_tcp_10000"].period, "2026-11");
+    }
+
+    #[test]
+    fn test_apply_used_bytes() {
+        let mut tracker = QuotaTracker::default();
+        tracker.usage.insert(
+            "ip quota_tcp_10000".to_string(),
+            QuotaUsage {
+                used: 12345,
+                period: String::new(),
+            },
+        );
+        let script = "add quota ip self-filter quota_tcp_10000 { over 536870912000 bytes }\n\
+            add quota ip6 self-filter quota_tcp_10000 { over 536870912000 bytes }\n";
+        assert_eq!(
+            tracker.apply(script),
+            "add quota ip self-filter quota_tcp_10000 { over 536870912000 bytes used 12345 bytes }\n\
+            add quota ip6 self-filter quota_tcp_10000 { over 536870912000 bytes }\n"
+        );
+    }
+}
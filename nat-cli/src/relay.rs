@@ -0,0 +1,512 @@
+use crate::config::RuntimeCell;
+use crate::ip;
+use ipnetwork::IpNetwork;
+use log::{debug, error, info, warn};
+use nat_common::{ForwardMode, NftCell, Protocol};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// UDP会话默认的空闲超时
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// 单个UDP会话中等待发往后端的数据包数量，超出时丢弃
+const UDP_SESSION_QUEUE: usize = 64;
+const UDP_BUFFER_SIZE: usize = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// 一个中继监听：本机端口到目标地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelaySpec {
+    pub transport: Transport,
+    pub listen_port: u16,
+    pub target: SocketAddr,
+    /// 源地址白名单，为空时接受所有来源
+    pub allow_src: Vec<IpNetwork>,
+    /// 所属规则的legacy格式，用于日志
+    pub rule: String,
+}
+
+impl Display for RelaySpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} :{} -> {} ({})",
+            self.transport, self.listen_port, self.target, self.rule
+        )
+    }
+}
+
+impl RelaySpec {
+    /// 双栈监听收到的IPv4连接地址形如 ::ffff:1.2.3.4，先还原为IPv4再匹配白名单
+    fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.allow_src.is_empty() || self.allow_src.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// 规则对应的中继监听，每个监听端口、每种传输协议各一个；非relay规则返回空列表
+pub fn relay_specs(cell: &NftCell) -> Result<Vec<RelaySpec>, io::Error> {
+    if cell.forward_mode() != ForwardMode::Relay {
+        return Ok(Vec::new());
+    }
+    let (domain, ip_version, protocol, allow_src, ports): (_, _, _, _, Vec<(u16, u16)>) = match cell
+    {
+        NftCell::Single {
+            sport,
+            dport,
+            domain,
+            protocol,
+            ip_version,
+            allow_src,
+            ..
+        } => {
+            let ports = match dport.single() {
+                Some(dport) => sport.iter_ports().map(|port| (port, dport)).collect(),
+                None => sport.iter_ports().zip(dport.iter_ports()).collect(),
+            };
+            (domain, ip_version, protocol, allow_src, ports)
+        }
+        NftCell::Range {
+            port_start,
+            port_end,
+            dst_port_start,
+            domain,
+            protocol,
+            ip_version,
+            allow_src,
+            ..
+        } => {
+            let dst_start = dst_port_start.unwrap_or(*port_start);
+            let ports = (*port_start..=*port_end)
+                .map(|port| (port, dst_start + (port - port_start)))
+                .collect();
+            (domain, ip_version, protocol, allow_src, ports)
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let target_ip = ip::remote_ip(domain, ip_version)?
+        .parse::<IpAddr>()
+        .map_err(|e| invalid(format!("无效的目标地址 {domain}: {e}")))?;
+    let allow_src = allow_src
+        .iter()
+        .map(|ip| IpNetwork::from_str(ip).map_err(|e| invalid(format!("无效的IP地址 {ip}: {e}"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let transports: &[Transport] = match protocol {
+        Protocol::Tcp => &[Transport::Tcp],
+        Protocol::Udp => &[Transport::Udp],
+        Protocol::All => &[Transport::Tcp, Transport::Udp],
+        _ => return Err(invalid(format!("relay 模式不支持协议 {protocol}"))),
+    };
+
+    let mut specs = Vec::with_capacity(ports.len() * transports.len());
+    for transport in transports {
+        for (listen_port, target_port) in &ports {
+            specs.push(RelaySpec {
+                transport: *transport,
+                listen_port: *listen_port,
+                target: SocketAddr::new(target_ip, *target_port),
+                allow_src: allow_src.clone(),
+                rule: cell.to_string(),
+            });
+        }
+    }
+    Ok(specs)
+}
+
+/// 配置中所有relay规则的监听，目标解析失败的规则记录错误后跳过
+pub fn collect_specs(cells: &[RuntimeCell]) -> Vec<RelaySpec> {
+    let mut specs = Vec::new();
+    for cell in cells {
+        if let RuntimeCell::Rule(rule) = cell {
+            match relay_specs(rule) {
+                Ok(rule_specs) => specs.extend(rule_specs),
+                Err(e) => error!("Failed to build relay for {rule}: {e}"),
+            }
+        }
+    }
+    specs
+}
+
+struct Relay {
+    spec: RelaySpec,
+    udp_timeout: Duration,
+    handle: JoinHandle<()>,
+}
+
+/// 管理所有中继监听，配置或目标地址变化时重启对应的监听
+/// tokio运行时在第一次需要中继时才创建，没有relay规则时不占用额外线程
+#[derive(Default)]
+pub struct RelayManager {
+    runtime: Option<Runtime>,
+    relays: HashMap<(Transport, u16), Relay>,
+}
+
+impl RelayManager {
+    pub fn update(&mut self, specs: Vec<RelaySpec>, udp_timeout: Duration) {
+        let mut wanted: HashMap<(Transport, u16), RelaySpec> = HashMap::new();
+        for spec in specs {
+            let key = (spec.transport, spec.listen_port);
+            if let Some(existing) = wanted.get(&key) {
+                warn!(
+                    "端口 {} 已被中继 {existing} 使用，忽略 {spec}",
+                    spec.listen_port
+                );
+                continue;
+            }
+            wanted.insert(key, spec);
+        }
+
+        let stale: Vec<(Transport, u16)> = self
+            .relays
+            .iter()
+            .filter(|(key, relay)| {
+                wanted.get(key) != Some(&relay.spec) || relay.udp_timeout != udp_timeout
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            if let (Some(relay), Some(runtime)) = (self.relays.remove(&key), &self.runtime) {
+                info!("停止用户态中继 {}", relay.spec);
+                relay.handle.abort();
+                // 等待任务退出、监听端口释放后再重新绑定
+                let _ = runtime.block_on(relay.handle);
+            }
+        }
+
+        wanted.retain(|key, _| !self.relays.contains_key(key));
+        if wanted.is_empty() {
+            return;
+        }
+        if self.runtime.is_none() {
+            match tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .thread_name("relay")
+                .build()
+            {
+                Ok(runtime) => self.runtime = Some(runtime),
+                Err(e) => {
+                    error!("创建用户态中继运行时失败: {e}");
+                    return;
+                }
+            }
+        }
+        let Some(runtime) = &self.runtime else {
+            return;
+        };
+        for (key, spec) in wanted {
+            match runtime.block_on(start(spec.clone(), udp_timeout)) {
+                Ok(handle) => {
+                    info!("启动用户态中继 {spec}");
+                    self.relays.insert(
+                        key,
+                        Relay {
+                            spec,
+                            udp_timeout,
+                            handle,
+                        },
+                    );
+                }
+                // 未记录的监听会在下一轮重试
+                Err(e) => error!("启动用户态中继 {spec} 失败: {e}"),
+            }
+        }
+    }
+}
+
+async fn start(spec: RelaySpec, udp_timeout: Duration) -> io::Result<JoinHandle<()>> {
+    let spec = Arc::new(spec);
+    match spec.transport {
+        Transport::Tcp => {
+            let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, spec.listen_port)).await
+            {
+                Ok(listener) => listener,
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e),
+                // 系统未启用IPv6时只监听IPv4
+                Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, spec.listen_port)).await?,
+            };
+            Ok(tokio::spawn(serve_tcp(listener, spec)))
+        }
+        Transport::Udp => {
+            let socket = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, spec.listen_port)).await {
+                Ok(socket) => socket,
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e),
+                Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, spec.listen_port)).await?,
+            };
+            Ok(tokio::spawn(serve_udp(Arc::new(socket), spec, udp_timeout)))
+        }
+    }
+}
+
+/// 已建立的TCP连接在监听重启后继续保持，直到任意一端关闭
+async fn serve_tcp(listener: TcpListener, spec: Arc<RelaySpec>) {
+    loop {
+        let (mut inbound, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("中继 {spec} 接受连接失败: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if !spec.allows(peer.ip()) {
+            debug!("中继 {spec} 拒绝来自 {peer} 的连接");
+            continue;
+        }
+        let target = spec.target;
+        tokio::spawn(async move {
+            let mut outbound = match TcpStream::connect(target).await {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    warn!("中继 {peer} -> {target} 连接目标失败: {e}");
+                    return;
+                }
+            };
+            let _ = inbound.set_nodelay(true);
+            let _ = outbound.set_nodelay(true);
+            if let Err(e) = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
+                debug!("中继 {peer} -> {target} 连接断开: {e}");
+            }
+        });
+    }
+}
+
+/// 每个客户端地址一个会话，会话使用独立的上游socket；会话随监听一起停止
+async fn serve_udp(socket: Arc<UdpSocket>, spec: Arc<RelaySpec>, idle_timeout: Duration) {
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("中继 {spec} 接收数据失败: {e}");
+                continue;
+            }
+        };
+        if tasks.try_join_next().is_some() {
+            while tasks.try_join_next().is_some() {}
+            sessions.retain(|_, sender| !sender.is_closed());
+        }
+        if !spec.allows(client.ip()) {
+            continue;
+        }
+        let mut packet = buf[..len].to_vec();
+        if let Some(sender) = sessions.get(&client) {
+            match sender.try_send(packet) {
+                Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => continue,
+                // 会话已超时退出，建立新会话
+                Err(mpsc::error::TrySendError::Closed(returned)) => packet = returned,
+            }
+        }
+        let (sender, receiver) = mpsc::channel(UDP_SESSION_QUEUE);
+        let _ = sender.try_send(packet);
+        sessions.insert(client, sender);
+        tasks.spawn(udp_session(
+            socket.clone(),
+            client,
+            spec.target,
+            receiver,
+            idle_timeout,
+        ));
+    }
+}
+
+async fn udp_session(
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    target: SocketAddr,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+) {
+    let bind_addr: SocketAddr = if target.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let upstream = match UdpSocket::bind(bind_addr).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("中继 {client} -> {target} 创建上游socket失败: {e}");
+            return;
+        }
+    };
+    if let Err(e) = upstream.connect(target).await {
+        warn!("中继 {client} -> {target} 连接目标失败: {e}");
+        return;
+    }
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+    loop {
+        // 任意方向有数据都会重新开始计时
+        tokio::select! {
+            packet = receiver.recv() => match packet {
+                Some(packet) => {
+                    if let Err(e) = upstream.send(&packet).await {
+                        debug!("中继 {client} -> {target} 发送失败: {e}");
+                    }
+                }
+                None => break,
+            },
+            received = upstream.recv(&mut buf) => match received {
+                Ok(len) => {
+                    if let Err(e) = socket.send_to(&buf[..len], client).await {
+                        debug!("中继 {target} -> {client} 回包失败: {e}");
+                    }
+                }
+                Err(e) => debug!("中继 {client} -> {target} 接收失败: {e}"),
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!("中继 {client} -> {target} UDP会话空闲超时");
+                break;
+            }
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+
+    fn free_port() -> u16 {
+        StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn spec(transport: Transport, listen_port: u16, target: SocketAddr) -> RelaySpec {
+        RelaySpec {
+            transport,
+            listen_port,
+            target,
+            allow_src: vec![],
+            rule: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_relay_specs_ports() {
+        let cell =
+            NftCell::try_from("SINGLE,80|443,8080|8443,10.0.0.1,tcp,ipv4,mode=relay").unwrap();
+        let specs = relay_specs(&cell).unwrap();
+        let ports: Vec<(u16, SocketAddr)> = specs
+            .iter()
+            .map(|spec| (spec.listen_port, spec.target))
+            .collect();
+        assert_eq!(
+            ports,
+            vec![
+                (80, "10.0.0.1:8080".parse().unwrap()),
+                (443, "10.0.0.1:8443".parse().unwrap()),
+            ]
+        );
+
+        let cell =
+            NftCell::try_from("RANGE,1000,1001,10.0.0.1,all,ipv4,mode=relay,dst_port=2000-2001")
+                .unwrap();
+        let specs = relay_specs(&cell).unwrap();
+        assert_eq!(specs.len(), 4);
+        assert_eq!(specs[1].transport, Transport::Tcp);
+        assert_eq!(specs[1].target, "10.0.0.1:2001".parse().unwrap());
+        assert_eq!(specs[2].transport, Transport::Udp);
+
+        let cell = NftCell::try_from("SINGLE,80,80,10.0.0.1,tcp,ipv4").unwrap();
+        assert!(relay_specs(&cell).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_allow_src_with_mapped_address() {
+        let mut spec = spec(Transport::Tcp, 80, "10.0.0.1:80".parse().unwrap());
+        spec.allow_src = vec!["192.168.0.0/16".parse().unwrap()];
+        assert!(spec.allows("::ffff:192.168.1.2".parse().unwrap()));
+        assert!(spec.allows("192.168.1.2".parse().unwrap()));
+        assert!(!spec.allows("10.1.1.1".parse().unwrap()));
+        assert!(!spec.allows("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_tcp_relay() {
+        let backend = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let target = backend.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let port = free_port();
+        let mut manager = RelayManager::default();
+        manager.update(
+            vec![spec(Transport::Tcp, port, target)],
+            DEFAULT_UDP_TIMEOUT,
+        );
+        let mut client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        manager.update(vec![], DEFAULT_UDP_TIMEOUT);
+        assert!(manager.relays.is_empty());
+    }
+
+    #[test]
+    fn test_udp_relay_idle_timeout() {
+        let backend = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = backend.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let (len, peer) = backend.recv_from(&mut buf).unwrap();
+                backend.send_to(&buf[..len], peer).unwrap();
+            }
+        });
+
+        let port = free_port();
+        let mut manager = RelayManager::default();
+        manager.update(
+            vec![spec(Transport::Udp, port, target)],
+            Duration::from_millis(200),
+        );
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        for _ in 0..2 {
+            client.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+            let len = client.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+            // 超时后同一客户端重新建立会话
+            std::thread::sleep(Duration::from_millis(400));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, Local, Offset, TimeZone, Utc};
+use nat_common::Schedule;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const MINUTES_PER_WEEK: i64 = 7 * MINUTES_PER_DAY;
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// 将时间窗口换算为UTC下的 meta day / meta hour 匹配条件
+/// nft 以 TZ=UTC 执行，内核按UTC比较；换算后跨越UTC午夜的时间段会被拆成多段，
+/// 每段对应一组匹配条件，规则需要为每组条件各生成一份
+pub fn nft_time_matches(schedule: &Schedule, now: DateTime<Utc>) -> Result<Vec<String>, io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut days = schedule.weekdays().map_err(invalid)?;
+    if days.is_empty() {
+        days = (0..7).collect();
+    }
+    let mut hours = schedule.hour_ranges().map_err(invalid)?;
+    if hours.is_empty() {
+        hours.push((0, MINUTES_PER_DAY as u32));
+    }
+    let offset_minutes = match &schedule.timezone {
+        Some(timezone) => {
+            let tz = timezone
+                .parse::<chrono_tz::Tz>()
+                .map_err(|_| invalid(format!("无效的时区: {timezone}")))?;
+            tz.offset_from_utc_datetime(&now.naive_utc())
+                .fix()
+                .local_minus_utc()
+        }
+        None => Local
+            .offset_from_utc_datetime(&now.naive_utc())
+            .local_minus_utc(),
+    } as i64
+        / 60;
+
+    // 以一周内的分钟数表示本地时间段，平移到UTC后按天切分
+    let mut pieces: BTreeMap<(i64, i64), BTreeSet<i64>> = BTreeMap::new();
+    for day in &days {
+        for (start, end) in &hours {
+            let (start, end) = (*start as i64, *end as i64);
+            // 结束早于开始表示跨越午夜
+            let mut remaining = if end > start {
+                end - start
+            } else {
+                end + MINUTES_PER_DAY - start
+            };
+            let mut start = (*day as i64 * MINUTES_PER_DAY + start - offset_minutes)
+                .rem_euclid(MINUTES_PER_WEEK);
+            while remaining > 0 {
+                let day_start = start / MINUTES_PER_DAY * MINUTES_PER_DAY;
+                let piece_end = (start + remaining).min(day_start + MINUTES_PER_DAY);
+                pieces
+                    .entry((start - day_start, piece_end - day_start))
+                    .or_default()
+                    .insert((day_start / MINUTES_PER_DAY) % 7);
+                remaining -= piece_end - start;
+                start = piece_end % MINUTES_PER_WEEK;
+            }
+        }
+    }
+
+    Ok(pieces
+        .into_iter()
+        .map(|((start, end), days)| {
+            let mut matches = String::new();
+            if days.len() < 7 {
+                let names: Vec<String> = days
+                    .iter()
+                    .map(|day| format!("\"{}\"", WEEKDAYS[*day as usize]))
+                    .collect();
+                if names.len() == 1 {
+                    matches += &format!("meta day {} ", names[0]);
+                } else {
+                    matches += &format!("meta day {{ {} }} ", names.join(", "));
+                }
+            }
+            if (start, end) != (0, MINUTES_PER_DAY) {
+                // meta hour 的范围两端都包含，结束时间取前一秒
+                let end = end * 60 - 1;
+                matches += &format!(
+                    "meta hour \"{:02}:{:02}\"-\"{:02}:{:02}:{:02}\" ",
+                    start / 60,
+                    start % 60,
+                    end / 3600,
+                    end / 60 % 60,
+                    end % 60
+                );
+            }
+            matches
+        })
+        .collect())
+}
+
+/// 为脚本中 is_entry 选中的 add rule 插入时间匹配条件，有多组条件时每组各生成一条
+/// is_entry 的参数为 "{table} {chain}"，其余规则（计量、配额、SNAT等）原样保留
+pub fn apply_time_matches(
+    script: &str,
+    matches: &[String],
+    is_entry: impl Fn(&str) -> bool,
+) -> String {
+    let mut result = String::new();
+    for line in script.split_inclusive('\n') {
+        let Some(rest) = line.strip_prefix("add rule ") else {
+            result += line;
+            continue;
+        };
+        // add rule {family} {table} {chain} {expr}
+        let mut parts = rest.splitn(4, ' ');
+        let (Some(family), Some(table), Some(chain), Some(expr)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            result += line;
+            continue;
+        };
+        if !is_entry(&format!("{table} {chain}")) {
+            result += line;
+            continue;
+        }
+        for time_match in matches {
+            result += &format!("add rule {family} {table} {chain} {time_match}{expr}");
+        }
+    }
+    result
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(days: &[&str], hours: &[&str], timezone: &str) -> Schedule {
+        Schedule {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            hours: hours.iter().map(|h| h.to_string()).collect(),
+            timezone: Some(timezone.to_string()),
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2025-01-15T00:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn test_business_hours_utc() {
+        let schedule = schedule(
+            &["mon", "tue", "wed", "thu", "fri"],
+            &["09:00-18:00"],
+            "UTC",
+        );
+        let matches = nft_time_matches(&schedule, now()).unwrap();
+        assert_eq!(
+            matches,
+            vec![
+                "meta day { \"Monday\", \"Tuesday\", \"Wednesday\", \"Thursday\", \"Friday\" } meta hour \"09:00\"-\"17:59:59\" "
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timezone_shift_across_midnight() {
+        // 北京时间 06:00-10:00 即UTC前一天 22:00 到当天 02:00
+        let schedule = schedule(&["mon"], &["06:00-10:00"], "Asia/Shanghai");
+        let matches = nft_time_matches(&schedule, now()).unwrap();
+        assert_eq!(
+            matches,
+            vec![
+                "meta day \"Monday\" meta hour \"00:00\"-\"01:59:59\" ",
+                "meta day \"Sunday\" meta hour \"22:00\"-\"23:59:59\" ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_night_window_every_day() {
+        let schedule = schedule(&[], &["22:00-06:00"], "UTC");
+        let matches = nft_time_matches(&schedule, now()).unwrap();
+        assert_eq!(
+            matches,
+            vec![
+                "meta hour \"00:00\"-\"05:59:59\" ",
+                "meta hour \"22:00\"-\"23:59:59\" ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_time_matches() {
+        let script = "add set ip self-filter s { type ipv4_addr; }\n\
+            add rule ip self-nat PREROUTING tcp dport 80 counter dnat to 10.0.0.1:80\n\
+            add rule ip self-nat POSTROUTING ip daddr 10.0.0.1 tcp dport 80 counter masquerade\n";
+        let matches = vec![
+            "meta hour \"00:00\"-\"01:59:59\" ".to_string(),
+            "meta day \"Sunday\" ".to_string(),
+        ];
+        let result = apply_time_matches(script, &matches, |chain| chain == "self-nat PREROUTING");
+        assert_eq!(
+            result,
+            "add set ip self-filter s { type ipv4_addr; }\n\
+            add rule ip self-nat PREROUTING meta hour \"00:00\"-\"01:59:59\" tcp dport 80 counter dnat to 10.0.0.1:80\n\
+            add rule ip self-nat PREROUTING meta day \"Sunday\" tcp dport 80 counter dnat to 10.0.0.1:80\n\
+            add rule ip self-nat POSTROUTING ip daddr 10.0.0.1 tcp dport 80 counter masquerade\n"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use crate::accounting::{self, Accounting, LiveRules};
+use log::error;
+use nat_common::status::{self, Status};
+use std::collections::BTreeMap;
+
+/// 规则标识 -> 错误信息
+pub type RuleErrors = BTreeMap<String, Vec<String>>;
+
+/// 从 nft -f 的错误输出中找出出错的脚本行，按行内注释中的规则标识归类
+/// nft的错误格式为 "{文件}:{行}:{列}-{列}: Error: {信息}"，没有规则标识的行（如集合定义）只记录在日志中
+pub fn nft_errors(script: &str, stderr: &str) -> RuleErrors {
+    let lines: Vec<&str> = script.lines().collect();
+    let mut errors = RuleErrors::new();
+    for line in stderr.lines() {
+        let Some((location, message)) = line.split_once(": Error: ") else {
+            continue;
+        };
+        let mut fields = location.rsplit(':');
+        let (Some(_columns), Some(line_num)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Some(script_line) = line_num
+            .parse::<usize>()
+            .ok()
+            .and_then(|line_num| lines.get(line_num.checked_sub(1)?))
+        else {
+            continue;
+        };
+        let Some((_, comment)) = script_line.split_once(" comment \"") else {
+            continue;
+        };
+        if let Some(rule_id) = status::comment_rule_id(comment) {
+            errors
+                .entry(rule_id.to_string())
+                .or_default()
+                .push(message.to_string());
+        }
+    }
+    errors
+}
+
+/// 汇总累计计数、nft中的规则和错误，写入状态文件供 nat-console 读取
+pub fn write(accounting: &Accounting, errors: &[&RuleErrors]) {
+    let live = accounting::read_live().unwrap_or_else(|e| {
+        error!("读取nft规则失败: {e}");
+        LiveRules::default()
+    });
+    let mut status = collect(accounting, live, errors);
+    status.updated_at = chrono::Local::now().to_rfc3339();
+    if let Err(e) = status.save() {
+        error!("保存规则状态文件 {} 失败: {e}", status::STATUS_FILE);
+    }
+}
+
+fn collect(accounting: &Accounting, live: LiveRules, errors: &[&RuleErrors]) -> Status {
+    let mut status = Status::default();
+    for (rule_id, count) in live.rules {
+        status.rules.entry(rule_id).or_default().live_rules = count;
+    }
+    for (rule_id, chains) in accounting.totals().iter().chain(live.counters.iter()) {
+        let rule = status.rules.entry(rule_id.clone()).or_default();
+        for (chain, counter) in chains {
+            rule.counters
+                .entry(chain.clone())
+                .or_default()
+                .add(*counter);
+        }
+    }
+    for (rule_id, messages) in errors.iter().flat_map(|errors| errors.iter()) {
+        status
+            .rules
+            .entry(rule_id.clone())
+            .or_default()
+            .errors
+            .extend(messages.iter().cloned());
+    }
+    status
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nat_common::status::Counter;
+
+    #[test]
+    fn test_nft_errors() {
+        let script = "add table ip self-nat\n\
+            add rule ip self-nat PREROUTING tcp dport 10000 counter dnat to 10.0.0.1:443 comment \"[web] SINGLE,10000,443,10.0.0.1,tcp,ipv4\"\n\
+            add set ip self-filter blocklist { type ipv4_addr; flags interval; }\n";
+        let stderr = "/etc/nftables-nat/nat-diy.nft:2:1-97: Error: Could not process rule: No such file or directory\n\
+            add rule ip self-nat PREROUTING tcp dport 10000 counter dnat to 10.0.0.1:443\n\
+            ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^\n\
+            /etc/nftables-nat/nat-diy.nft:3:1-20: Error: syntax error\n";
+        let errors = nft_errors(script, stderr);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors["web"],
+            vec!["Could not process rule: No such file or directory".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_collect() {
+        let counter = Counter {
+            packets: 1,
+            bytes: 60,
+        };
+        let chains = BTreeMap::from([("ip self-nat PREROUTING".to_string(), counter)]);
+        let live = LiveRules {
+            counters: BTreeMap::from([("web".to_string(), chains)]),
+            rules: BTreeMap::from([("web".to_string(), 2)]),
+        };
+        let errors = RuleErrors::from([("ssh".to_string(), vec!["error".to_string()])]);
+        let status = collect(&Accounting::default(), live, &[&errors]);
+        assert_eq!(status.rules["web"].live_rules, 2);
+        assert_eq!(status.rules["web"].total(), counter);
+        assert_eq!(status.rules["ssh"].live_rules, 0);
+        assert_eq!(status.rules["ssh"].errors, vec!["error".to_string()]);
+    }
+}
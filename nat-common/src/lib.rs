@@ -1,3 +1,4 @@
+use chrono::{DateTime, FixedOffset};
 use clap::Parser;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Display;
@@ -5,6 +6,7 @@ use std::num::ParseIntError;
 use std::str::FromStr;
 
 pub mod logger;
+pub mod status;
 
 /// NAT CLI 命令行参数
 #[derive(Parser, Debug, Clone)]
@@ -49,7 +51,7 @@ pub enum IpVersion {
     V4,
     V6,
     #[default]
-    All, // 优先IPv4，如果IPv4不可用则使用IPv6
+    All, // 双栈：同时解析IPv4和IPv6，两个family都生成规则
 }
 
 impl Display for IpVersion {
@@ -110,6 +112,89 @@ pub enum Protocol {
     All,
     Tcp,
     Udp,
+    Sctp,
+    /// 仅用于过滤规则，可指定ICMP类型，如 icmp:echo-request
+    Icmp(Option<u8>),
+    /// 仅用于过滤规则，可指定ICMPv6类型，如 icmpv6:echo-request
+    Icmpv6(Option<u8>),
+    /// 任意传输层协议号，如 l4proto:47（GRE），仅用于过滤规则
+    L4proto(u8),
+}
+
+/// nft 支持的ICMP类型名
+const ICMP_TYPES: [(&str, u8); 11] = [
+    ("echo-reply", 0),
+    ("destination-unreachable", 3),
+    ("source-quench", 4),
+    ("redirect", 5),
+    ("echo-request", 8),
+    ("router-advertisement", 9),
+    ("router-solicitation", 10),
+    ("time-exceeded", 11),
+    ("parameter-problem", 12),
+    ("timestamp-request", 13),
+    ("timestamp-reply", 14),
+];
+
+/// nft 支持的ICMPv6类型名
+const ICMPV6_TYPES: [(&str, u8); 11] = [
+    ("destination-unreachable", 1),
+    ("packet-too-big", 2),
+    ("time-exceeded", 3),
+    ("parameter-problem", 4),
+    ("echo-request", 128),
+    ("echo-reply", 129),
+    ("nd-router-solicit", 133),
+    ("nd-router-advert", 134),
+    ("nd-neighbor-solicit", 135),
+    ("nd-neighbor-advert", 136),
+    ("nd-redirect", 137),
+];
+
+impl Protocol {
+    /// 是否有端口，只有 tcp、udp、sctp 和 all 可以匹配端口或用于转发
+    pub fn has_ports(&self) -> bool {
+        matches!(
+            self,
+            Protocol::All | Protocol::Tcp | Protocol::Udp | Protocol::Sctp
+        )
+    }
+
+    /// 只属于一个地址族的协议：icmp 仅IPv4，icmpv6 仅IPv6
+    pub fn ip_version(&self) -> Option<IpVersion> {
+        match self {
+            Protocol::Icmp(_) => Some(IpVersion::V4),
+            Protocol::Icmpv6(_) => Some(IpVersion::V6),
+            _ => None,
+        }
+    }
+
+    /// ICMP/ICMPv6类型的名称，nft没有对应名称时返回数字
+    pub fn icmp_type_name(&self) -> Option<String> {
+        let (types, value) = match self {
+            Protocol::Icmp(Some(value)) => (&ICMP_TYPES, *value),
+            Protocol::Icmpv6(Some(value)) => (&ICMPV6_TYPES, *value),
+            _ => return None,
+        };
+        Some(
+            types
+                .iter()
+                .find(|(_, v)| *v == value)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_else(|| value.to_string()),
+        )
+    }
+}
+
+/// 解析ICMP类型，支持名称或数字
+fn parse_icmp_type(types: &[(&str, u8)], value: &str) -> Result<u8, String> {
+    let value = value.trim().to_lowercase();
+    types
+        .iter()
+        .find(|(name, _)| *name == value)
+        .map(|(_, v)| *v)
+        .or_else(|| value.parse::<u8>().ok())
+        .ok_or_else(|| format!("无效的ICMP类型: {value}"))
 }
 
 // Drop链类型枚举
@@ -174,26 +259,52 @@ impl Display for Protocol {
             Protocol::All => write!(f, "all"),
             Protocol::Tcp => write!(f, "tcp"),
             Protocol::Udp => write!(f, "udp"),
+            Protocol::Sctp => write!(f, "sctp"),
+            Protocol::Icmp(_) | Protocol::Icmpv6(_) => {
+                let name = if matches!(self, Protocol::Icmp(_)) {
+                    "icmp"
+                } else {
+                    "icmpv6"
+                };
+                match self.icmp_type_name() {
+                    Some(icmp_type) => write!(f, "{name}:{icmp_type}"),
+                    None => write!(f, "{name}"),
+                }
+            }
+            Protocol::L4proto(number) => write!(f, "l4proto:{number}"),
         }
     }
 }
 
-impl From<String> for Protocol {
-    fn from(protocol: String) -> Self {
-        match protocol.to_lowercase().as_str() {
-            "tcp" => Protocol::Tcp,
-            "udp" => Protocol::Udp,
-            _ => Protocol::All,
-        }
-    }
-}
+impl FromStr for Protocol {
+    type Err = String;
 
-impl From<&str> for Protocol {
-    fn from(protocol: &str) -> Self {
-        match protocol.to_lowercase().as_str() {
-            "tcp" => Protocol::Tcp,
-            "udp" => Protocol::Udp,
-            _ => Protocol::All,
+    /// 支持 all、tcp、udp、sctp、icmp[:类型]、icmpv6[:类型] 和 l4proto:协议号
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s.as_str(), None),
+        };
+        match (name, arg) {
+            ("all", None) => Ok(Protocol::All),
+            ("tcp", None) => Ok(Protocol::Tcp),
+            ("udp", None) => Ok(Protocol::Udp),
+            ("sctp", None) => Ok(Protocol::Sctp),
+            ("icmp", None) => Ok(Protocol::Icmp(None)),
+            ("icmp", Some(arg)) => Ok(Protocol::Icmp(Some(parse_icmp_type(&ICMP_TYPES, arg)?))),
+            ("icmpv6", None) => Ok(Protocol::Icmpv6(None)),
+            ("icmpv6", Some(arg)) => {
+                Ok(Protocol::Icmpv6(Some(parse_icmp_type(&ICMPV6_TYPES, arg)?)))
+            }
+            ("l4proto", Some(arg)) => arg
+                .trim()
+                .parse::<u8>()
+                .map(Protocol::L4proto)
+                .map_err(|_| format!("无效的协议号: {arg}，应为0-255")),
+            _ => Err(format!(
+                "无效的协议: {s}，应为 all、tcp、udp、sctp、icmp[:类型]、icmpv6[:类型] 或 l4proto:协议号"
+            )),
         }
     }
 }
@@ -219,930 +330,5055 @@ impl<'de> Deserialize<'de> for Protocol {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(Protocol::from(s))
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
-// TOML配置结构定义
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TomlConfig {
-    #[serde(default)]
-    pub rules: Vec<NftCell>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum NftCell {
-    #[serde(rename = "single")]
-    Single {
-        #[serde(rename = "sport")]
-        sport: u16,
-        #[serde(rename = "dport")]
-        dport: u16,
-        #[serde(rename = "domain")]
-        domain: String,
-        #[serde(default)]
-        protocol: Protocol,
-        #[serde(default)]
-        ip_version: IpVersion,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        comment: Option<String>,
-    },
-    #[serde(rename = "range")]
-    Range {
-        #[serde(rename = "port_start")]
-        port_start: u16,
-        #[serde(rename = "port_end")]
-        port_end: u16,
-        #[serde(rename = "domain")]
-        domain: String,
-        #[serde(default)]
-        protocol: Protocol,
-        #[serde(default)]
-        ip_version: IpVersion,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        comment: Option<String>,
-    },
-    #[serde(rename = "redirect")]
-    Redirect {
-        #[serde(rename = "sport")]
-        src_port: u16,
-        #[serde(rename = "sport_end", skip_serializing_if = "Option::is_none")]
-        src_port_end: Option<u16>,
-        #[serde(rename = "dport")]
-        dst_port: u16,
-        #[serde(default)]
-        protocol: Protocol,
-        #[serde(default)]
-        ip_version: IpVersion,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        comment: Option<String>,
-    },
-    #[serde(rename = "drop")]
-    Drop {
-        #[serde(default)]
-        chain: Chain,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        src_ip: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        dst_ip: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        src_port: Option<u16>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        src_port_end: Option<u16>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        dst_port: Option<u16>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        dst_port_end: Option<u16>,
-        #[serde(default)]
-        protocol: Protocol,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        comment: Option<String>,
-    },
+// 负载均衡策略枚举
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalanceStrategy {
+    /// numgen random，按权重随机选择后端
+    #[default]
+    Random,
+    /// numgen inc，按权重轮询后端
+    RoundRobin,
+    /// jhash 源地址，同一客户端固定到同一后端
+    Hash,
 }
 
-impl Display for NftCell {
+impl Display for BalanceStrategy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            NftCell::Single {
-                sport,
-                dport,
-                domain,
-                protocol,
-                ip_version,
-                ..
-            } => write!(f, "SINGLE,{sport},{dport},{domain},{protocol},{ip_version}"),
-            NftCell::Range {
-                port_start,
-                port_end,
-                domain,
-                protocol,
-                ip_version,
-                ..
-            } => write!(
-                f,
-                "RANGE,{port_start},{port_end},{domain},{protocol},{ip_version}"
-            ),
-            NftCell::Redirect {
-                src_port,
-                src_port_end,
-                dst_port,
-                protocol,
-                ip_version,
-                ..
-            } => {
-                if let Some(end) = src_port_end {
-                    write!(
-                        f,
-                        "REDIRECT,{src_port}-{end},{dst_port},{protocol},{ip_version}"
-                    )
-                } else {
-                    write!(f, "REDIRECT,{src_port},{dst_port},{protocol},{ip_version}")
-                }
-            }
-            NftCell::Drop {
-                chain,
-                src_ip,
-                dst_ip,
-                src_port,
-                src_port_end,
-                dst_port,
-                dst_port_end,
-                protocol,
-                ..
-            } => {
-                let mut parts = vec![format!("DROP,{}", chain)];
+            BalanceStrategy::Random => write!(f, "random"),
+            BalanceStrategy::RoundRobin => write!(f, "round-robin"),
+            BalanceStrategy::Hash => write!(f, "hash"),
+        }
+    }
+}
 
-                if let Some(ip) = src_ip {
-                    parts.push(format!("src_ip={}", ip));
-                }
-                if let Some(ip) = dst_ip {
-                    parts.push(format!("dst_ip={}", ip));
-                }
-                if let Some(port) = src_port {
-                    if let Some(end) = src_port_end {
-                        parts.push(format!("src_port={}-{}", port, end));
-                    } else {
-                        parts.push(format!("src_port={}", port));
-                    }
-                }
-                if let Some(port) = dst_port {
-                    if let Some(end) = dst_port_end {
-                        parts.push(format!("dst_port={}-{}", port, end));
-                    } else {
-                        parts.push(format!("dst_port={}", port));
-                    }
-                }
-                parts.push(format!("{}", protocol));
+impl FromStr for BalanceStrategy {
+    type Err = String;
 
-                write!(f, "{}", parts.join(","))
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "random" => Ok(BalanceStrategy::Random),
+            "round-robin" | "roundrobin" | "rr" => Ok(BalanceStrategy::RoundRobin),
+            "hash" | "jhash" => Ok(BalanceStrategy::Hash),
+            _ => Err(format!(
+                "无效的负载均衡策略: {s}，应为 random、round-robin 或 hash"
+            )),
         }
     }
 }
 
-impl TomlConfig {
-    /// 验证配置是否合法
-    pub fn validate(&self) -> Result<(), String> {
-        for (idx, rule) in self.rules.iter().enumerate() {
-            rule.validate()
-                .map_err(|e| format!("规则 {} 验证失败: {}", idx + 1, e))?;
-        }
-        Ok(())
+impl Serialize for BalanceStrategy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
     }
+}
 
-    /// 从 TOML 字符串解析配置并验证
-    pub fn from_toml_str(s: &str) -> Result<Self, String> {
-        let config: TomlConfig = toml::from_str(s).map_err(|e| format!("解析TOML失败: {}", e))?;
-        config.validate()?;
-        Ok(config)
+impl<'de> Deserialize<'de> for BalanceStrategy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        BalanceStrategy::from_str(&s).map_err(serde::de::Error::custom)
     }
+}
 
-    /// 转换为 TOML 字符串
-    pub fn to_toml_string(&self) -> Result<String, String> {
-        toml::to_string_pretty(self).map_err(|e| format!("序列化TOML失败: {}", e))
+/// 端口或端口段，单个端口时 start == end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
     }
 }
 
-impl TryFrom<&str> for NftCell {
-    type Error = ParseError;
+impl FromStr for PortRange {
+    type Err = String;
 
-    /// 从legacy格式行解析NftCell
-    /// 注释行和空行返回 Err(ParseError::Skip)
-    /// 格式错误返回 Err(ParseError::InvalidFormat)
-    fn try_from(line: &str) -> Result<Self, Self::Error> {
-        let line = line.trim();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("无效的端口: {s}"))
+        };
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => {
+                let port = parse(s)?;
+                (port, port)
+            }
+        };
+        Ok(PortRange { start, end })
+    }
+}
 
-        // 处理注释和空行
-        if line.is_empty() || line.starts_with('#') {
-            return Err(ParseError::Skip);
+/// 一组端口和端口段，编译为nft匿名集合
+/// TOML中可以写单个端口 `80`、端口段 `"8000-8100"` 或列表 `[80, 443, "8000-8100"]`
+/// legacy格式用 `|` 分隔，如 `80|443|8000-8100`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ports(pub Vec<PortRange>);
+
+impl Ports {
+    /// 只有一个端口时返回该端口
+    pub fn single(&self) -> Option<u16> {
+        match self.0.as_slice() {
+            [range] if range.start == range.end => Some(range.start),
+            _ => None,
         }
+    }
 
-        let cells: Vec<&str> = line.split(',').collect();
-        let rule_type = cells.first().map(|s| s.trim()).unwrap_or("");
+    /// 展开后的端口数量
+    pub fn port_count(&self) -> usize {
+        self.0
+            .iter()
+            .map(|range| (range.end as usize).saturating_sub(range.start as usize) + 1)
+            .sum()
+    }
 
-        // 处理DROP类型
-        if rule_type == "DROP" {
-            if cells.len() < 3 {
-                return Err(ParseError::InvalidFormat(format!(
-                    "无效的过滤规则: {line}, DROP类型至少需要3个字段"
-                )));
+    /// 按配置顺序展开所有端口
+    pub fn iter_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().flat_map(|range| range.start..=range.end)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.0.is_empty() {
+            return Err("端口列表不能为空".to_string());
+        }
+        for range in &self.0 {
+            validate_port(range.start)?;
+            if range.start > range.end {
+                return Err(format!(
+                    "起始端口 {} 必须小于结束端口 {}",
+                    range.start, range.end
+                ));
             }
+        }
+        // nft匿名集合中的元素不能重叠
+        let mut sorted = self.0.clone();
+        sorted.sort();
+        for pair in sorted.windows(2) {
+            if pair[1].start <= pair[0].end {
+                return Err(format!("端口 {} 与 {} 重叠", pair[0], pair[1]));
+            }
+        }
+        Ok(())
+    }
+}
 
-            let chain: Chain = cells[1].trim().into();
+impl From<u16> for Ports {
+    fn from(port: u16) -> Self {
+        Ports(vec![PortRange {
+            start: port,
+            end: port,
+        }])
+    }
+}
 
-            let mut src_ip: Option<String> = None;
-            let mut dst_ip: Option<String> = None;
-            let mut src_port: Option<u16> = None;
-            let mut src_port_end: Option<u16> = None;
-            let mut dst_port: Option<u16> = None;
-            let mut dst_port_end: Option<u16> = None;
-            let mut protocol = Protocol::All;
+impl Display for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|range| range.to_string()).collect();
+        write!(f, "{}", parts.join("|"))
+    }
+}
 
-            // 解析key=value对和其他参数
-            for cell in cells.iter().skip(2) {
-                let cell = cell.trim();
+impl FromStr for Ports {
+    type Err = String;
 
-                // 检查是否是协议
-                if cell == "tcp" || cell == "udp" || cell == "all" {
-                    protocol = cell.into();
-                    continue;
-                }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('|')
+            .map(PortRange::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Ports)
+    }
+}
 
-                // 解析key=value
-                if let Some(eq_pos) = cell.find('=') {
-                    let key = &cell[..eq_pos];
-                    let value = &cell[eq_pos + 1..];
+impl Serialize for Ports {
+    /// 单个端口输出为数字，保持与只支持单端口时的配置一致
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeSeq;
 
-                    match key {
-                        "src_ip" => src_ip = Some(value.to_string()),
-                        "dst_ip" => dst_ip = Some(value.to_string()),
-                        "src_port" => {
-                            if value.contains('-') {
-                                let parts: Vec<&str> = value.split('-').collect();
-                                if parts.len() != 2 {
-                                    return Err(ParseError::InvalidFormat(format!(
-                                        "无效的端口范围格式: {value}"
-                                    )));
-                                }
-                                src_port = Some(parts[0].parse::<u16>()?);
-                                src_port_end = Some(parts[1].parse::<u16>()?);
-                            } else {
-                                src_port = Some(value.parse::<u16>()?);
-                            }
-                        }
-                        "dst_port" => {
-                            if value.contains('-') {
-                                let parts: Vec<&str> = value.split('-').collect();
-                                if parts.len() != 2 {
-                                    return Err(ParseError::InvalidFormat(format!(
-                                        "无效的端口范围格式: {value}"
-                                    )));
-                                }
-                                dst_port = Some(parts[0].parse::<u16>()?);
-                                dst_port_end = Some(parts[1].parse::<u16>()?);
-                            } else {
-                                dst_port = Some(value.parse::<u16>()?);
-                            }
-                        }
-                        _ => {
-                            return Err(ParseError::InvalidFormat(format!(
-                                "未知的过滤参数: {key}"
-                            )));
-                        }
-                    }
-                }
+        if let Some(port) = self.single() {
+            return serializer.serialize_u16(port);
+        }
+        if let [range] = self.0.as_slice() {
+            return serializer.serialize_str(&range.to_string());
+        }
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for range in &self.0 {
+            if range.start == range.end {
+                seq.serialize_element(&range.start)?;
+            } else {
+                seq.serialize_element(&range.to_string())?;
             }
+        }
+        seq.end()
+    }
+}
 
-            return Ok(NftCell::Drop {
-                chain,
-                src_ip,
-                dst_ip,
-                src_port,
-                src_port_end,
-                dst_port,
-                dst_port_end,
-                protocol,
-                comment: None,
-            });
+impl<'de> Deserialize<'de> for Ports {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Item {
+            Port(u16),
+            Text(String),
         }
 
-        // 验证字段数量（对于非DROP类型）
-        match rule_type {
-            "REDIRECT" => {
-                if cells.len() < 3 || cells.len() > 5 {
-                    return Err(ParseError::InvalidFormat(format!(
-                        "无效的配置行: {line}, REDIRECT类型需要3-5个字段"
-                    )));
-                }
-            }
-            "SINGLE" | "RANGE" => {
-                if cells.len() < 4 || cells.len() > 6 {
-                    return Err(ParseError::InvalidFormat(format!(
-                        "无效的配置行: {line}, 字段数量不正确（需要4-6个字段）"
-                    )));
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            One(Item),
+            Many(Vec<Item>),
+        }
+
+        let items = match Value::deserialize(deserializer)? {
+            Value::One(item) => vec![item],
+            Value::Many(items) => items,
+        };
+        let mut ranges = Vec::with_capacity(items.len());
+        for item in items {
+            match item {
+                Item::Port(port) => ranges.push(PortRange {
+                    start: port,
+                    end: port,
+                }),
+                Item::Text(text) => {
+                    ranges.extend(text.parse::<Ports>().map_err(serde::de::Error::custom)?.0)
                 }
             }
-            _ => {
-                return Err(ParseError::InvalidFormat(format!(
-                    "无效的转发规则类型: {}",
-                    rule_type
-                )));
-            }
         }
+        Ok(Ports(ranges))
+    }
+}
 
-        // 解析协议
-        let protocol: Protocol = if rule_type == "REDIRECT" {
-            if cells.len() >= 4 {
-                cells[3].trim().into()
-            } else {
-                Protocol::All
+/// 过滤规则命中后的动作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterAction {
+    #[default]
+    Drop,
+    /// TCP回复 reset，其他协议回复 ICMP/ICMPv6 端口不可达
+    Reject,
+    /// 记录内核日志后丢弃
+    LogAndDrop,
+}
+
+impl Display for FilterAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterAction::Drop => write!(f, "drop"),
+            FilterAction::Reject => write!(f, "reject"),
+            FilterAction::LogAndDrop => write!(f, "log-and-drop"),
+        }
+    }
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop" => Ok(FilterAction::Drop),
+            "reject" => Ok(FilterAction::Reject),
+            "log-and-drop" | "log_and_drop" => Ok(FilterAction::LogAndDrop),
+            _ => Err(format!(
+                "无效的过滤动作: {s}，应为 drop、reject 或 log-and-drop"
+            )),
+        }
+    }
+}
+
+impl Serialize for FilterAction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FilterAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FilterAction::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// 转发规则的实现方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardMode {
+    /// 内核nftables DNAT
+    #[default]
+    Nat,
+    /// nat-cli进程内的TCP/UDP中继，入站和出站可以是不同的地址族
+    Relay,
+}
+
+impl Display for ForwardMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardMode::Nat => write!(f, "nat"),
+            ForwardMode::Relay => write!(f, "relay"),
+        }
+    }
+}
+
+impl FromStr for ForwardMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "nat" => Ok(ForwardMode::Nat),
+            "relay" => Ok(ForwardMode::Relay),
+            _ => Err(format!("无效的转发方式: {s}，应为 nat 或 relay")),
+        }
+    }
+}
+
+impl Serialize for ForwardMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ForwardMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        ForwardMode::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// 负载均衡的单个后端
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceTarget {
+    pub domain: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// 权重上限，避免生成过大的 numgen/jhash 取值范围
+pub const MAX_BALANCE_WEIGHT: u32 = 100;
+
+impl Display for BalanceTarget {
+    /// legacy格式：`domain` 或 `domain*weight`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weight == 1 {
+            write!(f, "{}", self.domain)
+        } else {
+            write!(f, "{}*{}", self.domain, self.weight)
+        }
+    }
+}
+
+impl FromStr for BalanceTarget {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.rsplit_once('*') {
+            Some((domain, weight)) => Ok(BalanceTarget {
+                domain: domain.trim().to_string(),
+                weight: weight.trim().parse::<u32>().map_err(|e| {
+                    ParseError::InvalidFormat(format!("权重解析失败: {weight}, {e}"))
+                })?,
+            }),
+            None => Ok(BalanceTarget {
+                domain: s.to_string(),
+                weight: 1,
+            }),
+        }
+    }
+}
+
+/// 转发规则在POSTROUTING中的源地址转换方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Snat {
+    /// 使用出站网卡地址
+    Masquerade,
+    /// 不做SNAT，后端看到的是客户端真实源地址（透明代理等场景）
+    None,
+    /// snat to 指定地址
+    Address(String),
+}
+
+impl Snat {
+    /// 指定地址时返回是否为IPv6地址
+    pub fn is_ipv6(&self) -> Option<bool> {
+        match self {
+            Snat::Address(addr) => addr.parse::<std::net::IpAddr>().ok().map(|ip| ip.is_ipv6()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Snat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Snat::Masquerade => write!(f, "masquerade"),
+            Snat::None => write!(f, "none"),
+            Snat::Address(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+impl FromStr for Snat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_lowercase().as_str() {
+            "masquerade" => Ok(Snat::Masquerade),
+            "none" => Ok(Snat::None),
+            _ => s
+                .parse::<std::net::IpAddr>()
+                .map(|ip| Snat::Address(ip.to_string()))
+                .map_err(|_| format!("无效的snat配置: {s}，应为 masquerade、none 或IP地址")),
+        }
+    }
+}
+
+impl Serialize for Snat {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Snat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Snat::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// 限速的时间单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+}
+
+impl Display for TimeUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeUnit::Second => write!(f, "second"),
+            TimeUnit::Minute => write!(f, "minute"),
+            TimeUnit::Hour => write!(f, "hour"),
+            TimeUnit::Day => write!(f, "day"),
+            TimeUnit::Week => write!(f, "week"),
+        }
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "second" | "s" => Ok(TimeUnit::Second),
+            "minute" | "m" => Ok(TimeUnit::Minute),
+            "hour" | "h" => Ok(TimeUnit::Hour),
+            "day" | "d" => Ok(TimeUnit::Day),
+            "week" | "w" => Ok(TimeUnit::Week),
+            other => Err(format!("无效的时间单位: {other}")),
+        }
+    }
+}
+
+/// 字节限速的单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteUnit {
+    Bytes,
+    KBytes,
+    MBytes,
+}
+
+impl Display for ByteUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ByteUnit::Bytes => write!(f, "bytes"),
+            ByteUnit::KBytes => write!(f, "kbytes"),
+            ByteUnit::MBytes => write!(f, "mbytes"),
+        }
+    }
+}
+
+impl FromStr for ByteUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "bytes" | "b" => Ok(ByteUnit::Bytes),
+            "kbytes" | "kb" => Ok(ByteUnit::KBytes),
+            "mbytes" | "mb" => Ok(ByteUnit::MBytes),
+            other => Err(format!("无效的字节单位: {other}")),
+        }
+    }
+}
+
+/// 规则的限速配置
+/// 支持包速率 `100/second burst 200` 和字节速率 `10 mbytes/second burst 1 mbytes`
+/// Display 输出 nft `limit rate` 语句可直接使用的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u64,
+    /// None 表示按包计数
+    pub byte_unit: Option<ByteUnit>,
+    pub per: TimeUnit,
+    pub burst: Option<u64>,
+    pub burst_unit: Option<ByteUnit>,
+}
+
+impl RateLimit {
+    /// 是否为字节速率
+    pub fn is_bytes(&self) -> bool {
+        self.byte_unit.is_some()
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.byte_unit {
+            Some(unit) => write!(f, "{} {}/{}", self.rate, unit, self.per)?,
+            None => write!(f, "{}/{}", self.rate, self.per)?,
+        }
+        if let Some(burst) = self.burst {
+            match self.burst_unit {
+                Some(unit) => write!(f, " burst {} {}", burst, unit)?,
+                None => write!(f, " burst {} packets", burst)?,
             }
-        } else if cells.len() >= 5 {
-            cells[4].trim().into()
+        }
+        Ok(())
+    }
+}
+
+/// 拆分数字和紧跟的单位，例如 `10mbytes` -> (10, "mbytes")
+fn split_number_unit(s: &str) -> Result<(u64, &str), String> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = s[..pos]
+        .parse::<u64>()
+        .map_err(|_| format!("无效的数值: {s}"))?;
+    Ok((number, s[pos..].trim()))
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rate_part, burst_part) = match s.split_once("burst") {
+            Some((rate, burst)) => (rate.trim(), Some(burst.trim())),
+            None => (s, None),
+        };
+
+        let (amount, per) = rate_part
+            .split_once('/')
+            .ok_or_else(|| format!("无效的限速格式: {s}，应为 速率/时间单位"))?;
+        let (rate, unit) = split_number_unit(amount)?;
+        let byte_unit = if unit.is_empty() {
+            None
         } else {
-            Protocol::All
+            Some(unit.parse::<ByteUnit>()?)
         };
+        let per = per.parse::<TimeUnit>()?;
 
-        // 解析IP版本
-        let ip_version: IpVersion = if rule_type == "REDIRECT" {
-            if cells.len() >= 5 {
-                cells[4].trim().into()
-            } else {
-                IpVersion::V4 // 默认IPv4以保持向后兼容
+        let (burst, burst_unit) = match burst_part {
+            Some(burst) => {
+                let (burst, unit) = split_number_unit(burst)?;
+                // burst 未写单位时沿用速率的单位
+                let burst_unit = match unit {
+                    "" => byte_unit,
+                    "packets" if byte_unit.is_none() => None,
+                    unit if byte_unit.is_some() => Some(unit.parse::<ByteUnit>()?),
+                    _ => return Err(format!("限速 {s} 的速率和burst单位不一致（包/字节）")),
+                };
+                (Some(burst), burst_unit)
             }
-        } else if cells.len() >= 6 {
-            cells[5].trim().into()
-        } else {
-            IpVersion::V4 // 默认IPv4以保持向后兼容
+            None => (None, None),
         };
 
-        // 解析类型并创建NftCell
-        match rule_type {
-            "RANGE" => {
-                let port_start = cells[1].trim().parse::<u16>()?;
-                let port_end = cells[2].trim().parse::<u16>()?;
+        if rate == 0 {
+            return Err(format!("限速速率不能为0: {s}"));
+        }
+
+        Ok(RateLimit {
+            rate,
+            byte_unit,
+            per,
+            burst,
+            burst_unit,
+        })
+    }
+}
+
+/// 流量配额，如 `500 GiB`、`10GB`
+/// KB/MB/GB/TB 按1000进位，KiB/MiB/GiB/TiB 按1024进位
+/// Display 输出 nft quota 可直接使用的字节数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+}
+
+impl Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.bytes)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (amount, unit) = split_number_unit(s)?;
+        let multiplier: u64 = match unit.to_lowercase().as_str() {
+            "" | "b" | "bytes" => 1,
+            "kb" => 1000,
+            "mb" => 1000u64.pow(2),
+            "gb" => 1000u64.pow(3),
+            "tb" => 1000u64.pow(4),
+            "kib" | "kbytes" => 1024,
+            "mib" | "mbytes" => 1024u64.pow(2),
+            "gib" | "gbytes" => 1024u64.pow(3),
+            "tib" => 1024u64.pow(4),
+            other => return Err(format!("无效的配额单位: {other}")),
+        };
+        let bytes = amount
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("配额过大: {s}"))?;
+        if bytes == 0 {
+            return Err(format!("配额不能为0: {s}"));
+        }
+        Ok(Quota { bytes })
+    }
+}
+
+/// 流量配额的清零周期，按本机时区在周期开始时清零
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaReset {
+    /// 每天 00:00
+    Daily,
+    /// 每周一 00:00
+    Weekly,
+    /// 每月1日 00:00
+    Monthly,
+}
+
+impl Display for QuotaReset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaReset::Daily => write!(f, "daily"),
+            QuotaReset::Weekly => write!(f, "weekly"),
+            QuotaReset::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+impl FromStr for QuotaReset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "daily" => Ok(QuotaReset::Daily),
+            "weekly" => Ok(QuotaReset::Weekly),
+            "monthly" => Ok(QuotaReset::Monthly),
+            _ => Err(format!(
+                "无效的配额清零周期: {s}，应为 daily、weekly 或 monthly"
+            )),
+        }
+    }
+}
+
+impl Serialize for QuotaReset {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for QuotaReset {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        QuotaReset::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// 规则生效的时间窗口，编译为 nft 的 meta day / meta hour 匹配
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// 星期，如 ["mon", "fri"]，为空表示每天
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    /// 时间段，如 ["09:00-18:00"]，结束早于开始表示跨越午夜，为空表示全天
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hours: Vec<String>,
+    /// IANA时区，如 "Asia/Shanghai"，未配置时使用系统时区
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+impl Schedule {
+    /// 解析星期，0 表示星期日（与 nft meta day 一致）
+    pub fn weekdays(&self) -> Result<Vec<u32>, String> {
+        self.days
+            .iter()
+            .map(|day| weekday(day).ok_or_else(|| format!("无效的星期: {}", day.trim())))
+            .collect()
+    }
+
+    /// 解析时间段，返回 (开始分钟, 结束分钟)，结束可以是 24:00
+    pub fn hour_ranges(&self) -> Result<Vec<(u32, u32)>, String> {
+        self.hours
+            .iter()
+            .map(|range| {
+                let (start, end) = range
+                    .split_once('-')
+                    .ok_or_else(|| format!("无效的时间段: {range}，应为 HH:MM-HH:MM"))?;
+                let start = parse_clock(start)?;
+                let end = parse_clock(end)?;
+                if start == end || start == 24 * 60 {
+                    return Err(format!("无效的时间段: {range}"));
+                }
+                Ok((start, end))
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.days.is_empty() && self.hours.is_empty() {
+            return Err("至少需要指定 days 或 hours".to_string());
+        }
+        self.weekdays()?;
+        self.hour_ranges()?;
+        if let Some(timezone) = &self.timezone {
+            timezone
+                .parse::<chrono_tz::Tz>()
+                .map_err(|_| format!("无效的时区: {timezone}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// 解析星期名，只接受英文缩写或全称，如 mon、monday，0 表示星期日
+fn weekday(day: &str) -> Option<u32> {
+    const DAYS: [(&str, &str); 7] = [
+        ("sun", "sunday"),
+        ("mon", "monday"),
+        ("tue", "tuesday"),
+        ("wed", "wednesday"),
+        ("thu", "thursday"),
+        ("fri", "friday"),
+        ("sat", "saturday"),
+    ];
+    let day = day.trim().to_lowercase();
+    DAYS.iter()
+        .position(|(short, full)| day == *short || day == *full)
+        .map(|idx| idx as u32)
+}
+
+/// 解析 HH:MM，返回当天的分钟数
+fn parse_clock(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let (hour, minute) = s
+        .split_once(':')
+        .ok_or_else(|| format!("无效的时间: {s}，应为 HH:MM"))?;
+    let hour = hour
+        .parse::<u32>()
+        .map_err(|_| format!("无效的时间: {s}"))?;
+    let minute = minute
+        .parse::<u32>()
+        .map_err(|_| format!("无效的时间: {s}"))?;
+    if minute >= 60 || hour > 24 || (hour == 24 && minute != 0) {
+        return Err(format!("无效的时间: {s}"));
+    }
+    Ok(hour * 60 + minute)
+}
+
+impl Display for Schedule {
+    /// legacy格式：`mon|tue 09:00-18:00|20:00-22:00 Asia/Shanghai`，各部分均可省略
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.days.is_empty() {
+            parts.push(self.days.join("|"));
+        }
+        if !self.hours.is_empty() {
+            parts.push(self.hours.join("|"));
+        }
+        if let Some(timezone) = &self.timezone {
+            parts.push(timezone.clone());
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut schedule = Schedule {
+            days: vec![],
+            hours: vec![],
+            timezone: None,
+        };
+        for part in s.split_whitespace() {
+            let items: Vec<String> = part.split('|').map(|item| item.to_string()).collect();
+            // 先按星期解析，不是星期的部分再按时区解析
+            if part.starts_with(|c: char| c.is_ascii_digit()) {
+                schedule.hours = items;
+            } else if items.iter().all(|item| weekday(item).is_some()) {
+                schedule.days = items;
+            } else if part.parse::<chrono_tz::Tz>().is_ok() {
+                schedule.timezone = Some(part.to_string());
+            } else if part.contains('|') {
+                return Err(format!("无效的星期: {part}"));
+            } else {
+                return Err(format!(
+                    "无效的星期或时区: {part}，星期应为英文缩写或全称，时区应为 IANA 时区名"
+                ));
+            }
+        }
+        schedule.validate()?;
+        Ok(schedule)
+    }
+}
+
+/// nft log 语句支持的日志级别
+const LOG_LEVELS: [&str; 9] = [
+    "emerg", "alert", "crit", "err", "warn", "notice", "info", "debug", "audit",
+];
+
+/// 规则命中时写入内核日志的配置，编译为规则前一条只带 log 语句的规则
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RuleLog {
+    /// 日志前缀，便于在内核日志中按规则过滤
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// 日志级别：emerg、alert、crit、err、warn、notice、info、debug 或 audit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    /// 日志限速，例如 "10/minute"，只能按包计数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<String>,
+}
+
+impl RuleLog {
+    /// 解析日志限速，未配置时返回None
+    pub fn rate_limit(&self) -> Result<Option<RateLimit>, String> {
+        let Some(rate) = &self.rate else {
+            return Ok(None);
+        };
+        let limit = RateLimit::from_str(rate)?;
+        if limit.is_bytes() {
+            return Err(format!("日志限速只能按包计数: {rate}"));
+        }
+        Ok(Some(limit))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(prefix) = &self.prefix
+            && (prefix.is_empty()
+                || prefix.len() > 64
+                || prefix.contains(['"', '\\', ','])
+                || prefix.chars().any(char::is_whitespace))
+        {
+            return Err(format!(
+                "日志前缀 '{prefix}' 无效，长度应为1-64且不能包含空白、引号、反斜杠或逗号"
+            ));
+        }
+        if let Some(level) = &self.level
+            && !LOG_LEVELS.contains(&level.as_str())
+        {
+            return Err(format!(
+                "无效的日志级别: {level}，应为 {}",
+                LOG_LEVELS.join("、")
+            ));
+        }
+        self.rate_limit()?;
+        Ok(())
+    }
+}
+
+impl Display for RuleLog {
+    /// legacy格式：`prefix level rate`，如 `ssh-in info 10/minute`，各部分均可省略
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<&str> = [&self.prefix, &self.level, &self.rate]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl FromStr for RuleLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut log = RuleLog::default();
+        // 限速可能带 burst，如 `10/minute burst 5`，从第一个含 / 的部分开始都属于限速
+        let (head, rate) = match s.find('/') {
+            Some(pos) => {
+                let start = s[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+                (&s[..start], Some(s[start..].trim()))
+            }
+            None => (s, None),
+        };
+        log.rate = rate.map(str::to_string);
+        for part in head.split_whitespace() {
+            if LOG_LEVELS.contains(&part) {
+                log.level = Some(part.to_string());
+            } else if log.prefix.is_none() {
+                log.prefix = Some(part.to_string());
+            } else {
+                return Err(format!("无效的日志配置: {s}，应为 前缀 级别 限速"));
+            }
+        }
+        log.validate()?;
+        Ok(log)
+    }
+}
+
+/// 全局设置，对应TOML中的 [settings]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    /// IPv4规则默认的SNAT方式，取代环境变量 nat_local_ip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snat_ipv4: Option<Snat>,
+    /// IPv6规则默认的SNAT方式，取代环境变量 nat_local_ipv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snat_ipv6: Option<Snat>,
+    /// GeoIP数据库目录：MaxMind GeoLite2-Country CSV 所在目录，或每个国家一个CIDR文件（如 cn.zone、CN.txt）的目录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<String>,
+    /// 所有转发规则默认开启回环NAT
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hairpin: bool,
+    /// mode = "relay" 的UDP会话空闲超时（秒），未配置时为60秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_udp_timeout: Option<u64>,
+}
+
+impl Settings {
+    fn is_default(&self) -> bool {
+        *self == Settings::default()
+    }
+
+    /// 验证全局设置
+    pub fn validate(&self) -> Result<(), String> {
+        if self.snat_ipv4.as_ref().and_then(Snat::is_ipv6) == Some(true) {
+            return Err("settings.snat_ipv4 不能是IPv6地址".to_string());
+        }
+        if self.snat_ipv6.as_ref().and_then(Snat::is_ipv6) == Some(false) {
+            return Err("settings.snat_ipv6 不能是IPv4地址".to_string());
+        }
+        if self.relay_udp_timeout == Some(0) {
+            return Err("settings.relay_udp_timeout 必须大于0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// 规则分组，对应TOML中的 [[groups]]，组内规则生成到每个分组独立的nft链中
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleGroup {
+    pub name: String,
+    /// 为 false 时停用组内所有规则
+    #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl RuleGroup {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name, "分组名")
+    }
+}
+
+/// 命名IP集合，对应TOML中的 [[sets]]，编译为 self-filter 中的 interval 集合
+/// IPv4和IPv6地址可以混合配置，分别放入两个family的同名集合
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpSet {
+    pub name: String,
+    /// 直接写在配置中的IP或CIDR
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub elements: Vec<String>,
+    /// 本地文件路径，每行一个IP或CIDR，# 开头为注释，文件变化后自动重新加载
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl IpSet {
+    /// 验证集合名称和内联元素
+    pub fn validate(&self) -> Result<(), String> {
+        validate_set_name(&self.name)?;
+        if self.elements.is_empty() && self.file.is_none() {
+            return Err(format!("集合 {} 至少需要 elements 或 file 之一", self.name));
+        }
+        for ip in &self.elements {
+            validate_ip_address(ip, &format!("集合 {} 的", self.name))?;
+        }
+        if let Some(file) = &self.file
+            && file.trim().is_empty()
+        {
+            return Err(format!("集合 {} 的 file 不能为空", self.name));
+        }
+        Ok(())
+    }
+}
+
+// TOML配置结构定义
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TomlConfig {
+    #[serde(default, skip_serializing_if = "Settings::is_default")]
+    pub settings: Settings,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sets: Vec<IpSet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<RuleGroup>,
+    #[serde(default)]
+    pub rules: Vec<NftCell>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum NftCell {
+    #[serde(rename = "single")]
+    Single {
+        /// 监听端口，可以是端口列表，如 [80, 443, "8000-8100"]
+        #[serde(rename = "sport")]
+        sport: Ports,
+        /// 目标端口：单个端口，或与 sport 端口数相同的列表（按顺序一一对应）
+        #[serde(rename = "dport")]
+        dport: Ports,
+        #[serde(rename = "domain")]
+        domain: String,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        ip_version: IpVersion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        /// 规则标识，会写入nft注释，用于把nft中的规则、计数和错误对应回配置；未配置时按规则内容自动生成
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// 所属分组，对应 [[groups]] 中的 name，组内规则生成到独立的nft链中
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        /// 标签，用于标识规则
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        /// 为 false 时暂停该规则：保留在配置中，但不生成nft规则
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        /// 过期时间（RFC 3339），如 "2026-10-18T18:00:00+08:00"，过期后不再生成nft规则
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        /// 转发方式：nat（默认，内核DNAT）或 relay（用户态中继，可以跨地址族转发）
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<ForwardMode>,
+        /// 回环NAT：本机和内网通过本机地址访问该转发，未配置时使用全局设置
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hairpin: Option<bool>,
+        /// 命中时写入内核日志
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        /// 生效时间窗口
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        /// 源地址所属国家（ISO 3166 两位代码），需要在 [settings] 中配置 geoip
+        /// 转发规则只接受这些国家的源地址，过滤规则匹配这些国家的源地址
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        /// 引用 [[sets]] 中定义的命名集合，转发规则只接受集合内的源地址，过滤规则匹配集合内的源地址
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        /// 入站网卡名称，支持 eth* 通配
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        /// POSTROUTING 出站网卡名称
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        oifname: Option<String>,
+        /// POSTROUTING的SNAT方式：masquerade、none 或指定地址，未配置时使用全局设置
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snat: Option<Snat>,
+        /// 单个源IP的最大并发连接数
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_conns_per_src: Option<u32>,
+        /// 限速，例如 "100/second burst 200" 或 "10 mbytes/second"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        /// 流量配额，例如 "500 GiB"，用完后丢弃该规则的全部流量
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota: Option<String>,
+        /// 配额清零周期：daily、weekly 或 monthly，未配置时不自动清零
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota_reset: Option<QuotaReset>,
+        /// 为匹配的包设置 meta mark（fwmark），可配合 ip rule 做策略路由
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        /// 为连接设置 ct mark，回复方向的包会从 ct mark 恢复 meta mark
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow_src: Vec<String>,
+    },
+    #[serde(rename = "range")]
+    Range {
+        #[serde(rename = "port_start")]
+        port_start: u16,
+        #[serde(rename = "port_end")]
+        port_end: u16,
+        /// 目标端口段起始，未指定时与监听端口段相同
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dst_port_start: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dst_port_end: Option<u16>,
+        #[serde(rename = "domain")]
+        domain: String,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        ip_version: IpVersion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<ForwardMode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hairpin: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        oifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snat: Option<Snat>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_conns_per_src: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota_reset: Option<QuotaReset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow_src: Vec<String>,
+    },
+    #[serde(rename = "redirect")]
+    Redirect {
+        #[serde(rename = "sport")]
+        src_port: u16,
+        #[serde(rename = "sport_end", skip_serializing_if = "Option::is_none")]
+        src_port_end: Option<u16>,
+        #[serde(rename = "dport")]
+        dst_port: u16,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        ip_version: IpVersion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hairpin: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota_reset: Option<QuotaReset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow_src: Vec<String>,
+    },
+    #[serde(rename = "balance")]
+    Balance {
+        #[serde(rename = "sport")]
+        sport: Ports,
+        #[serde(rename = "dport")]
+        dport: u16,
+        #[serde(default)]
+        strategy: BalanceStrategy,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        ip_version: IpVersion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hairpin: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        oifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snat: Option<Snat>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quota_reset: Option<QuotaReset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow_src: Vec<String>,
+        targets: Vec<BalanceTarget>,
+    },
+    #[serde(rename = "drop")]
+    Drop {
+        #[serde(default)]
+        chain: Chain,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_ip: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_ip: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_port: Option<Ports>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_port_end: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_port: Option<Ports>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_port_end: Option<u16>,
+        #[serde(default)]
+        protocol: Protocol,
+        /// 命中后的动作：drop、reject 或 log-and-drop
+        #[serde(default)]
+        action: FilterAction,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+    },
+    #[serde(rename = "accept")]
+    Accept {
+        #[serde(default)]
+        chain: Chain,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_ip: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_ip: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_port: Option<Ports>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        src_port_end: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_port: Option<Ports>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst_port_end: Option<u16>,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<RuleLog>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        src_country: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        src_set: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        iifname: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mark: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ct_mark: Option<u32>,
+    },
+}
+
+impl Display for NftCell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NftCell::Single {
+                sport,
+                dport,
+                domain,
+                protocol,
+                ip_version,
+                ..
+            } => write!(
+                f,
+                "SINGLE,{sport},{dport},{domain},{protocol},{ip_version}{}",
+                self.fmt_legacy_options()
+            ),
+            NftCell::Range {
+                port_start,
+                port_end,
+                domain,
+                protocol,
+                ip_version,
+                ..
+            } => write!(
+                f,
+                "RANGE,{port_start},{port_end},{domain},{protocol},{ip_version}{}",
+                self.fmt_legacy_options()
+            ),
+            NftCell::Redirect {
+                src_port,
+                src_port_end,
+                dst_port,
+                protocol,
+                ip_version,
+                ..
+            } => {
+                let options = self.fmt_legacy_options();
+                if let Some(end) = src_port_end {
+                    write!(
+                        f,
+                        "REDIRECT,{src_port}-{end},{dst_port},{protocol},{ip_version}{options}"
+                    )
+                } else {
+                    write!(
+                        f,
+                        "REDIRECT,{src_port},{dst_port},{protocol},{ip_version}{options}"
+                    )
+                }
+            }
+            NftCell::Balance {
+                sport,
+                dport,
+                targets,
+                strategy,
+                protocol,
+                ip_version,
+                ..
+            } => {
+                let targets = targets
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join("|");
+                write!(
+                    f,
+                    "BALANCE,{sport},{dport},{targets},{protocol},{ip_version},{strategy}{}",
+                    self.fmt_legacy_options()
+                )
+            }
+            NftCell::Drop {
+                chain,
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                limit,
+                iifname,
+                src_set,
+                src_country,
+                schedule,
+                ..
+            }
+            | NftCell::Accept {
+                chain,
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                limit,
+                iifname,
+                src_set,
+                src_country,
+                schedule,
+                ..
+            } => {
+                let rule_type = if matches!(self, NftCell::Accept { .. }) {
+                    "ACCEPT"
+                } else {
+                    "DROP"
+                };
+                let mut parts = vec![format!("{},{}", rule_type, chain)];
+
+                if let Some(ip) = src_ip {
+                    parts.push(format!("src_ip={}", ip));
+                }
+                if let Some(name) = src_set {
+                    parts.push(format!("src_set={}", name));
+                }
+                if !src_country.is_empty() {
+                    parts.push(format!("src_country={}", src_country.join("|")));
+                }
+                if let Some(ip) = dst_ip {
+                    parts.push(format!("dst_ip={}", ip));
+                }
+                if let Some(port) = src_port {
+                    if let Some(end) = src_port_end {
+                        parts.push(format!("src_port={}-{}", port, end));
+                    } else {
+                        parts.push(format!("src_port={}", port));
+                    }
+                }
+                if let Some(port) = dst_port {
+                    if let Some(end) = dst_port_end {
+                        parts.push(format!("dst_port={}-{}", port, end));
+                    } else {
+                        parts.push(format!("dst_port={}", port));
+                    }
+                }
+                if let Some(limit) = limit {
+                    parts.push(format!("limit={}", limit));
+                }
+                if let Some(schedule) = schedule {
+                    parts.push(format!("schedule={}", schedule));
+                }
+                if let Some(log) = self.log() {
+                    parts.push(format!("log={}", log));
+                }
+                if let Some(name) = iifname {
+                    parts.push(format!("iifname={}", name));
+                }
+                if let Some(action) = self.filter_action()
+                    && action != FilterAction::Drop
+                {
+                    parts.push(format!("action={}", action));
+                }
+                parts.extend(self.fmt_mark_options());
+                parts.extend(self.fmt_group_options());
+                if let Some(expires_at) = self.expires_at() {
+                    parts.push(format!("expires_at={expires_at}"));
+                }
+                if !self.enabled() {
+                    parts.push("enabled=false".to_string());
+                }
+                parts.push(format!("{}", protocol));
+
+                write!(f, "{}", parts.join(","))
+            }
+        }
+    }
+}
+
+impl TomlConfig {
+    /// 验证配置是否合法
+    pub fn validate(&self) -> Result<(), String> {
+        self.settings.validate()?;
+        let mut set_names = std::collections::HashSet::new();
+        for set in &self.sets {
+            set.validate()?;
+            if !set_names.insert(set.name.as_str()) {
+                return Err(format!("集合 {} 重复定义", set.name));
+            }
+        }
+        let mut group_names = std::collections::HashSet::new();
+        for group in &self.groups {
+            group.validate()?;
+            if !group_names.insert(group.name.as_str()) {
+                return Err(format!("分组 {} 重复定义", group.name));
+            }
+        }
+        let mut rule_ids = std::collections::HashSet::new();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            rule.validate()
+                .map_err(|e| format!("规则 {} 验证失败: {}", idx + 1, e))?;
+            // 停用的规则可以与替代它的规则共用标识
+            let rule_id = rule.rule_id();
+            if rule.enabled() && !rule_ids.insert(rule_id.clone()) {
+                return Err(format!(
+                    "规则 {} 验证失败: 规则标识 {} 重复，请为规则配置不同的 id",
+                    idx + 1,
+                    rule_id
+                ));
+            }
+            if let Some(name) = rule.group()
+                && !group_names.contains(name)
+            {
+                return Err(format!("规则 {} 验证失败: 分组 {} 未定义", idx + 1, name));
+            }
+            if let Some(name) = rule.src_set()
+                && !set_names.contains(name)
+            {
+                return Err(format!("规则 {} 验证失败: 集合 {} 未定义", idx + 1, name));
+            }
+            if !rule.src_country().is_empty() && self.settings.geoip.is_none() {
+                return Err(format!(
+                    "规则 {} 验证失败: 使用 src_country 需要在 [settings] 中配置 geoip",
+                    idx + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 从 TOML 字符串解析配置并验证
+    pub fn from_toml_str(s: &str) -> Result<Self, String> {
+        let config: TomlConfig = toml::from_str(s).map_err(|e| format!("解析TOML失败: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 转换为 TOML 字符串
+    pub fn to_toml_string(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).map_err(|e| format!("序列化TOML失败: {}", e))
+    }
+}
+
+/// legacy格式中 SINGLE/RANGE/REDIRECT/BALANCE 的可选 key=value 参数
+#[derive(Debug, Default)]
+struct LegacyOptions {
+    allow_src: Vec<String>,
+    limit: Option<String>,
+    quota: Option<String>,
+    quota_reset: Option<QuotaReset>,
+    mark: Option<u32>,
+    ct_mark: Option<u32>,
+    schedule: Option<Schedule>,
+    log: Option<RuleLog>,
+    iifname: Option<String>,
+    hairpin: Option<bool>,
+    enabled: Option<bool>,
+    expires_at: Option<String>,
+    id: Option<String>,
+    group: Option<String>,
+    tags: Vec<String>,
+    src_set: Option<String>,
+    src_country: Vec<String>,
+    /// 仅SINGLE/RANGE可用：转发方式
+    mode: Option<ForwardMode>,
+    /// 仅SINGLE/RANGE/BALANCE可用：POSTROUTING 出站网卡
+    oifname: Option<String>,
+    /// 仅SINGLE/RANGE/BALANCE可用：SNAT方式
+    snat: Option<Snat>,
+    /// 仅SINGLE/RANGE可用：单个源IP的最大并发连接数
+    max_conns_per_src: Option<u32>,
+    /// 仅RANGE可用：目标端口段
+    dst_port_range: Option<(u16, u16)>,
+}
+
+/// legacy格式中的布尔参数，只接受 true 或 false
+fn parse_legacy_bool(key: &str, value: &str) -> Result<bool, ParseError> {
+    value
+        .trim()
+        .parse::<bool>()
+        .map_err(|_| ParseError::InvalidFormat(format!("{key} 应为 true 或 false: {value}")))
+}
+
+/// legacy格式中的标记值，支持十进制和 0x 开头的十六进制
+fn parse_legacy_mark(key: &str, value: &str) -> Result<u32, ParseError> {
+    let value = value.trim();
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    };
+    parsed.map_err(|_| ParseError::InvalidFormat(format!("{key} 应为32位整数: {value}")))
+}
+
+/// legacy格式中用 | 分隔的标签
+fn parse_legacy_tags(value: &str) -> Vec<String> {
+    value.split('|').map(|tag| tag.trim().to_string()).collect()
+}
+
+/// legacy格式中的国家代码，多个用 | 分隔，如 src_country=CN|HK
+fn parse_legacy_countries(value: &str) -> Vec<String> {
+    value
+        .split('|')
+        .map(|country| country.trim().to_string())
+        .collect()
+}
+
+impl LegacyOptions {
+    fn parse(cells: &[&str]) -> Result<Self, ParseError> {
+        let mut options = LegacyOptions::default();
+        for cell in cells {
+            let Some((key, value)) = cell.trim().split_once('=') else {
+                continue;
+            };
+            match key {
+                "allow_src" => {
+                    options.allow_src = value.split('|').map(|ip| ip.trim().to_string()).collect()
+                }
+                "limit" => options.limit = Some(value.trim().to_string()),
+                "mark" => options.mark = Some(parse_legacy_mark(key, value)?),
+                "ct_mark" => options.ct_mark = Some(parse_legacy_mark(key, value)?),
+                "quota" => options.quota = Some(value.trim().to_string()),
+                "quota_reset" => {
+                    options.quota_reset = Some(value.parse().map_err(ParseError::InvalidFormat)?)
+                }
+                "schedule" => {
+                    options.schedule = Some(value.parse().map_err(ParseError::InvalidFormat)?)
+                }
+                "log" => options.log = Some(value.parse().map_err(ParseError::InvalidFormat)?),
+                "iifname" => options.iifname = Some(value.trim().to_string()),
+                "hairpin" => options.hairpin = Some(parse_legacy_bool(key, value)?),
+                "enabled" => options.enabled = Some(parse_legacy_bool(key, value)?),
+                "expires_at" => options.expires_at = Some(value.trim().to_string()),
+                "id" => options.id = Some(value.trim().to_string()),
+                "group" => options.group = Some(value.trim().to_string()),
+                "tags" => options.tags = parse_legacy_tags(value),
+                "src_set" => options.src_set = Some(value.trim().to_string()),
+                "src_country" => options.src_country = parse_legacy_countries(value),
+                "mode" => options.mode = Some(value.parse().map_err(ParseError::InvalidFormat)?),
+                "oifname" => options.oifname = Some(value.trim().to_string()),
+                "snat" => options.snat = Some(value.parse().map_err(ParseError::InvalidFormat)?),
+                "max_conns_per_src" => {
+                    options.max_conns_per_src = Some(value.trim().parse::<u32>().map_err(|e| {
+                        ParseError::InvalidFormat(format!("max_conns_per_src 解析失败: {e}"))
+                    })?)
+                }
+                "dst_port" => {
+                    let Some((start, end)) = value.split_once('-') else {
+                        return Err(ParseError::InvalidFormat(format!(
+                            "无效的端口范围格式: {value}，应为 start-end"
+                        )));
+                    };
+                    options.dst_port_range =
+                        Some((start.trim().parse::<u16>()?, end.trim().parse::<u16>()?));
+                }
+                _ => {
+                    return Err(ParseError::InvalidFormat(format!("未知的转发参数: {key}")));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl NftCell {
+    /// 规则的限速配置
+    pub fn limit(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { limit, .. }
+            | NftCell::Range { limit, .. }
+            | NftCell::Redirect { limit, .. }
+            | NftCell::Balance { limit, .. }
+            | NftCell::Drop { limit, .. }
+            | NftCell::Accept { limit, .. } => limit.as_deref(),
+        }
+    }
+
+    /// 流量配额，过滤规则返回None
+    pub fn quota(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { quota, .. }
+            | NftCell::Range { quota, .. }
+            | NftCell::Redirect { quota, .. }
+            | NftCell::Balance { quota, .. } => quota.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// 流量配额的清零周期
+    pub fn quota_reset(&self) -> Option<QuotaReset> {
+        match self {
+            NftCell::Single { quota_reset, .. }
+            | NftCell::Range { quota_reset, .. }
+            | NftCell::Redirect { quota_reset, .. }
+            | NftCell::Balance { quota_reset, .. } => *quota_reset,
+            _ => None,
+        }
+    }
+
+    /// 为匹配的包设置的 meta mark
+    pub fn mark(&self) -> Option<u32> {
+        match self {
+            NftCell::Single { mark, .. }
+            | NftCell::Range { mark, .. }
+            | NftCell::Redirect { mark, .. }
+            | NftCell::Balance { mark, .. }
+            | NftCell::Drop { mark, .. }
+            | NftCell::Accept { mark, .. } => *mark,
+        }
+    }
+
+    /// 为连接设置的 ct mark
+    pub fn ct_mark(&self) -> Option<u32> {
+        match self {
+            NftCell::Single { ct_mark, .. }
+            | NftCell::Range { ct_mark, .. }
+            | NftCell::Redirect { ct_mark, .. }
+            | NftCell::Balance { ct_mark, .. }
+            | NftCell::Drop { ct_mark, .. }
+            | NftCell::Accept { ct_mark, .. } => *ct_mark,
+        }
+    }
+
+    /// 单个源IP的最大并发连接数，仅Single/Range规则支持
+    pub fn max_conns_per_src(&self) -> Option<u32> {
+        match self {
+            NftCell::Single {
+                max_conns_per_src, ..
+            }
+            | NftCell::Range {
+                max_conns_per_src, ..
+            } => *max_conns_per_src,
+            _ => None,
+        }
+    }
+
+    /// 入站网卡名称
+    pub fn iifname(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { iifname, .. }
+            | NftCell::Range { iifname, .. }
+            | NftCell::Redirect { iifname, .. }
+            | NftCell::Balance { iifname, .. }
+            | NftCell::Drop { iifname, .. }
+            | NftCell::Accept { iifname, .. } => iifname.as_deref(),
+        }
+    }
+
+    /// 出站网卡名称，Redirect/Drop/Accept规则不涉及出站网卡
+    pub fn oifname(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { oifname, .. }
+            | NftCell::Range { oifname, .. }
+            | NftCell::Balance { oifname, .. } => oifname.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// 引用的命名集合
+    pub fn src_set(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { src_set, .. }
+            | NftCell::Range { src_set, .. }
+            | NftCell::Redirect { src_set, .. }
+            | NftCell::Balance { src_set, .. }
+            | NftCell::Drop { src_set, .. }
+            | NftCell::Accept { src_set, .. } => src_set.as_deref(),
+        }
+    }
+
+    /// 源地址所属国家
+    pub fn src_country(&self) -> &[String] {
+        match self {
+            NftCell::Single { src_country, .. }
+            | NftCell::Range { src_country, .. }
+            | NftCell::Redirect { src_country, .. }
+            | NftCell::Balance { src_country, .. }
+            | NftCell::Drop { src_country, .. }
+            | NftCell::Accept { src_country, .. } => src_country,
+        }
+    }
+
+    /// 过滤规则命中后的动作，Accept规则和转发规则返回None
+    pub fn filter_action(&self) -> Option<FilterAction> {
+        match self {
+            NftCell::Drop { action, .. } => Some(*action),
+            _ => None,
+        }
+    }
+
+    /// 命中时的日志配置
+    pub fn log(&self) -> Option<&RuleLog> {
+        match self {
+            NftCell::Single { log, .. }
+            | NftCell::Range { log, .. }
+            | NftCell::Redirect { log, .. }
+            | NftCell::Balance { log, .. }
+            | NftCell::Drop { log, .. }
+            | NftCell::Accept { log, .. } => log.as_ref(),
+        }
+    }
+
+    /// 生效时间窗口
+    pub fn schedule(&self) -> Option<&Schedule> {
+        match self {
+            NftCell::Single { schedule, .. }
+            | NftCell::Range { schedule, .. }
+            | NftCell::Redirect { schedule, .. }
+            | NftCell::Balance { schedule, .. }
+            | NftCell::Drop { schedule, .. }
+            | NftCell::Accept { schedule, .. } => schedule.as_ref(),
+        }
+    }
+
+    /// 规则自身的回环NAT配置，过滤规则返回None
+    pub fn hairpin(&self) -> Option<bool> {
+        match self {
+            NftCell::Single { hairpin, .. }
+            | NftCell::Range { hairpin, .. }
+            | NftCell::Redirect { hairpin, .. }
+            | NftCell::Balance { hairpin, .. } => *hairpin,
+            _ => None,
+        }
+    }
+
+    /// 规则所属的分组
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { group, .. }
+            | NftCell::Range { group, .. }
+            | NftCell::Redirect { group, .. }
+            | NftCell::Balance { group, .. }
+            | NftCell::Drop { group, .. }
+            | NftCell::Accept { group, .. } => group.as_deref(),
+        }
+    }
+
+    /// 规则的标签
+    pub fn tags(&self) -> &[String] {
+        match self {
+            NftCell::Single { tags, .. }
+            | NftCell::Range { tags, .. }
+            | NftCell::Redirect { tags, .. }
+            | NftCell::Balance { tags, .. }
+            | NftCell::Drop { tags, .. }
+            | NftCell::Accept { tags, .. } => tags,
+        }
+    }
+
+    /// 规则是否启用，停用的规则不生成nft规则
+    pub fn enabled(&self) -> bool {
+        match self {
+            NftCell::Single { enabled, .. }
+            | NftCell::Range { enabled, .. }
+            | NftCell::Redirect { enabled, .. }
+            | NftCell::Balance { enabled, .. }
+            | NftCell::Drop { enabled, .. }
+            | NftCell::Accept { enabled, .. } => *enabled,
+        }
+    }
+
+    /// 规则的注释
+    pub fn comment(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { comment, .. }
+            | NftCell::Range { comment, .. }
+            | NftCell::Redirect { comment, .. }
+            | NftCell::Balance { comment, .. }
+            | NftCell::Drop { comment, .. }
+            | NftCell::Accept { comment, .. } => comment.as_deref(),
+        }
+    }
+
+    /// 配置中的规则标识
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { id, .. }
+            | NftCell::Range { id, .. }
+            | NftCell::Redirect { id, .. }
+            | NftCell::Balance { id, .. }
+            | NftCell::Drop { id, .. }
+            | NftCell::Accept { id, .. } => id.as_deref(),
+        }
+    }
+
+    /// 规则的稳定标识：优先使用配置的 id，否则按规则类型生成
+    /// 转发规则由协议和监听端口组成，如 single-tcp-10000；配置了 iifname、allow_src、src_set 或 src_country 时
+    /// 追加这些匹配条件的哈希，如 single-tcp-10000-1a2b3c4d，只修改目标地址、注释等配置时标识不变；
+    /// 过滤规则由链以及匹配条件、动作、限速、日志、定时、标记和过期时间的哈希组成，如 drop-input-1a2b3c4d
+    pub fn rule_id(&self) -> String {
+        if let Some(id) = self.id() {
+            return id.to_string();
+        }
+        let (kind, protocol, ports, ip_version) = match self {
+            NftCell::Single {
+                sport,
+                protocol,
+                ip_version,
+                ..
+            } => ("single", protocol, sport.to_string(), ip_version),
+            NftCell::Range {
+                port_start,
+                port_end,
+                protocol,
+                ip_version,
+                ..
+            } => (
+                "range",
+                protocol,
+                format!("{port_start}-{port_end}"),
+                ip_version,
+            ),
+            NftCell::Redirect {
+                src_port,
+                src_port_end,
+                protocol,
+                ip_version,
+                ..
+            } => {
+                let ports = match src_port_end {
+                    Some(end) => format!("{src_port}-{end}"),
+                    None => src_port.to_string(),
+                };
+                ("redirect", protocol, ports, ip_version)
+            }
+            NftCell::Balance {
+                sport,
+                protocol,
+                ip_version,
+                ..
+            } => ("balance", protocol, sport.to_string(), ip_version),
+            NftCell::Drop {
+                chain,
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                src_country,
+                src_set,
+                iifname,
+                ..
+            }
+            | NftCell::Accept {
+                chain,
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                src_country,
+                src_set,
+                iifname,
+                ..
+            } => {
+                let (kind, action) = match self {
+                    NftCell::Drop { action, .. } => ("drop", action.to_string()),
+                    _ => ("accept", String::new()),
+                };
+                // 动作、限速、日志、定时、标记和过期时间也参与哈希，只有这些配置不同的规则不会得到相同的标识
+                let matches = format!(
+                    "{chain}|{src_ip:?}|{dst_ip:?}|{src_port:?}|{src_port_end:?}|{dst_port:?}|{dst_port_end:?}|{protocol}|{src_country:?}|{src_set:?}|{iifname:?}|{action}|{:?}|{}|{}|{:?}|{:?}|{:?}",
+                    self.limit(),
+                    self.log().map(ToString::to_string).unwrap_or_default(),
+                    self.schedule().map(ToString::to_string).unwrap_or_default(),
+                    self.mark(),
+                    self.ct_mark(),
+                    self.expires_at(),
+                );
+                return format!("{kind}-{chain}-{:08x}", fnv1a(&matches));
+            }
+        };
+        let mut id = match ip_version {
+            IpVersion::All => format!("{kind}-{protocol}-{ports}"),
+            _ => format!("{kind}-{protocol}-{ports}-{ip_version}"),
+        };
+        // 同一端口按入站接口、源地址区分的多条规则（如LAN/WAN分流）需要不同的标识
+        let allow_src: &[String] = match self {
+            NftCell::Single { allow_src, .. }
+            | NftCell::Range { allow_src, .. }
+            | NftCell::Redirect { allow_src, .. }
+            | NftCell::Balance { allow_src, .. } => allow_src,
+            _ => &[],
+        };
+        if self.iifname().is_some()
+            || !allow_src.is_empty()
+            || self.src_set().is_some()
+            || !self.src_country().is_empty()
+        {
+            let matches = format!(
+                "{:?}|{allow_src:?}|{:?}|{:?}",
+                self.iifname(),
+                self.src_set(),
+                self.src_country()
+            );
+            id += &format!("-{:08x}", fnv1a(&matches));
+        }
+        id.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// 规则的过期时间，格式为RFC 3339
+    pub fn expires_at(&self) -> Option<&str> {
+        match self {
+            NftCell::Single { expires_at, .. }
+            | NftCell::Range { expires_at, .. }
+            | NftCell::Redirect { expires_at, .. }
+            | NftCell::Balance { expires_at, .. }
+            | NftCell::Drop { expires_at, .. }
+            | NftCell::Accept { expires_at, .. } => expires_at.as_deref(),
+        }
+    }
+
+    /// 解析后的过期时间，未配置或格式无效时返回None
+    pub fn expiry(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.expires_at()?).ok()
+    }
+
+    /// 转发方式，未配置时为内核DNAT；过滤、重定向和负载均衡规则始终为DNAT
+    pub fn forward_mode(&self) -> ForwardMode {
+        match self {
+            NftCell::Single { mode, .. } | NftCell::Range { mode, .. } => mode.unwrap_or_default(),
+            _ => ForwardMode::Nat,
+        }
+    }
+
+    /// 规则自身的SNAT配置，仅Single/Range/Balance规则支持
+    pub fn snat(&self) -> Option<&Snat> {
+        match self {
+            NftCell::Single { snat, .. }
+            | NftCell::Range { snat, .. }
+            | NftCell::Balance { snat, .. } => snat.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// legacy格式的 mark、ct_mark 参数，以十六进制输出
+    fn fmt_mark_options(&self) -> Vec<String> {
+        let mut parts = Vec::new();
+        if let Some(mark) = self.mark() {
+            parts.push(format!("mark={mark:#x}"));
+        }
+        if let Some(mark) = self.ct_mark() {
+            parts.push(format!("ct_mark={mark:#x}"));
+        }
+        parts
+    }
+
+    /// legacy格式的 id、group、tags 参数，所有规则类型共用
+    fn fmt_group_options(&self) -> Vec<String> {
+        let mut parts = Vec::new();
+        if let Some(id) = self.id() {
+            parts.push(format!("id={id}"));
+        }
+        if let Some(group) = self.group() {
+            parts.push(format!("group={group}"));
+        }
+        if !self.tags().is_empty() {
+            parts.push(format!("tags={}", self.tags().join("|")));
+        }
+        parts
+    }
+
+    /// 生成legacy格式的可选 key=value 参数部分，没有参数时返回空字符串
+    fn fmt_legacy_options(&self) -> String {
+        let mut parts = Vec::new();
+        match self {
+            NftCell::Single { allow_src, .. }
+            | NftCell::Range { allow_src, .. }
+            | NftCell::Redirect { allow_src, .. }
+            | NftCell::Balance { allow_src, .. } => {
+                if !allow_src.is_empty() {
+                    parts.push(format!("allow_src={}", allow_src.join("|")));
+                }
+            }
+            NftCell::Drop { .. } | NftCell::Accept { .. } => {}
+        }
+        if let Some(name) = self.src_set() {
+            parts.push(format!("src_set={name}"));
+        }
+        if !self.src_country().is_empty() {
+            parts.push(format!("src_country={}", self.src_country().join("|")));
+        }
+        if let Some(limit) = self.limit() {
+            parts.push(format!("limit={limit}"));
+        }
+        if let Some(quota) = self.quota() {
+            parts.push(format!("quota={quota}"));
+        }
+        if let Some(reset) = self.quota_reset() {
+            parts.push(format!("quota_reset={reset}"));
+        }
+        if let Some(schedule) = self.schedule() {
+            parts.push(format!("schedule={schedule}"));
+        }
+        if let Some(log) = self.log() {
+            parts.push(format!("log={log}"));
+        }
+        if let Some(max) = self.max_conns_per_src() {
+            parts.push(format!("max_conns_per_src={max}"));
+        }
+        if let NftCell::Range {
+            dst_port_start: Some(start),
+            dst_port_end: Some(end),
+            ..
+        } = self
+        {
+            parts.push(format!("dst_port={start}-{end}"));
+        }
+        if let Some(name) = self.iifname() {
+            parts.push(format!("iifname={name}"));
+        }
+        if let Some(name) = self.oifname() {
+            parts.push(format!("oifname={name}"));
+        }
+        if let Some(snat) = self.snat() {
+            parts.push(format!("snat={snat}"));
+        }
+        if let Some(hairpin) = self.hairpin() {
+            parts.push(format!("hairpin={hairpin}"));
+        }
+        if let NftCell::Single {
+            mode: Some(mode), ..
+        }
+        | NftCell::Range {
+            mode: Some(mode), ..
+        } = self
+        {
+            parts.push(format!("mode={mode}"));
+        }
+        parts.extend(self.fmt_mark_options());
+        parts.extend(self.fmt_group_options());
+        if let Some(expires_at) = self.expires_at() {
+            parts.push(format!("expires_at={expires_at}"));
+        }
+        if !self.enabled() {
+            parts.push("enabled=false".to_string());
+        }
+        parts.iter().map(|part| format!(",{part}")).collect()
+    }
+}
+
+impl TryFrom<&str> for NftCell {
+    type Error = ParseError;
+
+    /// 从legacy格式行解析NftCell
+    /// 注释行和空行返回 Err(ParseError::Skip)
+    /// 格式错误返回 Err(ParseError::InvalidFormat)
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let line = line.trim();
+
+        // 处理注释和空行
+        if line.is_empty() || line.starts_with('#') {
+            return Err(ParseError::Skip);
+        }
+
+        let cells: Vec<&str> = line.split(',').collect();
+        let rule_type = cells.first().map(|s| s.trim()).unwrap_or("");
+
+        // 处理DROP/ACCEPT类型
+        if rule_type == "DROP" || rule_type == "ACCEPT" {
+            if cells.len() < 3 {
+                return Err(ParseError::InvalidFormat(format!(
+                    "无效的过滤规则: {line}, {rule_type}类型至少需要3个字段"
+                )));
+            }
+
+            let chain: Chain = cells[1].trim().into();
+
+            let mut src_ip: Option<String> = None;
+            let mut dst_ip: Option<String> = None;
+            let mut src_port: Option<Ports> = None;
+            let mut src_port_end: Option<u16> = None;
+            let mut dst_port: Option<Ports> = None;
+            let mut dst_port_end: Option<u16> = None;
+            let mut protocol = Protocol::All;
+            let mut limit: Option<String> = None;
+            let mut iifname: Option<String> = None;
+            let mut schedule: Option<Schedule> = None;
+            let mut action = FilterAction::Drop;
+            let mut log: Option<RuleLog> = None;
+            let mut enabled = true;
+            let mut expires_at: Option<String> = None;
+            let mut id: Option<String> = None;
+            let mut group: Option<String> = None;
+            let mut tags: Vec<String> = vec![];
+            let mut src_set: Option<String> = None;
+            let mut src_country: Vec<String> = vec![];
+            let mut mark: Option<u32> = None;
+            let mut ct_mark: Option<u32> = None;
+
+            // 解析key=value对和其他参数
+            for cell in cells.iter().skip(2) {
+                let cell = cell.trim();
+
+                // 不带 = 的参数只能是协议，拼错的协议报错而不是按 all 处理
+                // 末尾的 ip_version 只为与转发规则格式一致，过滤规则按地址判断地址族
+                if cell.is_empty()
+                    || cell.eq_ignore_ascii_case("ipv4")
+                    || cell.eq_ignore_ascii_case("ipv6")
+                {
+                    continue;
+                }
+                if !cell.contains('=') {
+                    protocol = cell
+                        .parse()
+                        .map_err(|_| ParseError::InvalidFormat(format!("未知的协议: {cell}")))?;
+                    continue;
+                }
+
+                // 解析key=value
+                if let Some(eq_pos) = cell.find('=') {
+                    let key = &cell[..eq_pos];
+                    let value = &cell[eq_pos + 1..];
+
+                    match key {
+                        "src_ip" => src_ip = Some(value.to_string()),
+                        "dst_ip" => dst_ip = Some(value.to_string()),
+                        "limit" => limit = Some(value.trim().to_string()),
+                        "iifname" => iifname = Some(value.trim().to_string()),
+                        "schedule" => {
+                            schedule = Some(value.parse().map_err(ParseError::InvalidFormat)?)
+                        }
+                        "log" => log = Some(value.parse().map_err(ParseError::InvalidFormat)?),
+                        "enabled" => enabled = parse_legacy_bool(key, value)?,
+                        "expires_at" => expires_at = Some(value.trim().to_string()),
+                        "id" => id = Some(value.trim().to_string()),
+                        "group" => group = Some(value.trim().to_string()),
+                        "tags" => tags = parse_legacy_tags(value),
+                        "src_set" => src_set = Some(value.trim().to_string()),
+                        "src_country" => src_country = parse_legacy_countries(value),
+                        "mark" => mark = Some(parse_legacy_mark(key, value)?),
+                        "ct_mark" => ct_mark = Some(parse_legacy_mark(key, value)?),
+                        "action" if rule_type == "DROP" => {
+                            action = value.parse().map_err(ParseError::InvalidFormat)?
+                        }
+                        "src_port" => {
+                            // 多个端口用 | 分隔，单个端口段沿用 start-end 字段
+                            if value.contains('|') {
+                                src_port = Some(value.parse().map_err(ParseError::InvalidFormat)?);
+                            } else if value.contains('-') {
+                                let parts: Vec<&str> = value.split('-').collect();
+                                if parts.len() != 2 {
+                                    return Err(ParseError::InvalidFormat(format!(
+                                        "无效的端口范围格式: {value}"
+                                    )));
+                                }
+                                src_port = Some(parts[0].parse::<u16>()?.into());
+                                src_port_end = Some(parts[1].parse::<u16>()?);
+                            } else {
+                                src_port = Some(value.parse::<u16>()?.into());
+                            }
+                        }
+                        "dst_port" => {
+                            // 多个端口用 | 分隔，单个端口段沿用 start-end 字段
+                            if value.contains('|') {
+                                dst_port = Some(value.parse().map_err(ParseError::InvalidFormat)?);
+                            } else if value.contains('-') {
+                                let parts: Vec<&str> = value.split('-').collect();
+                                if parts.len() != 2 {
+                                    return Err(ParseError::InvalidFormat(format!(
+                                        "无效的端口范围格式: {value}"
+                                    )));
+                                }
+                                dst_port = Some(parts[0].parse::<u16>()?.into());
+                                dst_port_end = Some(parts[1].parse::<u16>()?);
+                            } else {
+                                dst_port = Some(value.parse::<u16>()?.into());
+                            }
+                        }
+                        _ => {
+                            return Err(ParseError::InvalidFormat(format!(
+                                "未知的过滤参数: {key}"
+                            )));
+                        }
+                    }
+                }
+            }
+
+            if rule_type == "ACCEPT" {
+                return Ok(NftCell::Accept {
+                    chain,
+                    src_ip,
+                    dst_ip,
+                    src_port,
+                    src_port_end,
+                    dst_port,
+                    dst_port_end,
+                    protocol,
+                    comment: None,
+                    id,
+                    group,
+                    tags,
+                    enabled,
+                    expires_at,
+                    log,
+                    schedule,
+                    src_country,
+                    src_set,
+                    limit,
+                    iifname,
+                    mark,
+                    ct_mark,
+                });
+            }
+            return Ok(NftCell::Drop {
+                chain,
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                action,
+                comment: None,
+                id,
+                group,
+                tags,
+                enabled,
+                expires_at,
+                log,
+                schedule,
+                src_country,
+                src_set,
+                limit,
+                mark,
+                ct_mark,
+                iifname,
+            });
+        }
+
+        // 非DROP类型的 key=value 为可选参数，其余为位置参数
+        let (option_cells, cells): (Vec<&str>, Vec<&str>) =
+            cells.into_iter().partition(|cell| cell.contains('='));
+        let options = LegacyOptions::parse(&option_cells)?;
+        if options.dst_port_range.is_some() && rule_type != "RANGE" {
+            return Err(ParseError::InvalidFormat(format!(
+                "无效的配置行: {line}, dst_port 参数仅适用于RANGE类型"
+            )));
+        }
+        if options.mode.is_some() && rule_type != "SINGLE" && rule_type != "RANGE" {
+            return Err(ParseError::InvalidFormat(format!(
+                "无效的配置行: {line}, mode 参数仅适用于SINGLE和RANGE类型"
+            )));
+        }
+        if (options.oifname.is_some() || options.snat.is_some()) && rule_type == "REDIRECT" {
+            return Err(ParseError::InvalidFormat(format!(
+                "无效的配置行: {line}, REDIRECT类型不经过POSTROUTING，不支持 oifname/snat 参数"
+            )));
+        }
+        if options.max_conns_per_src.is_some() && rule_type != "SINGLE" && rule_type != "RANGE" {
+            return Err(ParseError::InvalidFormat(format!(
+                "无效的配置行: {line}, max_conns_per_src 参数仅适用于SINGLE和RANGE类型"
+            )));
+        }
+
+        // 验证字段数量（对于非DROP类型）
+        match rule_type {
+            "REDIRECT" => {
+                if cells.len() < 3 || cells.len() > 5 {
+                    return Err(ParseError::InvalidFormat(format!(
+                        "无效的配置行: {line}, REDIRECT类型需要3-5个字段"
+                    )));
+                }
+            }
+            "SINGLE" | "RANGE" => {
+                if cells.len() < 4 || cells.len() > 6 {
+                    return Err(ParseError::InvalidFormat(format!(
+                        "无效的配置行: {line}, 字段数量不正确（需要4-6个字段）"
+                    )));
+                }
+            }
+            "BALANCE" => {
+                if cells.len() < 4 || cells.len() > 7 {
+                    return Err(ParseError::InvalidFormat(format!(
+                        "无效的配置行: {line}, BALANCE类型需要4-7个字段"
+                    )));
+                }
+            }
+            _ => {
+                return Err(ParseError::InvalidFormat(format!(
+                    "无效的转发规则类型: {}",
+                    rule_type
+                )));
+            }
+        }
+
+        // 解析协议
+        let protocol: Protocol = if rule_type == "REDIRECT" {
+            if cells.len() >= 4 {
+                cells[3].parse().map_err(ParseError::InvalidFormat)?
+            } else {
+                Protocol::All
+            }
+        } else if cells.len() >= 5 {
+            cells[4].parse().map_err(ParseError::InvalidFormat)?
+        } else {
+            Protocol::All
+        };
+
+        // 解析IP版本
+        let ip_version: IpVersion = if rule_type == "REDIRECT" {
+            if cells.len() >= 5 {
+                cells[4].trim().into()
+            } else {
+                IpVersion::V4 // 默认IPv4以保持向后兼容
+            }
+        } else if cells.len() >= 6 {
+            cells[5].trim().into()
+        } else {
+            IpVersion::V4 // 默认IPv4以保持向后兼容
+        };
+
+        // 解析类型并创建NftCell
+        match rule_type {
+            "RANGE" => {
+                let port_start = cells[1].trim().parse::<u16>()?;
+                let port_end = cells[2].trim().parse::<u16>()?;
+
+                Ok(NftCell::Range {
+                    port_start,
+                    port_end,
+                    dst_port_start: options.dst_port_range.map(|(start, _)| start),
+                    dst_port_end: options.dst_port_range.map(|(_, end)| end),
+                    domain: cells[3].trim().to_string(),
+                    protocol,
+                    ip_version,
+                    comment: None,
+                    id: options.id,
+                    group: options.group,
+                    tags: options.tags,
+                    enabled: options.enabled.unwrap_or(true),
+                    expires_at: options.expires_at,
+                    mode: options.mode,
+                    hairpin: options.hairpin,
+                    log: options.log,
+                    schedule: options.schedule,
+                    src_country: options.src_country,
+                    src_set: options.src_set,
+                    iifname: options.iifname,
+                    oifname: options.oifname,
+                    snat: options.snat,
+                    limit: options.limit,
+                    quota: options.quota,
+                    quota_reset: options.quota_reset,
+                    mark: options.mark,
+                    ct_mark: options.ct_mark,
+                    max_conns_per_src: options.max_conns_per_src,
+                    allow_src: options.allow_src,
+                })
+            }
+            "SINGLE" => {
+                let sport = cells[1].parse().map_err(ParseError::InvalidFormat)?;
+                let dport = cells[2].parse().map_err(ParseError::InvalidFormat)?;
+
+                Ok(NftCell::Single {
+                    sport,
+                    dport,
+                    domain: cells[3].trim().to_string(),
+                    protocol,
+                    ip_version,
+                    comment: None,
+                    id: options.id,
+                    group: options.group,
+                    tags: options.tags,
+                    enabled: options.enabled.unwrap_or(true),
+                    expires_at: options.expires_at,
+                    mode: options.mode,
+                    hairpin: options.hairpin,
+                    log: options.log,
+                    schedule: options.schedule,
+                    src_country: options.src_country,
+                    src_set: options.src_set,
+                    iifname: options.iifname,
+                    oifname: options.oifname,
+                    snat: options.snat,
+                    limit: options.limit,
+                    quota: options.quota,
+                    quota_reset: options.quota_reset,
+                    mark: options.mark,
+                    ct_mark: options.ct_mark,
+                    max_conns_per_src: options.max_conns_per_src,
+                    allow_src: options.allow_src,
+                })
+            }
+            "BALANCE" => {
+                let sport = cells[1].parse().map_err(ParseError::InvalidFormat)?;
+                let dport = cells[2].trim().parse::<u16>()?;
+                let targets = cells[3]
+                    .split('|')
+                    .map(BalanceTarget::from_str)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let strategy = if cells.len() >= 7 {
+                    cells[6].parse().map_err(ParseError::InvalidFormat)?
+                } else {
+                    BalanceStrategy::Random
+                };
+
+                Ok(NftCell::Balance {
+                    sport,
+                    dport,
+                    strategy,
+                    protocol,
+                    ip_version,
+                    comment: None,
+                    id: options.id,
+                    group: options.group,
+                    tags: options.tags,
+                    enabled: options.enabled.unwrap_or(true),
+                    expires_at: options.expires_at,
+                    hairpin: options.hairpin,
+                    log: options.log,
+                    schedule: options.schedule,
+                    src_country: options.src_country,
+                    src_set: options.src_set,
+                    iifname: options.iifname,
+                    oifname: options.oifname,
+                    snat: options.snat,
+                    limit: options.limit,
+                    quota: options.quota,
+                    quota_reset: options.quota_reset,
+                    mark: options.mark,
+                    ct_mark: options.ct_mark,
+                    allow_src: options.allow_src,
+                    targets,
+                })
+            }
+            "REDIRECT" => {
+                let port_field = cells[1].trim();
+                let (src_port, src_port_end) = if port_field.contains('-') {
+                    let parts: Vec<&str> = port_field.split('-').collect();
+                    if parts.len() != 2 {
+                        return Err(ParseError::InvalidFormat(format!(
+                            "无效的端口范围格式: {port_field}，应为 start-end"
+                        )));
+                    }
+                    let start = parts[0].trim().parse::<u16>()?;
+                    let end = parts[1].trim().parse::<u16>()?;
+                    (start, Some(end))
+                } else {
+                    (port_field.parse::<u16>()?, None)
+                };
+
+                let dst_port = cells[2].trim().parse::<u16>()?;
+
+                Ok(NftCell::Redirect {
+                    src_port,
+                    src_port_end,
+                    dst_port,
+                    protocol,
+                    ip_version,
+                    comment: None,
+                    id: options.id,
+                    group: options.group,
+                    tags: options.tags,
+                    enabled: options.enabled.unwrap_or(true),
+                    expires_at: options.expires_at,
+                    hairpin: options.hairpin,
+                    log: options.log,
+                    schedule: options.schedule,
+                    src_country: options.src_country,
+                    src_set: options.src_set,
+                    iifname: options.iifname,
+                    limit: options.limit,
+                    quota: options.quota,
+                    quota_reset: options.quota_reset,
+                    mark: options.mark,
+                    ct_mark: options.ct_mark,
+                    allow_src: options.allow_src,
+                })
+            }
+            _ => Err(ParseError::InvalidFormat(format!(
+                "无效的转发规则类型: {}",
+                rule_type
+            ))),
+        }
+    }
+}
+
+impl NftCell {
+    /// 验证单个规则是否合法
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(limit) = self.limit() {
+            RateLimit::from_str(limit).map_err(|e| format!("限速配置无效: {e}"))?;
+        }
+        if self.max_conns_per_src() == Some(0) {
+            return Err("max_conns_per_src 必须大于0".to_string());
+        }
+        if let Some(quota) = self.quota() {
+            Quota::from_str(quota).map_err(|e| format!("配额配置无效: {e}"))?;
+        }
+        if let Some(id) = self.id() {
+            validate_rule_id(id)?;
+        }
+        if let Some(expires_at) = self.expires_at() {
+            DateTime::parse_from_rfc3339(expires_at).map_err(|e| {
+                format!("expires_at '{expires_at}' 不是有效的RFC 3339时间，如 2026-10-18T18:00:00+08:00: {e}")
+            })?;
+        }
+        if self.mark() == Some(0) || self.ct_mark() == Some(0) {
+            return Err("mark 和 ct_mark 不能为0".to_string());
+        }
+        if matches!(self, NftCell::Drop { .. })
+            && (self.mark().is_some() || self.ct_mark().is_some())
+        {
+            return Err("drop 规则的包会被丢弃，不支持 mark 和 ct_mark".to_string());
+        }
+        if self.quota_reset().is_some() && self.quota().is_none() {
+            return Err("配置了 quota_reset 但没有配置 quota".to_string());
+        }
+        // 两个family的配额对象各自计量，双栈规则的实际上限会变成两倍
+        if self.quota().is_some()
+            && let NftCell::Single { ip_version, .. }
+            | NftCell::Range { ip_version, .. }
+            | NftCell::Redirect { ip_version, .. }
+            | NftCell::Balance { ip_version, .. } = self
+            && *ip_version == IpVersion::All
+        {
+            return Err(
+                "配置了 quota 的规则需要指定 ip_version 为 ipv4 或 ipv6，双栈流量请分成两条规则"
+                    .to_string(),
+            );
+        }
+        if let Some(name) = self.iifname() {
+            validate_ifname(name, "iifname")?;
+        }
+        if let Some(schedule) = self.schedule() {
+            schedule
+                .validate()
+                .map_err(|e| format!("schedule 无效: {e}"))?;
+        }
+        if let Some(log) = self.log() {
+            log.validate().map_err(|e| format!("log 无效: {e}"))?;
+        }
+        if let Some(group) = self.group() {
+            validate_name(group, "分组名")?;
+        }
+        for tag in self.tags() {
+            if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || ",|\"\\".contains(c)) {
+                return Err(format!(
+                    "标签 '{tag}' 无效，不能为空，也不能包含空白、逗号、竖线、引号或反斜杠"
+                ));
+            }
+        }
+        for country in self.src_country() {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(format!(
+                    "国家代码 '{country}' 无效，应为两位字母代码，如 CN"
+                ));
+            }
+        }
+        if let Some(name) = self.oifname() {
+            validate_ifname(name, "oifname")?;
+        }
+        if let NftCell::Single {
+            snat: Some(Snat::Address(addr)),
+            ip_version,
+            ..
+        }
+        | NftCell::Range {
+            snat: Some(Snat::Address(addr)),
+            ip_version,
+            ..
+        }
+        | NftCell::Balance {
+            snat: Some(Snat::Address(addr)),
+            ip_version,
+            ..
+        } = self
+        {
+            validate_ip_family(addr, ip_version, "snat")?;
+        }
+        let relay = self.forward_mode() == ForwardMode::Relay;
+        if relay {
+            self.validate_relay()?;
+        }
+        match self {
+            NftCell::Single { protocol, .. }
+            | NftCell::Range { protocol, .. }
+            | NftCell::Redirect { protocol, .. }
+            | NftCell::Balance { protocol, .. } => {
+                if !protocol.has_ports() {
+                    return Err(format!(
+                        "转发规则的协议只能是 all、tcp、udp 或 sctp，不支持 {protocol}"
+                    ));
+                }
+            }
+            NftCell::Drop {
+                protocol,
+                src_ip,
+                dst_ip,
+                src_port,
+                dst_port,
+                ..
+            }
+            | NftCell::Accept {
+                protocol,
+                src_ip,
+                dst_ip,
+                src_port,
+                dst_port,
+                ..
+            } => {
+                if !protocol.has_ports() && (src_port.is_some() || dst_port.is_some()) {
+                    return Err(format!(
+                        "协议 {protocol} 没有端口，不能匹配源端口或目标端口"
+                    ));
+                }
+                if let Some(ip_version) = protocol.ip_version() {
+                    let is_ipv6 = ip_version == IpVersion::V6;
+                    for ip in [src_ip, dst_ip].into_iter().flatten() {
+                        if ipnetwork::IpNetwork::from_str(ip).is_ok_and(|n| n.is_ipv6() != is_ipv6)
+                        {
+                            return Err(format!("协议 {protocol} 不能匹配地址 {ip}"));
+                        }
+                    }
+                }
+            }
+        }
+        match self {
+            NftCell::Single {
+                sport,
+                dport,
+                domain,
+                ip_version,
+                allow_src,
+                ..
+            } => {
+                if domain.trim().is_empty() {
+                    return Err("域名不能为空".to_string());
+                }
+                sport.validate()?;
+                dport.validate()?;
+                if dport.single().is_none() && dport.port_count() != sport.port_count() {
+                    return Err(format!(
+                        "目标端口 {dport} 与监听端口 {sport} 数量不一致，目标端口应为单个端口或一一对应"
+                    ));
+                }
+                validate_allow_src(allow_src, ip_version, relay)?;
+            }
+            NftCell::Range {
+                port_start,
+                port_end,
+                dst_port_start,
+                dst_port_end,
+                domain,
+                ip_version,
+                allow_src,
+                ..
+            } => {
+                if domain.trim().is_empty() {
+                    return Err("域名不能为空".to_string());
+                }
+                if port_start >= port_end {
+                    return Err(format!(
+                        "起始端口 {} 必须小于结束端口 {}",
+                        port_start, port_end
+                    ));
+                }
+                validate_port(*port_start)?;
+                validate_port(*port_end)?;
+                match (dst_port_start, dst_port_end) {
+                    (None, None) => {}
+                    (Some(dst_start), Some(dst_end)) => {
+                        validate_port(*dst_start)?;
+                        validate_port(*dst_end)?;
+                        if dst_start >= dst_end {
+                            return Err(format!(
+                                "目标起始端口 {} 必须小于目标结束端口 {}",
+                                dst_start, dst_end
+                            ));
+                        }
+                        if dst_end - dst_start != port_end - port_start {
+                            return Err(format!(
+                                "目标端口段 {}-{} 与监听端口段 {}-{} 长度不一致",
+                                dst_start, dst_end, port_start, port_end
+                            ));
+                        }
+                    }
+                    _ => {
+                        return Err("dst_port_start 和 dst_port_end 必须同时指定".to_string());
+                    }
+                }
+                validate_allow_src(allow_src, ip_version, relay)?;
+            }
+            NftCell::Redirect {
+                src_port,
+                src_port_end,
+                dst_port,
+                ip_version,
+                allow_src,
+                ..
+            } => {
+                if let Some(end) = src_port_end {
+                    if src_port >= end {
+                        return Err(format!("起始端口 {} 必须小于结束端口 {}", src_port, end));
+                    }
+                    validate_port(*end)?;
+                }
+                validate_port(*src_port)?;
+                validate_port(*dst_port)?;
+                validate_allow_src(allow_src, ip_version, relay)?;
+            }
+            NftCell::Balance {
+                sport,
+                dport,
+                targets,
+                ip_version,
+                allow_src,
+                ..
+            } => {
+                if targets.is_empty() {
+                    return Err("负载均衡至少需要一个后端".to_string());
+                }
+                for target in targets {
+                    if target.domain.trim().is_empty() {
+                        return Err("后端域名不能为空".to_string());
+                    }
+                    if target.weight == 0 || target.weight > MAX_BALANCE_WEIGHT {
+                        return Err(format!(
+                            "后端 {} 的权重 {} 无效，应在 1-{} 之间",
+                            target.domain, target.weight, MAX_BALANCE_WEIGHT
+                        ));
+                    }
+                }
+                sport.validate()?;
+                validate_port(*dport)?;
+                validate_allow_src(allow_src, ip_version, relay)?;
+            }
+            NftCell::Drop {
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                ..
+            }
+            | NftCell::Accept {
+                src_ip,
+                dst_ip,
+                src_port,
+                src_port_end,
+                dst_port,
+                dst_port_end,
+                protocol,
+                ..
+            } => {
+                // 至少需要指定一个过滤条件
+                if src_ip.is_none()
+                    && dst_ip.is_none()
+                    && src_port.is_none()
+                    && dst_port.is_none()
+                    && self.src_set().is_none()
+                    && self.src_country().is_empty()
+                    && protocol.has_ports()
+                {
+                    return Err(
+                        "至少需要指定一个过滤条件（源IP、源集合、源国家、目标IP、源端口、目标端口或ICMP/l4proto协议）"
+                            .to_string(),
+                    );
+                }
+
+                // 验证端口范围
+                if let Some(ports) = src_port {
+                    ports.validate()?;
+                    if let Some(end) = src_port_end {
+                        let Some(port) = ports.single() else {
+                            return Err("源端口为列表时不能指定 src_port_end".to_string());
+                        };
+                        validate_port(*end)?;
+                        if port >= *end {
+                            return Err(format!("源端口起始 {} 必须小于结束端口 {}", port, end));
+                        }
+                    }
+                }
+
+                if let Some(ports) = dst_port {
+                    ports.validate()?;
+                    if let Some(end) = dst_port_end {
+                        let Some(port) = ports.single() else {
+                            return Err("目标端口为列表时不能指定 dst_port_end".to_string());
+                        };
+                        validate_port(*end)?;
+                        if port >= *end {
+                            return Err(format!("目标端口起始 {} 必须小于结束端口 {}", port, end));
+                        }
+                    }
+                }
+
+                // 验证IP地址格式
+                if let Some(ip) = src_ip {
+                    if ip.trim().is_empty() {
+                        return Err("源IP不能为空".to_string());
+                    }
+                    validate_ip_address(ip, "源IP")?;
+                }
+
+                if let Some(ip) = dst_ip {
+                    if ip.trim().is_empty() {
+                        return Err("目标IP不能为空".to_string());
+                    }
+                    validate_ip_address(ip, "目标IP")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 用户态中继只处理TCP/UDP，不经过NAT表，也不受时间窗口控制
+    fn validate_relay(&self) -> Result<(), String> {
+        if let NftCell::Single { protocol, .. } | NftCell::Range { protocol, .. } = self
+            && !matches!(protocol, Protocol::All | Protocol::Tcp | Protocol::Udp)
+        {
+            return Err(format!(
+                "relay 模式的协议只能是 all、tcp 或 udp，不支持 {protocol}"
+            ));
+        }
+        let unsupported = [
+            ("snat", self.snat().is_some()),
+            ("oifname", self.oifname().is_some()),
+            ("iifname", self.iifname().is_some()),
+            ("hairpin", self.hairpin().is_some()),
+            ("schedule", self.schedule().is_some()),
+            ("quota", self.quota().is_some()),
+            ("mark", self.mark().is_some()),
+            ("ct_mark", self.ct_mark().is_some()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, configured)| *configured) {
+            return Err(format!("relay 模式不支持 {name}"));
+        }
+        Ok(())
+    }
+}
+
+/// 规则标识会写入nft注释和状态文件，限制为较短的字母、数字、下划线和连字符
+fn validate_rule_id(id: &str) -> Result<(), String> {
+    let valid = !id.is_empty()
+        && id.len() <= 32
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "id '{id}' 无效，只能包含字母、数字、下划线和连字符，长度不超过32"
+        ))
+    }
+}
+
+/// 32位FNV-1a哈希，结果只取决于输入内容，不随编译器版本变化
+fn fnv1a(text: &str) -> u32 {
+    text.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// 集合名会直接写入nft脚本，只允许字母开头的字母、数字和下划线
+fn validate_set_name(name: &str) -> Result<(), String> {
+    validate_name(name, "集合名")
+}
+
+/// 集合名、分组名会被原样用作nft中的集合名和链名
+fn validate_name(name: &str, kind: &str) -> Result<(), String> {
+    let valid = name.len() <= 64
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "{kind} '{name}' 无效，只能包含字母、数字和下划线，且以字母开头"
+        ))
+    }
+}
+
+fn validate_port(port: u16) -> Result<(), String> {
+    if port == 0 {
+        return Err("端口号不能为0".to_string());
+    }
+    Ok(())
+}
+
+/// 网卡名最长15个字符（IFNAMSIZ - 1），且会被原样写入nft脚本，因此不允许空白和引号
+fn validate_ifname(name: &str, field_name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{field_name} 不能为空"));
+    }
+    if name.len() > 15 {
+        return Err(format!("{field_name} {name} 过长，网卡名最多15个字符"));
+    }
+    if name
+        .chars()
+        .any(|c| c.is_whitespace() || c == '"' || c == '\'')
+    {
+        return Err(format!("{field_name} {name} 包含非法字符"));
+    }
+    Ok(())
+}
+
+/// 验证IP地址格式
+fn validate_ip_address(ip: &str, field_name: &str) -> Result<(), String> {
+    // 尝试解析为 IpNetwork（支持 CIDR 表示法）
+    if ipnetwork::IpNetwork::from_str(ip).is_ok() {
+        Ok(())
+    } else {
+        Err(format!("{}地址 '{}' 格式无效", field_name, ip))
+    }
+}
+
+/// 验证IP地址族与规则的 ip_version 一致，ip_version=all 时不限制
+fn validate_ip_family(ip: &str, ip_version: &IpVersion, field_name: &str) -> Result<(), String> {
+    let network = ipnetwork::IpNetwork::from_str(ip)
+        .map_err(|_| format!("{}地址 '{}' 格式无效", field_name, ip))?;
+    match ip_version {
+        IpVersion::V4 if network.is_ipv6() => Err(format!(
+            "{}地址 '{}' 为IPv6格式，与规则的 ip_version={} 不匹配",
+            field_name, ip, ip_version
+        )),
+        IpVersion::V6 if network.is_ipv4() => Err(format!(
+            "{}地址 '{}' 为IPv4格式，与规则的 ip_version={} 不匹配",
+            field_name, ip, ip_version
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 验证转发规则的源地址白名单
+/// ip_version 是后端的地址族；relay 模式在双栈地址上监听，客户端地址族与后端无关，不检查地址族
+fn validate_allow_src(
+    allow_src: &[String],
+    ip_version: &IpVersion,
+    relay: bool,
+) -> Result<(), String> {
+    for ip in allow_src {
+        if ip.trim().is_empty() {
+            return Err("允许的源IP不能为空".to_string());
+        }
+        validate_ip_address(ip, "允许的源IP")?;
+        if !relay {
+            validate_ip_family(ip, ip_version, "允许的源IP")?;
+        }
+    }
+    Ok(())
+}
+
+/// 验证legacy格式配置内容
+/// 返回第一个遇到的错误，跳过注释和空行
+pub fn validate_legacy_config(content: &str) -> Result<(), String> {
+    let mut rule_ids = std::collections::HashSet::new();
+    for (line_num, line) in content.lines().enumerate() {
+        match NftCell::try_from(line) {
+            Ok(cell) => {
+                cell.validate()
+                    .map_err(|e| format!("第 {} 行验证失败: {}", line_num + 1, e))?;
+                if !cell.src_country().is_empty() {
+                    return Err(format!(
+                        "第 {} 行验证失败: 使用 src_country 需要配置 geoip，传统格式不支持全局设置，请使用TOML配置",
+                        line_num + 1
+                    ));
+                }
+                if let Some(name) = cell.src_set() {
+                    return Err(format!(
+                        "第 {} 行验证失败: 集合 {} 未定义，传统格式不支持定义集合，请使用TOML配置",
+                        line_num + 1,
+                        name
+                    ));
+                }
+                let rule_id = cell.rule_id();
+                if cell.enabled() && !rule_ids.insert(rule_id.clone()) {
+                    return Err(format!(
+                        "第 {} 行验证失败: 规则标识 {} 重复，请用 id= 配置不同的标识",
+                        line_num + 1,
+                        rule_id
+                    ));
+                }
+            }
+            Err(ParseError::Skip) => continue,
+            Err(ParseError::InvalidFormat(msg)) => {
+                return Err(format!("第 {} 行解析失败: {}", line_num + 1, msg));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_single_rule() {
+        let rule = NftCell::Single {
+            sport: 10000.into(),
+            dport: 443.into(),
+            domain: "example.com".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_empty_domain() {
+        let rule = NftCell::Single {
+            sport: 10000.into(),
+            dport: 443.into(),
+            domain: "".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        };
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_range_rule() {
+        let rule = NftCell::Range {
+            port_start: 1000,
+            port_end: 2000,
+            dst_port_start: None,
+            dst_port_end: None,
+            domain: "example.com".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::All,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_invalid_range() {
+        let rule = NftCell::Range {
+            port_start: 2000,
+            port_end: 1000,
+            dst_port_start: None,
+            dst_port_end: None,
+            domain: "example.com".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        };
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_and_validate_toml() {
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "example.com"
+protocol = "tcp"
+ip_version = "ipv4"
+
+[[rules]]
+type = "range"
+port_start = 1000
+port_end = 2000
+domain = "example.com"
+protocol = "all"
+ip_version = "all"
+"#;
+        let result = TomlConfig::from_toml_str(toml_str);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_ip_version_serde() {
+        assert_eq!(IpVersion::from("ipv4"), IpVersion::V4);
+        assert_eq!(IpVersion::from("ipv6"), IpVersion::V6);
+        assert_eq!(IpVersion::from("all"), IpVersion::All);
+        assert_eq!(IpVersion::from("unknown"), IpVersion::All);
+    }
+
+    #[test]
+    fn test_protocol_serde() {
+        assert_eq!("tcp".parse::<Protocol>(), Ok(Protocol::Tcp));
+        assert_eq!("udp".parse::<Protocol>(), Ok(Protocol::Udp));
+        assert_eq!("all".parse::<Protocol>(), Ok(Protocol::All));
+        assert!("unknown".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn test_nft_cell_display() {
+        let cell = NftCell::Single {
+            sport: 10000.into(),
+            dport: 443.into(),
+            domain: "example.com".to_string(),
+            protocol: Protocol::Tcp,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            mode: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            max_conns_per_src: None,
+            allow_src: vec![],
+        };
+        assert_eq!(cell.to_string(), "SINGLE,10000,443,example.com,tcp,ipv4");
+
+        let cell = NftCell::Redirect {
+            src_port: 8000,
+            src_port_end: Some(9000),
+            dst_port: 3128,
+            protocol: Protocol::All,
+            ip_version: IpVersion::All,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec![],
+        };
+        assert_eq!(cell.to_string(), "REDIRECT,8000-9000,3128,all,all");
+    }
+
+    #[test]
+    fn test_try_from_single() {
+        let line = "SINGLE,10000,443,example.com,tcp,ipv4";
+        let cell = NftCell::try_from(line).unwrap();
+        match cell {
+            NftCell::Single {
+                sport,
+                dport,
+                domain,
+                protocol,
+                ip_version,
+                ..
+            } => {
+                assert_eq!(sport, 10000.into());
+                assert_eq!(dport, 443.into());
+                assert_eq!(domain, "example.com");
+                assert_eq!(protocol, Protocol::Tcp);
+                assert_eq!(ip_version, IpVersion::V4);
+            }
+            _ => panic!("Expected Single variant"),
+        }
+    }
+
+    #[test]
+    fn test_try_from_redirect_range() {
+        let line = "REDIRECT,30001-39999,45678,tcp,ipv4";
+        let cell = NftCell::try_from(line).unwrap();
+        match cell {
+            NftCell::Redirect {
+                src_port,
+                src_port_end,
+                dst_port,
+                ..
+            } => {
+                assert_eq!(src_port, 30001);
+                assert_eq!(src_port_end, Some(39999));
+                assert_eq!(dst_port, 45678);
+            }
+            _ => panic!("Expected Redirect variant"),
+        }
+    }
+
+    #[test]
+    fn test_try_from_comment() {
+        let line = "# This is a comment";
+        let result = NftCell::try_from(line);
+        assert!(matches!(result, Err(ParseError::Skip)));
+    }
+
+    #[test]
+    fn test_try_from_empty() {
+        let line = "   ";
+        let result = NftCell::try_from(line);
+        assert!(matches!(result, Err(ParseError::Skip)));
+    }
+
+    #[test]
+    fn test_try_from_invalid() {
+        let line = "INVALID,123,456";
+        let result = NftCell::try_from(line);
+        assert!(matches!(result, Err(ParseError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_validate_legacy_config() {
+        let content = "# Comment\nSINGLE,10000,443,example.com,tcp,ipv4\nREDIRECT,8000,3128\n";
+        assert!(validate_legacy_config(content).is_ok());
+    }
+
+    #[test]
+    fn test_validate_legacy_config_invalid() {
+        let content = "SINGLE,10000,443,example.com\nINVALID,123";
+        let result = validate_legacy_config(content);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_drop_ipv4_with_ipv4_address() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("192.168.1.1".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_with_ipv6_address() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("2001:db8::1".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_ipv4_with_ipv6_address_fails() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("2001:db8::1".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        let result = rule.validate();
+        assert!(result.is_err());
+        let err_msg = result.unwrap_err();
+        assert!(err_msg.contains("IPv6格式"));
+        assert!(err_msg.contains("ipv4"));
+    }
+
+    #[test]
+    fn test_drop_ipv6_with_ipv4_address_fails() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: None,
+            dst_ip: Some("192.168.1.1".to_string()),
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        let result = rule.validate();
+        assert!(result.is_err());
+        let err_msg = result.unwrap_err();
+        assert!(err_msg.contains("IPv4格式"));
+        assert!(err_msg.contains("ipv6"));
+    }
+
+    #[test]
+    fn test_drop_all_with_ipv4_address() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("10.0.0.1".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_all_with_ipv6_address() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("fe80::1".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_ipv4_cidr_notation() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("192.168.1.0/24".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_ipv6_cidr_notation() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("2001:db8::/32".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drop_invalid_ip_address() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("invalid.ip.address".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        let result = rule.validate();
+        assert!(result.is_err());
+        let err_msg = result.unwrap_err();
+        assert!(err_msg.contains("格式无效"));
+    }
+
+    #[test]
+    fn test_drop_invalid_cidr() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("192.168.1.1/99".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        let result = rule.validate();
+        assert!(result.is_err());
+        let err_msg = result.unwrap_err();
+        assert!(err_msg.contains("格式无效"));
+    }
+
+    #[test]
+    fn test_drop_valid_ipv6_full() {
+        let rule = NftCell::Drop {
+            chain: Chain::Input,
+            src_ip: Some("2001:0db8:85a3:0000:0000:8a2e:0370:7334".to_string()),
+            dst_ip: None,
+            src_port: None,
+            src_port_end: None,
+            dst_port: None,
+            dst_port_end: None,
+            protocol: Protocol::All,
+            action: FilterAction::Drop,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+        };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_try_from_balance() {
+        let line = "BALANCE,10000,443,10.0.0.1*3|10.0.0.2,tcp,ipv4,hash";
+        let cell = NftCell::try_from(line).unwrap();
+        match &cell {
+            NftCell::Balance {
+                sport,
+                dport,
+                targets,
+                strategy,
+                ..
+            } => {
+                assert_eq!(*sport, 10000.into());
+                assert_eq!(*dport, 443);
+                assert_eq!(targets.len(), 2);
+                assert_eq!(targets[0].domain, "10.0.0.1");
+                assert_eq!(targets[0].weight, 3);
+                assert_eq!(targets[1].weight, 1);
+                assert_eq!(*strategy, BalanceStrategy::Hash);
+            }
+            _ => panic!("Expected Balance variant"),
+        }
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        // 未知的策略报错，不再当作 random
+        let line = "BALANCE,10000,443,10.0.0.1|10.0.0.2,tcp,ipv4,weighted";
+        assert!(NftCell::try_from(line).is_err());
+        let err = validate_legacy_config(line).unwrap_err();
+        assert!(err.contains("weighted"), "{err}");
+        let toml_str = r#"
+[[rules]]
+type = "balance"
+sport = 10000
+dport = 443
+strategy = "weighted"
+targets = [{ domain = "10.0.0.1" }]
+"#;
+        let err = TomlConfig::from_toml_str(toml_str).unwrap_err();
+        assert!(err.contains("weighted"), "{err}");
+    }
 
-                Ok(NftCell::Range {
-                    port_start,
-                    port_end,
-                    domain: cells[3].trim().to_string(),
-                    protocol,
-                    ip_version,
-                    comment: None,
-                })
-            }
-            "SINGLE" => {
-                let sport = cells[1].trim().parse::<u16>()?;
-                let dport = cells[2].trim().parse::<u16>()?;
+    #[test]
+    fn test_parse_balance_toml() {
+        let toml_str = r#"
+[[rules]]
+type = "balance"
+sport = 10000
+dport = 443
+protocol = "tcp"
+ip_version = "ipv4"
 
-                Ok(NftCell::Single {
-                    sport,
-                    dport,
-                    domain: cells[3].trim().to_string(),
-                    protocol,
-                    ip_version,
-                    comment: None,
-                })
+[[rules.targets]]
+domain = "10.0.0.1"
+weight = 2
+
+[[rules.targets]]
+domain = "10.0.0.2"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        match &config.rules[0] {
+            NftCell::Balance {
+                targets, strategy, ..
+            } => {
+                assert_eq!(targets[0].weight, 2);
+                assert_eq!(targets[1].weight, 1);
+                assert_eq!(*strategy, BalanceStrategy::Random);
             }
-            "REDIRECT" => {
-                let port_field = cells[1].trim();
-                let (src_port, src_port_end) = if port_field.contains('-') {
-                    let parts: Vec<&str> = port_field.split('-').collect();
-                    if parts.len() != 2 {
-                        return Err(ParseError::InvalidFormat(format!(
-                            "无效的端口范围格式: {port_field}，应为 start-end"
-                        )));
-                    }
-                    let start = parts[0].trim().parse::<u16>()?;
-                    let end = parts[1].trim().parse::<u16>()?;
-                    (start, Some(end))
-                } else {
-                    (port_field.parse::<u16>()?, None)
-                };
+            _ => panic!("Expected Balance variant"),
+        }
+        let serialized = config.to_toml_string().unwrap();
+        assert!(TomlConfig::from_toml_str(&serialized).is_ok());
+    }
 
-                let dst_port = cells[2].trim().parse::<u16>()?;
+    #[test]
+    fn test_validate_balance_invalid_weight() {
+        let rule = NftCell::Balance {
+            sport: 10000.into(),
+            dport: 443,
+            strategy: BalanceStrategy::Random,
+            protocol: Protocol::All,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            oifname: None,
+            snat: None,
+            allow_src: vec![],
+            targets: vec![BalanceTarget {
+                domain: "10.0.0.1".to_string(),
+                weight: 0,
+            }],
+        };
+        assert!(rule.validate().is_err());
+    }
 
-                Ok(NftCell::Redirect {
-                    src_port,
-                    src_port_end,
-                    dst_port,
-                    protocol,
-                    ip_version,
-                    comment: None,
-                })
+    #[test]
+    fn test_allow_src_legacy_roundtrip() {
+        let line = "SINGLE,10000,443,example.com,tcp,ipv4,allow_src=1.2.3.0/24|5.6.7.8";
+        let cell = NftCell::try_from(line).unwrap();
+        match &cell {
+            NftCell::Single { allow_src, .. } => {
+                assert_eq!(allow_src, &vec!["1.2.3.0/24", "5.6.7.8"]);
             }
-            _ => Err(ParseError::InvalidFormat(format!(
-                "无效的转发规则类型: {}",
-                rule_type
-            ))),
+            _ => panic!("Expected Single variant"),
         }
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
     }
-}
 
-impl NftCell {
-    /// 验证单个规则是否合法
-    pub fn validate(&self) -> Result<(), String> {
-        match self {
-            NftCell::Single {
-                sport,
-                dport,
-                domain,
-                ..
-            } => {
-                if domain.trim().is_empty() {
-                    return Err("域名不能为空".to_string());
-                }
-                validate_port(*sport)?;
-                validate_port(*dport)?;
-            }
+    #[test]
+    fn test_allow_src_family_mismatch() {
+        let rule = NftCell::Redirect {
+            src_port: 8000,
+            src_port_end: None,
+            dst_port: 3128,
+            protocol: Protocol::All,
+            ip_version: IpVersion::V4,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec!["2001:db8::/32".to_string()],
+        };
+        let err_msg = rule.validate().unwrap_err();
+        assert!(err_msg.contains("IPv6格式"));
+
+        let rule = NftCell::Redirect {
+            src_port: 8000,
+            src_port_end: None,
+            dst_port: 3128,
+            protocol: Protocol::All,
+            ip_version: IpVersion::All,
+            comment: None,
+            id: None,
+            group: None,
+            tags: vec![],
+            enabled: true,
+            expires_at: None,
+            hairpin: None,
+            log: None,
+            schedule: None,
+            src_country: vec![],
+            src_set: None,
+            limit: None,
+            quota: None,
+            quota_reset: None,
+            mark: None,
+            ct_mark: None,
+            iifname: None,
+            allow_src: vec!["2001:db8::/32".to_string(), "10.0.0.0/8".to_string()],
+        };
+        assert!(rule.validate().is_ok());
+
+        // relay 在双栈地址上监听，IPv4后端也可以接受IPv6客户端
+        let line = "SINGLE,10000,443,10.0.0.1,tcp,ipv4,allow_src=2001:db8::/32|1.2.3.0/24";
+        let cell = NftCell::try_from(line).unwrap();
+        assert!(cell.validate().unwrap_err().contains("IPv6格式"));
+        let cell = NftCell::try_from(format!("{line},mode=relay").as_str()).unwrap();
+        assert!(cell.validate().is_ok());
+    }
+
+    #[test]
+    fn test_range_dst_port_remap() {
+        let line = "RANGE,20000,20999,example.com,tcp,ipv4,dst_port=30000-30999";
+        let cell = NftCell::try_from(line).unwrap();
+        match &cell {
             NftCell::Range {
-                port_start,
-                port_end,
-                domain,
-                ..
-            } => {
-                if domain.trim().is_empty() {
-                    return Err("域名不能为空".to_string());
-                }
-                if port_start >= port_end {
-                    return Err(format!(
-                        "起始端口 {} 必须小于结束端口 {}",
-                        port_start, port_end
-                    ));
-                }
-                validate_port(*port_start)?;
-                validate_port(*port_end)?;
-            }
-            NftCell::Redirect {
-                src_port,
-                src_port_end,
-                dst_port,
+                dst_port_start,
+                dst_port_end,
                 ..
             } => {
-                if let Some(end) = src_port_end {
-                    if src_port >= end {
-                        return Err(format!("起始端口 {} 必须小于结束端口 {}", src_port, end));
-                    }
-                    validate_port(*end)?;
-                }
-                validate_port(*src_port)?;
-                validate_port(*dst_port)?;
+                assert_eq!(*dst_port_start, Some(30000));
+                assert_eq!(*dst_port_end, Some(30999));
             }
-            NftCell::Drop {
+            _ => panic!("Expected Range variant"),
+        }
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let cell = NftCell::try_from("RANGE,20000,20999,example.com,tcp,ipv4,dst_port=30000-31000")
+            .unwrap();
+        assert!(cell.validate().unwrap_err().contains("长度不一致"));
+
+        let result = NftCell::try_from("SINGLE,10000,443,example.com,tcp,ipv4,dst_port=1-2");
+        assert!(matches!(result, Err(ParseError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_try_from_accept() {
+        let line = "ACCEPT,input,src_ip=10.0.0.0/8,dst_port=22,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        match &cell {
+            NftCell::Accept {
+                chain,
                 src_ip,
-                dst_ip,
-                src_port,
-                src_port_end,
                 dst_port,
-                dst_port_end,
+                protocol,
                 ..
             } => {
-                // 至少需要指定一个过滤条件
-                if src_ip.is_none() && dst_ip.is_none() && src_port.is_none() && dst_port.is_none()
-                {
-                    return Err(
-                        "至少需要指定一个过滤条件（源IP、目标IP、源端口或目标端口）".to_string()
-                    );
-                }
+                assert_eq!(*chain, Chain::Input);
+                assert_eq!(src_ip.as_deref(), Some("10.0.0.0/8"));
+                assert_eq!(*dst_port, Some(22.into()));
+                assert_eq!(*protocol, Protocol::Tcp);
+            }
+            _ => panic!("Expected Accept variant"),
+        }
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+    }
 
-                // 验证端口范围
-                if let Some(port) = src_port {
-                    validate_port(*port)?;
-                    if let Some(end) = src_port_end {
-                        validate_port(*end)?;
-                        if port >= end {
-                            return Err(format!("源端口起始 {} 必须小于结束端口 {}", port, end));
-                        }
-                    }
-                }
+    #[test]
+    fn test_rate_limit_parse() {
+        let limit = RateLimit::from_str("100/second burst 200").unwrap();
+        assert!(!limit.is_bytes());
+        assert_eq!(limit.to_string(), "100/second burst 200 packets");
 
-                if let Some(port) = dst_port {
-                    validate_port(*port)?;
-                    if let Some(end) = dst_port_end {
-                        validate_port(*end)?;
-                        if port >= end {
-                            return Err(format!("目标端口起始 {} 必须小于结束端口 {}", port, end));
-                        }
-                    }
-                }
+        let limit = RateLimit::from_str("10 mbytes/second burst 1").unwrap();
+        assert!(limit.is_bytes());
+        assert_eq!(limit.to_string(), "10 mbytes/second burst 1 mbytes");
 
-                // 验证IP地址格式
-                if let Some(ip) = src_ip {
-                    if ip.trim().is_empty() {
-                        return Err("源IP不能为空".to_string());
-                    }
-                    validate_ip_address(ip, "源IP")?;
-                }
+        let limit = RateLimit::from_str("512kbytes/minute").unwrap();
+        assert_eq!(limit.to_string(), "512 kbytes/minute");
 
-                if let Some(ip) = dst_ip {
-                    if ip.trim().is_empty() {
-                        return Err("目标IP不能为空".to_string());
-                    }
-                    validate_ip_address(ip, "目标IP")?;
-                }
-            }
-        }
-        Ok(())
+        assert!(RateLimit::from_str("100").is_err());
+        assert!(RateLimit::from_str("0/second").is_err());
+        assert!(RateLimit::from_str("100/fortnight").is_err());
+        assert!(RateLimit::from_str("100/second burst 2 mbytes").is_err());
     }
-}
 
-fn validate_port(port: u16) -> Result<(), String> {
-    if port == 0 {
-        return Err("端口号不能为0".to_string());
+    #[test]
+    fn test_limit_legacy_and_validate() {
+        let line = "SINGLE,10000,443,example.com,tcp,ipv4,limit=100/second burst 200";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.limit(), Some("100/second burst 200"));
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let cell = NftCell::try_from("DROP,input,dst_port=22,limit=10/minute,tcp").unwrap();
+        assert_eq!(cell.limit(), Some("10/minute"));
+        assert_eq!(
+            cell.to_string(),
+            "DROP,input,dst_port=22,limit=10/minute,tcp"
+        );
+
+        let cell = NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,limit=fast").unwrap();
+        assert!(cell.validate().unwrap_err().contains("限速配置无效"));
     }
-    Ok(())
-}
 
-/// 验证IP地址格式
-fn validate_ip_address(ip: &str, field_name: &str) -> Result<(), String> {
-    // 尝试解析为 IpNetwork（支持 CIDR 表示法）
-    if ipnetwork::IpNetwork::from_str(ip).is_ok() {
-        Ok(())
-    } else {
-        Err(format!("{}地址 '{}' 格式无效", field_name, ip))
-    }
-}
+    #[test]
+    fn test_max_conns_per_src_legacy() {
+        let line = "RANGE,20000,20100,example.com,tcp,ipv4,max_conns_per_src=20";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.max_conns_per_src(), Some(20));
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,example.com,tcp,ipv4,max_conns_per_src=0").unwrap();
+        assert!(cell.validate().is_err());
+
+        let result = NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,max_conns_per_src=5");
+        assert!(matches!(result, Err(ParseError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_ifname_legacy() {
+        let line = "SINGLE,10000,443,example.com,tcp,ipv4,iifname=eth0,oifname=wg0";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.iifname(), Some("eth0"));
+        assert_eq!(cell.oifname(), Some("wg0"));
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let line = "DROP,input,src_ip=1.2.3.4,iifname=eth*,all";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.iifname(), Some("eth*"));
+        assert_eq!(cell.to_string(), line);
+
+        let result = NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,oifname=eth0");
+        assert!(matches!(result, Err(ParseError::InvalidFormat(_))));
+
+        let cell = NftCell::try_from(
+            "SINGLE,10000,443,example.com,tcp,ipv4,iifname=averyveryverylongname",
+        )
+        .unwrap();
+        assert!(cell.validate().unwrap_err().contains("过长"));
+    }
+
+    #[test]
+    fn test_snat_legacy_and_settings() {
+        let line = "SINGLE,10000,443,example.com,tcp,ipv4,snat=none";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.snat(), Some(&Snat::None));
+        assert_eq!(cell.to_string(), line);
+
+        let cell =
+            NftCell::try_from("RANGE,1000,2000,example.com,tcp,ipv4,snat=2001:db8::1").unwrap();
+        assert!(cell.validate().unwrap_err().contains("不匹配"));
+
+        assert!(NftCell::try_from("SINGLE,10000,443,example.com,tcp,ipv4,snat=foo").is_err());
+        assert!(NftCell::try_from("REDIRECT,8000,3128,tcp,ipv4,snat=none").is_err());
+
+        let toml_str = r#"
+[settings]
+snat_ipv4 = "10.0.0.1"
+snat_ipv6 = "none"
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "example.com"
+snat = "masquerade"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(
+            config.settings.snat_ipv4,
+            Some(Snat::Address("10.0.0.1".to_string()))
+        );
+        assert_eq!(config.settings.snat_ipv6, Some(Snat::None));
+        assert_eq!(config.rules[0].snat(), Some(&Snat::Masquerade));
+
+        let toml_str = "[settings]\nsnat_ipv4 = \"2001:db8::1\"\n";
+        assert!(TomlConfig::from_toml_str(toml_str).is_err());
+    }
+
+    #[test]
+    fn test_sets_validate() {
+        let toml_str = r#"
+[[sets]]
+name = "blocklist"
+elements = ["192.0.2.0/24", "2001:db8::/32"]
+
+[[sets]]
+name = "from_file"
+file = "/etc/nftables-nat/blocklist.txt"
+
+[[rules]]
+type = "drop"
+chain = "input"
+src_set = "blocklist"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.sets.len(), 2);
+        assert_eq!(config.rules[0].src_set(), Some("blocklist"));
+        assert_eq!(
+            config.rules[0].to_string(),
+            "DROP,input,src_set=blocklist,all"
+        );
+
+        // 引用未定义的集合
+        let toml_str = r#"
+[[rules]]
+type = "drop"
+chain = "input"
+src_set = "missing"
+"#;
+        assert!(
+            TomlConfig::from_toml_str(toml_str)
+                .unwrap_err()
+                .contains("未定义")
+        );
+
+        // 集合名非法
+        let toml_str = "[[sets]]\nname = \"bad-name\"\nelements = [\"1.2.3.4\"]\n";
+        assert!(TomlConfig::from_toml_str(toml_str).is_err());
 
-/// 验证legacy格式配置内容
-/// 返回第一个遇到的错误，跳过注释和空行
-pub fn validate_legacy_config(content: &str) -> Result<(), String> {
-    for (line_num, line) in content.lines().enumerate() {
-        match NftCell::try_from(line) {
-            Ok(cell) => {
-                cell.validate()
-                    .map_err(|e| format!("第 {} 行验证失败: {}", line_num + 1, e))?;
-            }
-            Err(ParseError::Skip) => continue,
-            Err(ParseError::InvalidFormat(msg)) => {
-                return Err(format!("第 {} 行解析失败: {}", line_num + 1, msg));
-            }
-        }
+        // 重复定义
+        let toml_str = "[[sets]]\nname = \"a\"\nelements = [\"1.2.3.4\"]\n[[sets]]\nname = \"a\"\nelements = [\"1.2.3.5\"]\n";
+        assert!(
+            TomlConfig::from_toml_str(toml_str)
+                .unwrap_err()
+                .contains("重复")
+        );
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_src_country_validate() {
+        let toml_str = r#"
+[settings]
+geoip = "/etc/nftables-nat/geoip"
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "example.com"
+src_country = ["CN", "HK"]
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.rules[0].src_country(), ["CN", "HK"]);
+        assert_eq!(
+            config.rules[0].to_string(),
+            "SINGLE,10000,443,example.com,all,all,src_country=CN|HK"
+        );
+
+        // 没有配置geoip数据库
+        let without_geoip =
+            toml_str.replace("[settings]\ngeoip = \"/etc/nftables-nat/geoip\"\n", "");
+        assert!(
+            TomlConfig::from_toml_str(&without_geoip)
+                .unwrap_err()
+                .contains("geoip")
+        );
+
+        let invalid = toml_str.replace("\"HK\"", "\"HKG\"");
+        assert!(
+            TomlConfig::from_toml_str(&invalid)
+                .unwrap_err()
+                .contains("国家代码")
+        );
+    }
 
     #[test]
-    fn test_validate_single_rule() {
-        let rule = NftCell::Single {
-            sport: 10000,
-            dport: 443,
-            domain: "example.com".to_string(),
-            protocol: Protocol::Tcp,
-            ip_version: IpVersion::V4,
-            comment: None,
+    fn test_schedule_validate() {
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "example.com"
+schedule = { days = ["mon", "tue", "wed", "thu", "fri"], hours = ["09:00-18:00"], timezone = "Asia/Shanghai" }
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        let schedule = config.rules[0].schedule().unwrap();
+        assert_eq!(schedule.weekdays().unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(schedule.hour_ranges().unwrap(), vec![(540, 1080)]);
+
+        let line = "DROP,input,dst_port=22,schedule=sat|sun 22:00-06:00 UTC,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+
+        let schedule = Schedule {
+            days: vec!["Sunday".to_string(), "sat".to_string()],
+            hours: vec![],
+            timezone: None,
         };
-        assert!(rule.validate().is_ok());
+        assert_eq!(schedule.weekdays().unwrap(), vec![0, 6]);
+
+        // 斜杠以外的时区名也按时区解析
+        for timezone in ["GMT", "EST", "Etc/UTC", "UTC"] {
+            let schedule = Schedule::from_str(&format!("mon 09:00-18:00 {timezone}")).unwrap();
+            assert_eq!(schedule.timezone.as_deref(), Some(timezone));
+            assert_eq!(schedule.days, vec!["mon"]);
+        }
+        let schedule = Schedule::from_str("22:00-06:00 Asia/Shanghai").unwrap();
+        assert!(schedule.days.is_empty());
+        let err = Schedule::from_str("mon 09:00-18:00 Mars/Base").unwrap_err();
+        assert!(err.contains("时区"), "{err}");
+        let err = Schedule::from_str("mon|someday 09:00-18:00").unwrap_err();
+        assert!(err.contains("无效的星期"), "{err}");
+
+        for invalid in [
+            "days = [\"someday\"]",
+            "days = [\"monkey\"]",
+            "days = [\"sunflower\"]",
+            "days = [\"tues\"]",
+            "hours = [\"25:00-26:00\"]",
+            "hours = [\"09:00-09:00\"]",
+            "hours = [\"9-18\"]",
+            "hours = [\"09:00-18:00\"], timezone = \"Mars/Base\"",
+            "timezone = \"UTC\"",
+        ] {
+            let toml_str = format!(
+                "[[rules]]\ntype = \"redirect\"\nsport = 8000\ndport = 3128\nschedule = {{ {invalid} }}\n"
+            );
+            assert!(
+                TomlConfig::from_toml_str(&toml_str)
+                    .unwrap_err()
+                    .contains("schedule 无效"),
+                "{invalid}"
+            );
+        }
     }
 
     #[test]
-    fn test_validate_empty_domain() {
-        let rule = NftCell::Single {
-            sport: 10000,
-            dport: 443,
-            domain: "".to_string(),
-            protocol: Protocol::Tcp,
-            ip_version: IpVersion::V4,
-            comment: None,
-        };
-        assert!(rule.validate().is_err());
+    fn test_filter_action() {
+        let line = "DROP,input,dst_port=22,action=reject,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.filter_action(), Some(FilterAction::Reject));
+        assert_eq!(cell.to_string(), line);
+
+        // 默认动作drop不出现在Display中
+        let cell = NftCell::try_from("DROP,input,dst_port=22,action=drop,tcp").unwrap();
+        assert_eq!(cell.to_string(), "DROP,input,dst_port=22,tcp");
+
+        let cell =
+            NftCell::try_from("DROP,forward,src_ip=1.2.3.4,action=log-and-drop,all").unwrap();
+        assert_eq!(cell.filter_action(), Some(FilterAction::LogAndDrop));
+
+        assert!(NftCell::try_from("DROP,input,dst_port=22,action=deny,tcp").is_err());
+        assert!(NftCell::try_from("ACCEPT,input,dst_port=22,action=reject,tcp").is_err());
+
+        let toml_str = r#"
+[[rules]]
+type = "drop"
+chain = "input"
+dst_port = 22
+action = "reject"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.rules[0].filter_action(), Some(FilterAction::Reject));
+        assert!(TomlConfig::from_toml_str(&toml_str.replace("reject", "deny")).is_err());
     }
 
     #[test]
-    fn test_validate_range_rule() {
-        let rule = NftCell::Range {
-            port_start: 1000,
-            port_end: 2000,
-            domain: "example.com".to_string(),
-            protocol: Protocol::Tcp,
-            ip_version: IpVersion::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+    fn test_rule_log() {
+        let log = RuleLog::from_str("ssh-in info 10/minute burst 5").unwrap();
+        assert_eq!(log.prefix.as_deref(), Some("ssh-in"));
+        assert_eq!(log.level.as_deref(), Some("info"));
+        assert_eq!(log.rate.as_deref(), Some("10/minute burst 5"));
+
+        let log = RuleLog::from_str("warn").unwrap();
+        assert_eq!(log.prefix, None);
+        assert_eq!(log.level.as_deref(), Some("warn"));
+
+        assert!(RuleLog::from_str("a b").is_err());
+        assert!(RuleLog::from_str("web 10 mbytes/second").is_err());
+        assert!(RuleLog::from_str("web verbose").is_err());
+
+        let line = "SINGLE,8080,80,example.com,tcp,ipv4,log=web info 10/minute";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.log().and_then(|l| l.prefix.as_deref()), Some("web"));
+        assert_eq!(cell.to_string(), line);
+
+        let line = "DROP,input,dst_port=22,log=ssh-drop,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 8080
+dport = 80
+domain = "example.com"
+log = { prefix = "web", level = "info", rate = "10/minute" }
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(
+            config.rules[0].log().and_then(|l| l.rate.as_deref()),
+            Some("10/minute")
+        );
+        let invalid = toml_str.replace("\"info\"", "\"loud\"");
+        assert!(TomlConfig::from_toml_str(&invalid).is_err());
     }
 
     #[test]
-    fn test_validate_invalid_range() {
-        let rule = NftCell::Range {
-            port_start: 2000,
-            port_end: 1000,
-            domain: "example.com".to_string(),
-            protocol: Protocol::Tcp,
-            ip_version: IpVersion::V4,
-            comment: None,
-        };
-        assert!(rule.validate().is_err());
+    fn test_protocol_extended() {
+        assert_eq!("sctp".parse::<Protocol>(), Ok(Protocol::Sctp));
+        assert_eq!("icmp".parse::<Protocol>(), Ok(Protocol::Icmp(None)));
+        assert_eq!(
+            "icmp:echo-request".parse::<Protocol>(),
+            Ok(Protocol::Icmp(Some(8)))
+        );
+        assert_eq!(
+            "icmpv6:128".parse::<Protocol>(),
+            Ok(Protocol::Icmpv6(Some(128)))
+        );
+        assert_eq!("l4proto:47".parse::<Protocol>(), Ok(Protocol::L4proto(47)));
+        assert_eq!(
+            Protocol::Icmpv6(Some(128)).to_string(),
+            "icmpv6:echo-request"
+        );
+        assert_eq!(Protocol::Icmp(Some(42)).to_string(), "icmp:42");
+        assert!("icmp:bogus".parse::<Protocol>().is_err());
+        assert!("l4proto:300".parse::<Protocol>().is_err());
+        assert!("gre".parse::<Protocol>().is_err());
+
+        let line = "DROP,input,src_ip=1.2.3.4,icmp:echo-request";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        // ICMP没有端口，也不能用于转发规则
+        let cell = NftCell::try_from("DROP,input,dst_port=22,icmp").unwrap();
+        assert!(cell.validate().is_err());
+        let cell = NftCell::try_from("SINGLE,8080,80,example.com,icmp,ipv4").unwrap();
+        assert!(cell.validate().is_err());
+        assert!(NftCell::try_from("SINGLE,8080,80,example.com,icmp:bogus,ipv4").is_err());
+
+        // 拼错的协议报错，不能当作 all 丢弃所有协议的包
+        for line in [
+            "DROP,input,src_ip=1.2.3.4,icpm",
+            "ACCEPT,input,dst_port=22,sctpp",
+        ] {
+            match NftCell::try_from(line) {
+                Err(ParseError::InvalidFormat(msg)) => assert!(msg.contains("未知的协议"), "{msg}"),
+                other => panic!("{line}: {other:?}"),
+            }
+            assert!(validate_legacy_config(line).is_err());
+        }
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,udp,ipv4").unwrap();
+        assert_eq!(cell.to_string(), "DROP,input,src_ip=1.2.3.4,udp");
+
+        // 协议与地址族不一致
+        let cell = NftCell::try_from("DROP,input,src_ip=2001:db8::1,icmp").unwrap();
+        assert!(cell.validate().is_err());
+
+        // 单独的ICMP类型可以作为过滤条件
+        let cell = NftCell::try_from("DROP,input,icmp:echo-request").unwrap();
+        assert!(cell.validate().is_ok());
+
+        let toml_str = r#"
+[[rules]]
+type = "drop"
+chain = "input"
+protocol = "l4proto:47"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert!(matches!(
+            config.rules[0],
+            NftCell::Drop {
+                protocol: Protocol::L4proto(47),
+                ..
+            }
+        ));
+        assert!(TomlConfig::from_toml_str(&toml_str.replace("l4proto:47", "gre")).is_err());
     }
 
     #[test]
-    fn test_parse_and_validate_toml() {
+    fn test_multiple_ports() {
+        let ports: Ports = "80|443|8000-8100".parse().unwrap();
+        assert_eq!(ports.port_count(), 103);
+        assert_eq!(ports.single(), None);
+        assert_eq!(ports.to_string(), "80|443|8000-8100");
+        assert!(ports.validate().is_ok());
+        assert!("80|70-90".parse::<Ports>().unwrap().validate().is_err());
+        assert!("80|x".parse::<Ports>().is_err());
+
+        let line = "SINGLE,80|443,8080|8443,example.com,tcp,ipv4";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        // 目标端口只能是单个端口或与监听端口一一对应
+        let cell = NftCell::try_from("SINGLE,80|443,8080,example.com,tcp,ipv4").unwrap();
+        assert!(cell.validate().is_ok());
+        let cell = NftCell::try_from("SINGLE,80|443,8080|8443|9443,example.com,tcp,ipv4").unwrap();
+        assert!(cell.validate().unwrap_err().contains("数量不一致"));
+
+        let line = "DROP,input,dst_port=22|3306|6379-6380,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
         let toml_str = r#"
+[[rules]]
+type = "single"
+sport = [80, 443, "8000-8100"]
+dport = [80, 443, "8000-8100"]
+domain = "example.com"
+
 [[rules]]
 type = "single"
 sport = 10000
 dport = 443
 domain = "example.com"
-protocol = "tcp"
-ip_version = "ipv4"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        let NftCell::Single { sport, .. } = &config.rules[0] else {
+            panic!("Expected Single variant");
+        };
+        assert_eq!(sport.to_string(), "80|443|8000-8100");
+        // 单个端口仍然序列化为数字
+        let output = config.to_toml_string().unwrap();
+        assert!(output.contains("sport = 10000"));
+        assert!(output.contains("8000-8100"));
+        let reparsed = TomlConfig::from_toml_str(&output).unwrap();
+        assert_eq!(reparsed.rules[0].to_string(), config.rules[0].to_string());
+    }
+
+    #[test]
+    fn test_hairpin() {
+        let line = "SINGLE,8080,80,example.com,tcp,ipv4,hairpin=true";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.hairpin(), Some(true));
+        assert_eq!(cell.to_string(), line);
+
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,hairpin=false").unwrap();
+        assert_eq!(cell.hairpin(), Some(false));
+        assert!(NftCell::try_from("SINGLE,8080,80,example.com,tcp,ipv4,hairpin=yes").is_err());
+
+        let toml_str = r#"
+[settings]
+hairpin = true
 
 [[rules]]
 type = "range"
 port_start = 1000
 port_end = 2000
 domain = "example.com"
-protocol = "all"
-ip_version = "all"
+hairpin = false
 "#;
-        let result = TomlConfig::from_toml_str(toml_str);
-        assert!(result.is_ok());
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert!(config.settings.hairpin);
+        assert_eq!(config.rules[0].hairpin(), Some(false));
+        assert!(
+            !TomlConfig::from_toml_str("rules = []")
+                .unwrap()
+                .settings
+                .hairpin
+        );
     }
 
     #[test]
-    fn test_ip_version_serde() {
-        assert_eq!(IpVersion::from("ipv4"), IpVersion::V4);
-        assert_eq!(IpVersion::from("ipv6"), IpVersion::V6);
-        assert_eq!(IpVersion::from("all"), IpVersion::All);
-        assert_eq!(IpVersion::from("unknown"), IpVersion::All);
-    }
+    fn test_forward_mode() {
+        let line = "SINGLE,8080,80,example.com,tcp,ipv6,mode=relay";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.forward_mode(), ForwardMode::Relay);
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
 
-    #[test]
-    fn test_protocol_serde() {
-        assert_eq!(Protocol::from("tcp"), Protocol::Tcp);
-        assert_eq!(Protocol::from("udp"), Protocol::Udp);
-        assert_eq!(Protocol::from("all"), Protocol::All);
-        assert_eq!(Protocol::from("unknown"), Protocol::All);
-    }
+        let cell = NftCell::try_from("RANGE,1000,2000,example.com,udp,ipv4").unwrap();
+        assert_eq!(cell.forward_mode(), ForwardMode::Nat);
 
-    #[test]
-    fn test_nft_cell_display() {
-        let cell = NftCell::Single {
-            sport: 10000,
-            dport: 443,
-            domain: "example.com".to_string(),
-            protocol: Protocol::Tcp,
-            ip_version: IpVersion::V4,
-            comment: None,
-        };
-        assert_eq!(cell.to_string(), "SINGLE,10000,443,example.com,tcp,ipv4");
+        assert!(NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,mode=relay").is_err());
+        assert!(NftCell::try_from("SINGLE,8080,80,example.com,tcp,ipv4,mode=proxy").is_err());
 
-        let cell = NftCell::Redirect {
-            src_port: 8000,
-            src_port_end: Some(9000),
-            dst_port: 3128,
-            protocol: Protocol::All,
-            ip_version: IpVersion::All,
-            comment: None,
-        };
-        assert_eq!(cell.to_string(), "REDIRECT,8000-9000,3128,all,all");
+        let cell =
+            NftCell::try_from("SINGLE,8080,80,example.com,tcp,ipv4,mode=relay,snat=none").unwrap();
+        assert!(cell.validate().unwrap_err().contains("snat"));
+
+        let toml_str = r#"
+[settings]
+relay_udp_timeout = 30
+
+[[rules]]
+type = "single"
+sport = 53
+dport = 53
+domain = "10.0.0.53"
+protocol = "udp"
+ip_version = "ipv4"
+mode = "relay"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.settings.relay_udp_timeout, Some(30));
+        assert_eq!(config.rules[0].forward_mode(), ForwardMode::Relay);
+
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 1
+dport = 1
+domain = "10.0.0.1"
+protocol = "sctp"
+mode = "relay"
+"#;
+        assert!(TomlConfig::from_toml_str(toml_str).is_err());
     }
 
     #[test]
-    fn test_try_from_single() {
-        let line = "SINGLE,10000,443,example.com,tcp,ipv4";
+    fn test_enabled_flag() {
+        let line = "RANGE,1000,2000,example.com,tcp,ipv4,enabled=false";
         let cell = NftCell::try_from(line).unwrap();
-        match cell {
-            NftCell::Single {
-                sport,
-                dport,
-                domain,
-                protocol,
-                ip_version,
-                ..
-            } => {
-                assert_eq!(sport, 10000);
-                assert_eq!(dport, 443);
-                assert_eq!(domain, "example.com");
-                assert_eq!(protocol, Protocol::Tcp);
-                assert_eq!(ip_version, IpVersion::V4);
-            }
-            _ => panic!("Expected Single variant"),
-        }
-    }
+        assert!(!cell.enabled());
+        assert_eq!(cell.to_string(), line);
 
-    #[test]
-    fn test_try_from_redirect_range() {
-        let line = "REDIRECT,30001-39999,45678,tcp,ipv4";
+        let line = "DROP,input,src_ip=1.2.3.4,enabled=false,all";
         let cell = NftCell::try_from(line).unwrap();
-        match cell {
-            NftCell::Redirect {
-                src_port,
-                src_port_end,
-                dst_port,
-                ..
-            } => {
-                assert_eq!(src_port, 30001);
-                assert_eq!(src_port_end, Some(39999));
-                assert_eq!(dst_port, 45678);
-            }
-            _ => panic!("Expected Redirect variant"),
-        }
+        assert!(!cell.enabled());
+        assert_eq!(cell.to_string(), line);
+
+        assert!(
+            NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4")
+                .unwrap()
+                .enabled()
+        );
+        assert!(NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,enabled=no").is_err());
+
+        let toml_str = r#"
+[[rules]]
+type = "redirect"
+sport = 8080
+dport = 3128
+enabled = false
+
+[[rules]]
+type = "accept"
+chain = "input"
+src_ip = "10.0.0.0/8"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert!(!config.rules[0].enabled());
+        assert!(config.rules[1].enabled());
+        // 启用的规则序列化时不输出 enabled
+        let output = config.to_toml_string().unwrap();
+        assert_eq!(output.matches("enabled").count(), 1);
     }
 
     #[test]
-    fn test_try_from_comment() {
-        let line = "# This is a comment";
-        let result = NftCell::try_from(line);
-        assert!(matches!(result, Err(ParseError::Skip)));
-    }
+    fn test_rule_groups() {
+        let line = "SINGLE,8080,80,example.com,tcp,ipv4,group=acme,tags=web|prod";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.group(), Some("acme"));
+        assert_eq!(cell.tags(), ["web", "prod"]);
+        assert_eq!(cell.to_string(), line);
+
+        let line = "ACCEPT,input,src_ip=10.0.0.0/8,group=office,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,group=a-b").unwrap();
+        assert!(cell.validate().unwrap_err().contains("分组名"));
+
+        let toml_str = r#"
+[[groups]]
+name = "acme"
+enabled = false
+comment = "客户A"
+
+[[rules]]
+type = "drop"
+chain = "input"
+src_ip = "1.2.3.4"
+group = "acme"
+tags = ["abuse"]
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert!(!config.groups[0].enabled);
+        assert_eq!(config.rules[0].group(), Some("acme"));
+
+        let toml_str = r#"
+[[rules]]
+type = "drop"
+chain = "input"
+src_ip = "1.2.3.4"
+group = "missing"
+"#;
+        assert!(
+            TomlConfig::from_toml_str(toml_str)
+                .unwrap_err()
+                .contains("分组 missing 未定义")
+        );
+
+        let toml_str = r#"
+[[groups]]
+name = "acme"
 
-    #[test]
-    fn test_try_from_empty() {
-        let line = "   ";
-        let result = NftCell::try_from(line);
-        assert!(matches!(result, Err(ParseError::Skip)));
+[[groups]]
+name = "acme"
+"#;
+        assert!(
+            TomlConfig::from_toml_str(toml_str)
+                .unwrap_err()
+                .contains("重复定义")
+        );
     }
 
     #[test]
-    fn test_try_from_invalid() {
-        let line = "INVALID,123,456";
-        let result = NftCell::try_from(line);
-        assert!(matches!(result, Err(ParseError::InvalidFormat(_))));
-    }
+    fn test_quota() {
+        assert_eq!(
+            Quota::from_str("500 GiB").unwrap().bytes,
+            500 * 1024 * 1024 * 1024
+        );
+        assert_eq!(Quota::from_str("10GB").unwrap().bytes, 10_000_000_000);
+        assert_eq!(
+            Quota::from_str("2 gbytes").unwrap().to_string(),
+            "2147483648 bytes"
+        );
+        assert!(Quota::from_str("0 GiB").is_err());
+        assert!(Quota::from_str("500 GiB/month").is_err());
+        assert!(Quota::from_str("99999999999 TiB").is_err());
 
-    #[test]
-    fn test_validate_legacy_config() {
-        let content = "# Comment\nSINGLE,10000,443,example.com,tcp,ipv4\nREDIRECT,8000,3128\n";
-        assert!(validate_legacy_config(content).is_ok());
-    }
+        let line = "SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=500 GiB,quota_reset=monthly";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.quota(), Some("500 GiB"));
+        assert_eq!(cell.quota_reset(), Some(QuotaReset::Monthly));
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
 
-    #[test]
-    fn test_validate_legacy_config_invalid() {
-        let content = "SINGLE,10000,443,example.com\nINVALID,123";
-        let result = validate_legacy_config(content);
-        assert!(result.is_err());
-    }
+        assert!(NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,quota_reset=yearly").is_err());
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,quota_reset=daily").unwrap();
+        assert!(cell.validate().is_err());
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,quota=1 TB,mode=relay").unwrap();
+        assert!(cell.validate().is_err());
 
-    #[test]
-    fn test_drop_ipv4_with_ipv4_address() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("192.168.1.1".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
-    }
+        let toml_str = r#"
+[[rules]]
+type = "balance"
+sport = 443
+dport = 443
+ip_version = "ipv4"
+quota = "1 TiB"
+quota_reset = "weekly"
+targets = [{ domain = "10.0.0.1" }, { domain = "10.0.0.2" }]
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.rules[0].quota(), Some("1 TiB"));
+        assert_eq!(config.rules[0].quota_reset(), Some(QuotaReset::Weekly));
 
-    #[test]
-    fn test_drop_with_ipv6_address() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("2001:db8::1".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+        let dual_stack = toml_str.replace("ip_version = \"ipv4\"\n", "");
+        assert!(
+            TomlConfig::from_toml_str(&dual_stack)
+                .unwrap_err()
+                .contains("ip_version")
+        );
     }
 
     #[test]
-    fn test_drop_ipv4_with_ipv6_address_fails() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("2001:db8::1".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        let result = rule.validate();
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err();
-        assert!(err_msg.contains("IPv6格式"));
-        assert!(err_msg.contains("ipv4"));
-    }
+    fn test_marks() {
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,mark=16,ct_mark=0x20").unwrap();
+        assert_eq!(cell.mark(), Some(0x10));
+        assert_eq!(cell.ct_mark(), Some(0x20));
+        assert_eq!(
+            cell.to_string(),
+            "SINGLE,10000,443,10.0.0.1,tcp,ipv4,mark=0x10,ct_mark=0x20"
+        );
+        assert!(cell.validate().is_ok());
 
-    #[test]
-    fn test_drop_ipv6_with_ipv4_address_fails() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: None,
-            dst_ip: Some("192.168.1.1".to_string()),
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        let result = rule.validate();
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err();
-        assert!(err_msg.contains("IPv4格式"));
-        assert!(err_msg.contains("ipv6"));
-    }
+        let line = "ACCEPT,forward,src_ip=10.0.0.0/8,mark=0x1,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.mark(), Some(1));
+        assert_eq!(cell.to_string(), line);
 
-    #[test]
-    fn test_drop_all_with_ipv4_address() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("10.0.0.1".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
-    }
+        assert!(NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,mark=0xzz").is_err());
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,mark=0x1,all").unwrap();
+        assert!(cell.validate().is_err());
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,ct_mark=0").unwrap();
+        assert!(cell.validate().is_err());
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,mark=0x1,mode=relay").unwrap();
+        assert!(cell.validate().is_err());
 
-    #[test]
-    fn test_drop_all_with_ipv6_address() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("fe80::1".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+        let toml_str = r#"
+[[rules]]
+type = "range"
+port_start = 1000
+port_end = 2000
+domain = "10.0.0.1"
+mark = 0x100
+ct_mark = 0x100
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(config.rules[0].mark(), Some(0x100));
+        assert_eq!(config.rules[0].ct_mark(), Some(0x100));
     }
 
     #[test]
-    fn test_drop_ipv4_cidr_notation() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("192.168.1.0/24".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+    fn test_expires_at() {
+        let line = "SINGLE,10000,22,10.0.0.5,tcp,ipv4,expires_at=2026-10-18T18:00:00+08:00";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.expires_at(), Some("2026-10-18T18:00:00+08:00"));
+        assert_eq!(
+            cell.expiry().unwrap(),
+            DateTime::parse_from_rfc3339("2026-10-18T10:00:00Z").unwrap()
+        );
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let line =
+            "ACCEPT,input,src_ip=203.0.113.7,dst_port=22,expires_at=2026-10-18T10:00:00Z,tcp";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.to_string(), line);
+
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,expires_at=tomorrow").unwrap();
+        assert!(cell.expiry().is_none());
+        assert!(cell.validate().is_err());
+
+        let toml_str = r#"
+[[rules]]
+type = "accept"
+chain = "input"
+src_ip = "203.0.113.7"
+expires_at = "2026-10-18T18:00:00+08:00"
+"#;
+        let config = TomlConfig::from_toml_str(toml_str).unwrap();
+        assert_eq!(
+            config.rules[0].expires_at(),
+            Some("2026-10-18T18:00:00+08:00")
+        );
     }
 
     #[test]
-    fn test_drop_ipv6_cidr_notation() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("2001:db8::/32".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+    fn test_rule_id() {
+        let line = "SINGLE,10000,443,10.0.0.1,tcp,ipv4,id=web";
+        let cell = NftCell::try_from(line).unwrap();
+        assert_eq!(cell.id(), Some("web"));
+        assert_eq!(cell.rule_id(), "web");
+        assert_eq!(cell.to_string(), line);
+        assert!(cell.validate().is_ok());
+
+        let cell = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4").unwrap();
+        assert_eq!(cell.rule_id(), "single-tcp-10000-ipv4");
+        let cell = NftCell::try_from("RANGE,1000,2000,10.0.0.1,all,all").unwrap();
+        assert_eq!(cell.rule_id(), "range-all-1000-2000");
+        let cell = NftCell::try_from("REDIRECT,8000-9000,3128,tcp,all").unwrap();
+        assert_eq!(cell.rule_id(), "redirect-tcp-8000-9000");
+
+        // 过滤规则的标识只取决于匹配条件，修改注释、启停不影响
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,all").unwrap();
+        let disabled = NftCell::try_from("DROP,input,src_ip=1.2.3.4,enabled=false,all").unwrap();
+        let other = NftCell::try_from("DROP,input,src_ip=1.2.3.5,all").unwrap();
+        assert!(cell.rule_id().starts_with("drop-input-"));
+        assert_eq!(cell.rule_id(), disabled.rule_id());
+        assert_ne!(cell.rule_id(), other.rule_id());
+
+        let cell = NftCell::try_from("DROP,input,src_ip=1.2.3.4,id=block_ssh,all").unwrap();
+        assert_eq!(cell.rule_id(), "block_ssh");
+        let cell = NftCell::try_from("REDIRECT,8080,3128,tcp,ipv4,id=bad id").unwrap();
+        assert!(cell.validate().is_err());
+
+        let legacy = "SINGLE,10000,443,10.0.0.1,tcp,ipv4,id=web\nSINGLE,10001,443,10.0.0.2,tcp,ipv4,id=web\n";
+        assert!(validate_legacy_config(legacy).is_err());
+
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.1"
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.2"
+"#;
+        let err = TomlConfig::from_toml_str(toml_str).unwrap_err();
+        assert!(err.contains("single-all-10000"), "{err}");
+        let toml_str = toml_str.replace(
+            "domain = \"10.0.0.2\"",
+            "domain = \"10.0.0.2\"\nid = \"web_backup\"",
+        );
+        assert!(TomlConfig::from_toml_str(&toml_str).is_ok());
+
+        // 停用的旧规则和替代它的规则可以共存
+        let legacy = "SINGLE,10000,443,10.0.0.1,tcp,ipv4,enabled=false\nSINGLE,10000,443,10.0.0.2,tcp,ipv4\n";
+        assert!(validate_legacy_config(legacy).is_ok());
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.1"
+enabled = false
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.2"
+"#;
+        assert!(TomlConfig::from_toml_str(toml_str).is_ok());
+
+        // 只有动作、限速或日志不同的过滤规则标识也不同
+        let legacy = "DROP,input,dst_port=22,tcp\n\
+            DROP,input,dst_port=22,action=reject,tcp\n\
+            DROP,input,dst_port=22,limit=10/minute,tcp\n\
+            DROP,input,dst_port=22,log=ssh-drop,tcp\n\
+            ACCEPT,input,dst_port=22,tcp\n";
+        assert!(validate_legacy_config(legacy).is_ok());
+
+        // 定时、标记和过期时间不同的过滤规则标识也不同
+        let base = NftCell::try_from("ACCEPT,input,dst_port=22,tcp").unwrap();
+        for line in [
+            "ACCEPT,input,dst_port=22,schedule=sat|sun 22:00-06:00 UTC,tcp",
+            "ACCEPT,input,dst_port=22,mark=0x1,tcp",
+            "ACCEPT,input,dst_port=22,ct_mark=0x1,tcp",
+            "ACCEPT,input,dst_port=22,expires_at=2099-01-01T00:00:00+08:00,tcp",
+        ] {
+            let cell = NftCell::try_from(line).unwrap();
+            assert_ne!(cell.rule_id(), base.rule_id(), "{line}");
+        }
+        let legacy = "DROP,input,dst_port=22,tcp\n\
+            DROP,input,dst_port=22,schedule=mon 09:00-18:00 UTC,tcp\n";
+        assert!(validate_legacy_config(legacy).is_ok());
+
+        // 同一端口按入站接口分流的转发规则标识不同，可以同时配置
+        let lan =
+            NftCell::try_from("SINGLE,10000,443,192.168.1.10,tcp,ipv4,iifname=br-lan").unwrap();
+        let wan = NftCell::try_from("SINGLE,10000,443,10.0.0.1,tcp,ipv4,iifname=eth0").unwrap();
+        assert!(lan.rule_id().starts_with("single-tcp-10000-ipv4-"));
+        assert_ne!(lan.rule_id(), wan.rule_id());
+        let toml_str = r#"
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "192.168.1.10"
+iifname = "br-lan"
+
+[[rules]]
+type = "single"
+sport = 10000
+dport = 443
+domain = "10.0.0.1"
+iifname = "eth0"
+"#;
+        assert!(TomlConfig::from_toml_str(toml_str).is_ok());
+        let legacy = format!("{lan}\n{wan}\n");
+        assert!(validate_legacy_config(&legacy).is_ok());
+        // 只修改目标地址时标识不变
+        let moved = NftCell::try_from("SINGLE,10000,443,10.0.0.2,tcp,ipv4,iifname=eth0").unwrap();
+        assert_eq!(moved.rule_id(), wan.rule_id());
     }
 
     #[test]
-    fn test_drop_invalid_ip_address() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("invalid.ip.address".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        let result = rule.validate();
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err();
-        assert!(err_msg.contains("格式无效"));
+    fn test_comment_rule_id() {
+        let comment = format!(
+            "{}SINGLE,10000,443,10.0.0.1,tcp,ipv4",
+            status::comment_prefix("web")
+        );
+        assert_eq!(status::comment_rule_id(&comment), Some("web"));
+        assert_eq!(
+            status::comment_rule_id("SINGLE,10000,443,10.0.0.1,tcp,ipv4"),
+            None
+        );
     }
 
     #[test]
-    fn test_drop_invalid_cidr() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("192.168.1.1/99".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        let result = rule.validate();
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err();
-        assert!(err_msg.contains("格式无效"));
+    fn test_src_set_legacy_round_trip() {
+        for line in [
+            "DROP,input,src_set=blocklist,all",
+            "ACCEPT,input,src_set=office,dst_port=22,tcp",
+            "SINGLE,10000,443,example.com,all,all,src_set=office",
+        ] {
+            let cell = NftCell::try_from(line).unwrap();
+            assert!(cell.src_set().is_some());
+            let round_trip = NftCell::try_from(cell.to_string().as_str()).unwrap();
+            assert_eq!(round_trip.to_string(), cell.to_string());
+            assert_eq!(round_trip.src_set(), cell.src_set());
+        }
+        // 传统格式不能定义集合
+        assert!(
+            validate_legacy_config("DROP,input,src_set=blocklist,all\n")
+                .unwrap_err()
+                .contains("未定义")
+        );
     }
 
     #[test]
-    fn test_drop_valid_ipv6_full() {
-        let rule = NftCell::Drop {
-            chain: Chain::Input,
-            src_ip: Some("2001:0db8:85a3:0000:0000:8a2e:0370:7334".to_string()),
-            dst_ip: None,
-            src_port: None,
-            src_port_end: None,
-            dst_port: None,
-            dst_port_end: None,
-            protocol: Protocol::All,
-            comment: None,
-        };
-        assert!(rule.validate().is_ok());
+    fn test_src_country_legacy_round_trip() {
+        for line in [
+            "DROP,forward,src_country=RU|KP,all",
+            "SINGLE,10000,443,example.com,all,all,src_country=CN|HK",
+            "REDIRECT,8080,3128,tcp,ipv4,src_country=CN",
+        ] {
+            let cell = NftCell::try_from(line).unwrap();
+            assert!(!cell.src_country().is_empty());
+            assert!(cell.validate().is_ok());
+            let round_trip = NftCell::try_from(cell.to_string().as_str()).unwrap();
+            assert_eq!(round_trip.to_string(), cell.to_string());
+            assert_eq!(round_trip.src_country(), cell.src_country());
+        }
+        let cell =
+            NftCell::try_from("SINGLE,10000,443,example.com,all,all,src_country=CN|HK").unwrap();
+        assert_eq!(cell.src_country(), ["CN", "HK"]);
+        // 传统格式没有 [settings]，无法配置geoip
+        assert!(
+            validate_legacy_config("DROP,forward,src_country=RU,all\n")
+                .unwrap_err()
+                .contains("geoip")
+        );
     }
 }
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+
+/// nat-cli 每轮写出的规则状态，nat-console 读取后对应回配置中的规则
+pub const STATUS_FILE: &str = "/etc/nftables-nat/status.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Counter {
+    pub fn add(&mut self, other: Counter) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// 单条配置规则在nft中的状态
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleStatus {
+    /// nft中带有该规则标识的规则条数，为0表示规则没有生效
+    #[serde(default)]
+    pub live_rules: usize,
+    /// "{family} {table} {chain}" -> 累计计数，包含历次重载前的计数
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters: BTreeMap<String, Counter>,
+    /// 生成或加载规则时的错误
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+impl RuleStatus {
+    /// 各链计数之和
+    pub fn total(&self) -> Counter {
+        let mut total = Counter::default();
+        for counter in self.counters.values() {
+            total.add(*counter);
+        }
+        total
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    /// 写入时间，RFC 3339格式
+    pub updated_at: String,
+    /// 规则标识 -> 状态
+    #[serde(default)]
+    pub rules: BTreeMap<String, RuleStatus>,
+}
+
+impl Status {
+    pub fn load() -> Result<Self, io::Error> {
+        let content = std::fs::read_to_string(STATUS_FILE)?;
+        serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(STATUS_FILE, content)
+    }
+}
+
+/// 写入nft注释开头的规则标识，如 "[single-tcp-10000] "
+pub fn comment_prefix(rule_id: &str) -> String {
+    format!("[{rule_id}] ")
+}
+
+/// 从nft注释中取出规则标识，不是本程序生成的注释时返回None
+pub fn comment_rule_id(comment: &str) -> Option<&str> {
+    let (id, _) = comment.strip_prefix('[')?.split_once("] ")?;
+    Some(id)
+}
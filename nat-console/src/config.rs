@@ -1,4 +1,4 @@
-use nat_common::{Args, TomlConfig};
+use nat_common::{Args, NftCell, ParseError, TomlConfig};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::fs;
@@ -146,6 +146,28 @@ impl ConfigFormat {
         Ok(ConfigFormat::Legacy(lines))
     }
 
+    /// 配置中的规则，legacy格式跳过注释和空行
+    pub fn rules(&self) -> Result<Vec<NftCell>, io::Error> {
+        match self {
+            ConfigFormat::Toml(content) => TomlConfig::from_toml_str(content)
+                .map(|config| config.rules)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            ConfigFormat::Legacy(lines) => {
+                let mut rules = Vec::new();
+                for line in lines {
+                    match NftCell::try_from(line.line.as_str()) {
+                        Ok(cell) => rules.push(cell),
+                        Err(ParseError::Skip) => {}
+                        Err(ParseError::InvalidFormat(msg)) => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                        }
+                    }
+                }
+                Ok(rules)
+            }
+        }
+    }
+
     pub fn save_to_file(&self, path: &str) -> Result<(), io::Error> {
         let content = self.to_string();
         fs::write(path, content)?;
@@ -11,6 +11,7 @@ use axum::{
 use axum_bootstrap::jwt::{Claims, ClaimsPayload, JwtConfig, LOGOUT_COOKIE};
 use axum_extra::extract::CookieJar;
 use log::{error, info};
+use nat_common::status::Status;
 use nat_common::{TomlConfig, validate_legacy_config};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -277,6 +278,83 @@ pub async fn get_rules_json(_user: Claims) -> Result<Json<RulesResponse>, (Statu
     Ok(Json(RulesResponse { rules }))
 }
 
+#[derive(Serialize)]
+pub struct RuleStatusEntry {
+    id: String,
+    rule: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    enabled: bool,
+    live_rules: usize,
+    packets: u64,
+    bytes: u64,
+    errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RuleStatusResponse {
+    /// nat 服务最近一次写入状态的时间，服务未运行过时为空
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+    rules: Vec<RuleStatusEntry>,
+}
+
+/// 按规则标识把 nat 服务写出的状态对应回配置中的每条规则
+pub async fn get_rule_status(
+    _user: Claims,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<RuleStatusResponse>, (StatusCode, String)> {
+    let config_info = get_config_info(
+        state.toml_config.as_deref(),
+        state.compatible_config.as_deref(),
+    )
+    .map_err(|e| {
+        error!("Failed to get config info: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("获取配置信息失败: {}", e),
+        )
+    })?;
+    let rules = load_config(&config_info)
+        .and_then(|config| config.rules())
+        .map_err(|e| {
+            error!("Failed to load config rules: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("读取配置规则失败: {}", e),
+            )
+        })?;
+    let status = Status::load().ok();
+
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            let id = rule.rule_id();
+            let rule_status = status
+                .as_ref()
+                .and_then(|status| status.rules.get(&id))
+                .cloned()
+                .unwrap_or_default();
+            let total = rule_status.total();
+            RuleStatusEntry {
+                id,
+                rule: rule.to_string(),
+                comment: rule.comment().map(str::to_string),
+                enabled: rule.enabled(),
+                live_rules: rule_status.live_rules,
+                packets: total.packets,
+                bytes: total.bytes,
+                errors: rule_status.errors,
+            }
+        })
+        .collect();
+
+    Ok(Json(RuleStatusResponse {
+        updated_at: status.map(|status| status.updated_at),
+        rules,
+    }))
+}
+
 /// 自定义认证中间件：支持 Authorization header (Bearer token) 和 Cookie 两种方式
 pub async fn hybrid_auth_middleware(
     State(jwt_config): State<Arc<JwtConfig>>,
@@ -1,7 +1,7 @@
 use crate::Args;
 use crate::handlers::{
-    AppState, get_config, get_current_user, get_rules, get_rules_json, hybrid_auth_middleware,
-    login_handler, logout_handler, save_config,
+    AppState, get_config, get_current_user, get_rule_status, get_rules, get_rules_json,
+    hybrid_auth_middleware, login_handler, logout_handler, save_config,
 };
 use axum::{
     Router,
@@ -54,6 +54,7 @@ pub async fn run_server(args: Args) -> Result<(), Box<dyn std::error::Error + Se
         .route("/api/me", get(get_current_user))
         .route("/api/config", get(get_config).post(save_config))
         .route("/api/rules", get(get_rules_json))
+        .route("/api/rule-status", get(get_rule_status))
         .route("/rules", get(get_rules))
         .layer(middleware::from_fn_with_state(
             Arc::new(jwt_config.clone()),